@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+
+use crate::diag::{bail, Diagnostic};
+use crate::link::REGISTERS;
+
+// Encoder for linked KTC32 images. The output is the raw little-endian
+// memory image starting at its base address. The encoding below is the one
+// the simulator decodes, not that of ktc32-asm, which still assembles images
+// for the hardware.
+//
+// Register-register instructions take 16 bits:
+//   [5:0] opcode  [10:6] rd  [15:11] rs
+// and all others 32 bits, with a 16-bit immediate in [31:16]. Opcodes below
+// 0x20 are the 16-bit ones. Branch and jump offsets are in bytes, relative to
+// the next instruction. jal has no rs, and uses its bits as the top of a
+// 21-bit offset.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    // op rd, rs
+    Reg,
+    // op rd, rs, imm with a signed immediate; also loads, stores and jalr.
+    Imm,
+    // op rd, rs, imm with the immediate taken as 16 bits.
+    Logic,
+    // op rd, rs, shamt
+    Shift,
+    // lui rd, imm
+    Upper,
+    // op rs1, rs2, label|offset
+    Branch,
+    // jal rd, label|offset
+    Jump,
+}
+
+// Offsets reach this far in either direction.
+const BRANCH_RANGE: i64 = 1 << 15;
+const JUMP_RANGE: i64 = 1 << 20;
+
+const INSTRUCTIONS: [(&str, u32, Format); 32] = [
+    ("mov", 0x01, Format::Reg),
+    ("add", 0x02, Format::Reg),
+    ("sub", 0x03, Format::Reg),
+    ("and", 0x04, Format::Reg),
+    ("or", 0x05, Format::Reg),
+    ("xor", 0x06, Format::Reg),
+    ("sll", 0x07, Format::Reg),
+    ("srl", 0x08, Format::Reg),
+    ("sra", 0x09, Format::Reg),
+    ("slt", 0x0a, Format::Reg),
+    ("sltu", 0x0b, Format::Reg),
+    ("addi", 0x20, Format::Imm),
+    ("andi", 0x21, Format::Logic),
+    ("ori", 0x22, Format::Logic),
+    ("xori", 0x23, Format::Logic),
+    ("slli", 0x24, Format::Shift),
+    ("srli", 0x25, Format::Shift),
+    ("srai", 0x26, Format::Shift),
+    ("lui", 0x27, Format::Upper),
+    ("lw", 0x28, Format::Imm),
+    ("lh", 0x29, Format::Imm),
+    ("lhu", 0x2a, Format::Imm),
+    ("lb", 0x2b, Format::Imm),
+    ("lbu", 0x2c, Format::Imm),
+    ("sw", 0x2d, Format::Imm),
+    ("sh", 0x2e, Format::Imm),
+    ("sb", 0x2f, Format::Imm),
+    ("beq", 0x30, Format::Branch),
+    ("bnq", 0x31, Format::Branch),
+    ("blt", 0x32, Format::Branch),
+    ("jal", 0x33, Format::Jump),
+    ("jalr", 0x34, Format::Imm),
+];
+
+fn find_instruction(name: &str) -> Option<(u32, Format)> {
+    INSTRUCTIONS
+        .iter()
+        .find(|(op, _, _)| *op == name)
+        .map(|&(_, opcode, format)| (opcode, format))
+}
+
+// The mnemonic of an opcode, for decoding.
+pub(crate) fn mnemonic(opcode: u32) -> Option<&'static str> {
+    INSTRUCTIONS
+        .iter()
+        .find(|(_, op, _)| *op == opcode)
+        .map(|&(name, _, _)| name)
+}
+
+fn parse_int(s: &str) -> Option<i64> {
+    let (neg, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let val = if let Some(hex) = s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = s.strip_prefix("0b").or(s.strip_prefix("0B")) {
+        i64::from_str_radix(bin, 2).ok()?
+    } else {
+        s.parse().ok()?
+    };
+    Some(if neg { -val } else { val })
+}
+
+// A line of the image, with the address it is placed at.
+struct Line<'a> {
+    number: usize,
+    addr: i64,
+    op: &'a str,
+    operands: Vec<&'a str>,
+}
+
+struct Assembler<'a> {
+    lines: Vec<Line<'a>>,
+    labels: HashMap<&'a str, i64>,
+    base: i64,
+    bytes: Vec<u8>,
+}
+
+impl<'a> Assembler<'a> {
+    // Lays out the image and records the address of every label.
+    fn new(text: &'a str, base: i64) -> Result<Assembler<'a>, Diagnostic> {
+        let mut asm = Assembler {
+            lines: Vec::new(),
+            labels: HashMap::new(),
+            base,
+            bytes: Vec::new(),
+        };
+        let mut addr = base;
+        for (i, line) in text.lines().enumerate() {
+            let number = i + 1;
+            let line = line.split(';').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(label) = line.strip_suffix(':') {
+                if asm.labels.insert(label, addr).is_some() {
+                    bail!("Duplicate label {} at line {}", label, number);
+                }
+                continue;
+            }
+
+            let (op, operands) = match line.split_once(char::is_whitespace) {
+                Some((op, operands)) => (op, operands.split(',').map(str::trim).collect()),
+                None => (line, Vec::new()),
+            };
+            let line = Line {
+                number,
+                addr,
+                op,
+                operands,
+            };
+            addr += match op {
+                ".align" => {
+                    let align = asm.int(&line, 0)?;
+                    if align <= 0 {
+                        bail!("Invalid alignment at line {}", number);
+                    }
+                    (align - addr % align) % align
+                }
+                ".zero" => asm.int(&line, 0)?,
+                ".word" => 4 * line.operands.len() as i64,
+                ".half" => 2 * line.operands.len() as i64,
+                ".byte" => line.operands.len() as i64,
+                "la" => 8,
+                // Section names and symbol visibility take no space.
+                _ if op.starts_with('.') => 0,
+                _ => match find_instruction(op) {
+                    Some((opcode, _)) if opcode < 0x20 => 2,
+                    Some(_) => 4,
+                    None => bail!("Unknown instruction {} at line {}", op, number),
+                },
+            };
+            asm.lines.push(line);
+        }
+        Ok(asm)
+    }
+
+    fn operand<'b>(&self, line: &'b Line, i: usize) -> Result<&'b str, Diagnostic> {
+        match line.operands.get(i) {
+            Some(operand) if !operand.is_empty() => Ok(operand),
+            _ => bail!("Missing operand of {} at line {}", line.op, line.number),
+        }
+    }
+
+    fn int(&self, line: &Line, i: usize) -> Result<i64, Diagnostic> {
+        let operand = self.operand(line, i)?;
+        match parse_int(operand) {
+            Some(val) => Ok(val),
+            None => bail!("Invalid number {} at line {}", operand, line.number),
+        }
+    }
+
+    fn reg(&self, line: &Line, i: usize) -> Result<u32, Diagnostic> {
+        let operand = self.operand(line, i)?;
+        if let Some(index) = REGISTERS.iter().position(|&reg| reg == operand) {
+            return Ok(index as u32);
+        }
+        match operand
+            .strip_prefix('r')
+            .and_then(|n| n.parse::<u32>().ok())
+        {
+            Some(n) if n < 16 => Ok(n),
+            _ => bail!("Invalid register {} at line {}", operand, line.number),
+        }
+    }
+
+    // A number, a label, or a label plus or minus a number.
+    fn value(&self, line: &Line, i: usize) -> Result<i64, Diagnostic> {
+        let operand = self.operand(line, i)?;
+        if let Some(val) = parse_int(operand) {
+            return Ok(val);
+        }
+        let (label, offset) = match operand[1..].find(['+', '-']) {
+            Some(j) => {
+                let (label, offset) = operand.split_at(j + 1);
+                let offset = offset.strip_prefix('+').unwrap_or(offset);
+                match parse_int(offset) {
+                    Some(offset) => (label, offset),
+                    None => bail!("Invalid operand {} at line {}", operand, line.number),
+                }
+            }
+            None => (operand, 0),
+        };
+        match self.labels.get(label) {
+            Some(addr) => Ok(addr + offset),
+            None => bail!("Undefined label {} at line {}", label, line.number),
+        }
+    }
+
+    // Branch offsets are given as numbers or as labels to jump to.
+    fn offset(&self, line: &Line, i: usize, next: i64, range: i64) -> Result<u32, Diagnostic> {
+        let operand = self.operand(line, i)?;
+        let offset = match parse_int(operand) {
+            Some(offset) => offset,
+            None => self.value(line, i)? - next,
+        };
+        if offset < -range || offset >= range {
+            bail!(
+                "Branch to {} is out of range at line {}",
+                operand,
+                line.number
+            );
+        }
+        Ok(offset as u32)
+    }
+
+    fn check_range(&self, line: &Line, val: i64, min: i64, max: i64) -> Result<u32, Diagnostic> {
+        if val < min || val > max {
+            bail!(
+                "Immediate {} of {} is out of range at line {}",
+                val,
+                line.op,
+                line.number
+            );
+        }
+        Ok(val as u32 & 0xffff)
+    }
+
+    fn emit(&mut self, val: u32, size: usize) {
+        self.bytes.extend_from_slice(&val.to_le_bytes()[..size]);
+    }
+
+    fn emit_data(&mut self, line: &Line, size: usize) -> Result<(), Diagnostic> {
+        for i in 0..line.operands.len() {
+            let val = self.value(line, i)?;
+            let min = -(1 << (size * 8 - 1));
+            let max = (1 << (size * 8)) - 1;
+            if val < min || val > max {
+                bail!("Value {} does not fit at line {}", val, line.number);
+            }
+            self.emit(val as u32, size);
+        }
+        Ok(())
+    }
+
+    fn encode(&mut self, line: &Line) -> Result<(), Diagnostic> {
+        let len = (line.addr - self.base) as usize;
+        self.bytes.resize(len, 0);
+
+        match line.op {
+            ".align" => {}
+            ".zero" => {
+                let len = self.bytes.len() + self.int(line, 0)? as usize;
+                self.bytes.resize(len, 0);
+            }
+            ".word" => self.emit_data(line, 4)?,
+            ".half" => self.emit_data(line, 2)?,
+            ".byte" => self.emit_data(line, 1)?,
+            // Expanded like a wide constant; addi sign-extends the low half.
+            "la" => {
+                let rd = self.reg(line, 0)?;
+                let addr = self.value(line, 1)? as u32;
+                let lo = addr as u16 as i16;
+                let hi = addr.wrapping_sub(lo as i32 as u32) >> 16;
+                let (lui, _) = find_instruction("lui").unwrap();
+                let (addi, _) = find_instruction("addi").unwrap();
+                self.emit(lui | rd << 6 | hi << 16, 4);
+                self.emit(addi | rd << 6 | rd << 11 | (lo as u16 as u32) << 16, 4);
+            }
+            op if op.starts_with('.') => {}
+            op => {
+                let (opcode, format) = find_instruction(op).unwrap();
+                let next = line.addr + 4;
+                let (rd, rs, imm) = match format {
+                    Format::Reg => (self.reg(line, 0)?, self.reg(line, 1)?, 0),
+                    Format::Imm => {
+                        let imm = self.int(line, 2)?;
+                        let imm = self.check_range(line, imm, -0x8000, 0x7fff)?;
+                        (self.reg(line, 0)?, self.reg(line, 1)?, imm)
+                    }
+                    Format::Logic => {
+                        let imm = self.int(line, 2)?;
+                        let imm = self.check_range(line, imm, -0x8000, 0xffff)?;
+                        (self.reg(line, 0)?, self.reg(line, 1)?, imm)
+                    }
+                    Format::Shift => {
+                        let imm = self.int(line, 2)?;
+                        let imm = self.check_range(line, imm, 0, 31)?;
+                        (self.reg(line, 0)?, self.reg(line, 1)?, imm)
+                    }
+                    Format::Upper => {
+                        let imm = self.int(line, 1)?;
+                        (
+                            self.reg(line, 0)?,
+                            0,
+                            self.check_range(line, imm, 0, 0xffff)?,
+                        )
+                    }
+                    Format::Branch => {
+                        let offset = self.offset(line, 2, next, BRANCH_RANGE)?;
+                        (self.reg(line, 0)?, self.reg(line, 1)?, offset & 0xffff)
+                    }
+                    Format::Jump => {
+                        let offset = self.offset(line, 1, next, JUMP_RANGE)?;
+                        (self.reg(line, 0)?, offset >> 16 & 0x1f, offset & 0xffff)
+                    }
+                };
+                if format == Format::Reg {
+                    self.emit(opcode | rd << 6 | rs << 11, 2);
+                } else {
+                    self.emit(opcode | rd << 6 | rs << 11 | imm << 16, 4);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Encodes the assembly of a linked image, to be loaded at `base`, into
+/// machine code.
+pub fn assemble(text: &str, base: u32) -> Result<Vec<u8>, Diagnostic> {
+    let mut asm = Assembler::new(text, base as i64)?;
+    let lines = std::mem::take(&mut asm.lines);
+    for line in &lines {
+        asm.encode(line)?;
+    }
+    Ok(asm.bytes)
+}
+
+/// The address of `label` in the assembly of a linked image loaded at `base`,
+/// if it defines one.
+pub fn label_address(text: &str, base: u32, label: &str) -> Result<Option<u32>, Diagnostic> {
+    let asm = Assembler::new(text, base as i64)?;
+    Ok(asm.labels.get(label).map(|&addr| addr as u32))
+}
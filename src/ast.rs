@@ -0,0 +1,457 @@
+use crate::diag::{bail, Diagnostic};
+use crate::types::{common_type, promote, Type, TypeKind};
+
+// The syntax tree the parser builds. Every variant carries exactly what it
+// needs, and every expression knows its type once it has been built, so
+// passes over the tree can match on it without unwrapping.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Shl,
+    Shr,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+}
+
+impl BinOp {
+    /// Whether the operands are compared or divided as unsigned values.
+    /// Shifts only depend on the left operand.
+    pub fn is_unsigned(self, lhs: &Type, rhs: &Type) -> bool {
+        match self {
+            BinOp::Shl | BinOp::Shr => lhs.is_unsigned,
+            _ => common_type(lhs, rhs).is_unsigned,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Callee {
+    /// A function called by its label.
+    Direct(String),
+    /// Anything else, which evaluates to the address of a function.
+    Indirect(Box<Expr>),
+}
+
+/// An expression that designates an object, which is what assignments store
+/// to and `&` takes the address of. The parser only builds these from
+/// expressions that have one.
+#[derive(Debug, Clone)]
+pub enum LValue {
+    Local(i64),
+    Global(String),
+    Deref(Box<Expr>),
+    Member(Box<LValue>, i64),
+}
+
+#[derive(Debug, Clone)]
+pub enum ExprKind {
+    Num(i64),
+    /// A local at this offset below fp.
+    Local(i64),
+    /// A global, static local, string literal or function, by its label.
+    Global(String),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Assign(LValue, Box<Expr>),
+    Addr(LValue),
+    Deref(Box<Expr>),
+    /// A conversion to the type of the expression, which is void or a scalar.
+    Cast(Box<Expr>),
+    /// A member of a struct, this many bytes into it.
+    Member(Box<Expr>, i64),
+    Call {
+        callee: Callee,
+        args: Vec<Expr>,
+        func_ty: Type,
+    },
+    /// Points a va_list at the variadic arguments, which start this many
+    /// bytes above fp.
+    VaStart(LValue, i64),
+    VaArg(LValue),
+}
+
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub ty: Type,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, ty: Type) -> Expr {
+        Expr { kind, ty }
+    }
+
+    pub fn num(val: i64) -> Expr {
+        Expr::new(ExprKind::Num(val), Type::int())
+    }
+
+    pub fn binary(op: BinOp, lhs: Expr, rhs: Expr) -> Expr {
+        let ty = match op {
+            // Pointer arithmetic has been scaled by the parser already.
+            BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Mod => {
+                match lhs.ty.pointee() {
+                    Some(base) => Type::pointer_to(base),
+                    None => common_type(&lhs.ty, &rhs.ty),
+                }
+            }
+            BinOp::Shl | BinOp::Shr => promote(&lhs.ty),
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le => Type::int(),
+        };
+        Expr::new(ExprKind::Binary(op, Box::new(lhs), Box::new(rhs)), ty)
+    }
+
+    // The object this expression designates, if it is one.
+    pub(crate) fn into_lvalue(self) -> Option<LValue> {
+        match self.kind {
+            ExprKind::Local(offset) => Some(LValue::Local(offset)),
+            ExprKind::Global(label) => Some(LValue::Global(label)),
+            ExprKind::Deref(lhs) => Some(LValue::Deref(lhs)),
+            ExprKind::Member(lhs, offset) => {
+                Some(LValue::Member(Box::new(lhs.into_lvalue()?), offset))
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn assign(lhs: Expr, rhs: Expr) -> Result<Expr, Diagnostic> {
+        let ty = lhs.ty.unqualified();
+        match lhs.into_lvalue() {
+            Some(lhs) if !matches!(ty.kind, TypeKind::Array | TypeKind::Func) => {
+                Ok(Expr::new(ExprKind::Assign(lhs, Box::new(rhs)), ty))
+            }
+            _ => bail!("Left side value is not assignable"),
+        }
+    }
+
+    pub(crate) fn addr(lhs: Expr) -> Result<Expr, Diagnostic> {
+        let ty = Type::pointer_to(lhs.ty.clone());
+        match lhs.into_lvalue() {
+            Some(lhs) => Ok(Expr::new(ExprKind::Addr(lhs), ty)),
+            None => bail!("Cannot take the address of this expression"),
+        }
+    }
+
+    pub(crate) fn deref(lhs: Expr) -> Result<Expr, Diagnostic> {
+        match lhs.ty.pointee() {
+            Some(ty) if ty.kind != TypeKind::Void => {
+                Ok(Expr::new(ExprKind::Deref(Box::new(lhs)), ty))
+            }
+            _ => bail!("Invalid pointer dereference"),
+        }
+    }
+
+    pub fn member(lhs: Expr, offset: i64, ty: Type) -> Expr {
+        Expr::new(ExprKind::Member(Box::new(lhs), offset), ty)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Stmt {
+    Expr(Expr),
+    Return(Option<Expr>),
+    If {
+        cond: Expr,
+        then: Box<Stmt>,
+        els: Option<Box<Stmt>>,
+    },
+    /// Loops without a condition run until they return.
+    While {
+        cond: Option<Expr>,
+        body: Box<Stmt>,
+    },
+    For {
+        init: Option<Box<Expr>>,
+        cond: Option<Box<Expr>>,
+        inc: Option<Box<Expr>>,
+        body: Box<Stmt>,
+    },
+    Block(Vec<Stmt>),
+    /// Clears the whole object this address points to before its initializer
+    /// stores the values that are not zero.
+    MemZero(Expr),
+}
+
+#[derive(Debug, Clone)]
+pub struct LVar {
+    pub name: String,
+    pub offset: i64,
+    pub ty: Type,
+    /// Variables with static storage duration are addressed by label instead of offset.
+    pub label: Option<String>,
+}
+
+/// A scalar in the initial image of a global, `offset` bytes into it.
+/// Bytes not covered by any of them are zero.
+#[derive(Debug, Clone)]
+pub struct GInit {
+    pub offset: i64,
+    pub size: i64,
+    pub val: i64,
+    /// The value is the address of this label plus `val`.
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GVar {
+    pub label: String,
+    pub ty: Type,
+    pub init: Vec<GInit>,
+    /// Not visible to other objects.
+    pub is_static: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    pub ty: Type,
+    pub params: Vec<LVar>,
+    pub is_variadic: bool,
+    /// False for prototypes, whose body is empty.
+    pub is_definition: bool,
+    /// Not visible to other objects.
+    pub is_static: bool,
+    pub body: Stmt,
+    pub stack_size: i64,
+}
+
+impl Function {
+    pub fn func_ty(&self) -> Type {
+        Type::func(
+            self.ty.clone(),
+            self.params.iter().map(|param| param.ty.clone()).collect(),
+            self.is_variadic,
+        )
+    }
+}
+
+/// A translation unit is a list of these: its functions in the order they
+/// were first declared, followed by the objects in static storage.
+#[derive(Debug, Clone)]
+pub enum Decl {
+    Function(Box<Function>),
+    Var(GVar),
+}
+
+/// The functions of a translation unit.
+pub fn functions(decls: &[Decl]) -> impl Iterator<Item = &Function> {
+    decls.iter().filter_map(|decl| match decl {
+        Decl::Function(func) => Some(func.as_ref()),
+        Decl::Var(_) => None,
+    })
+}
+
+/// The objects in static storage of a translation unit.
+pub fn globals(decls: &[Decl]) -> impl Iterator<Item = &GVar> {
+    decls.iter().filter_map(|decl| match decl {
+        Decl::Var(gvar) => Some(gvar),
+        Decl::Function(_) => None,
+    })
+}
+
+/// A pass that reads the tree. Each method visits the children of its node
+/// by default; a pass overrides the ones it is interested in and calls the
+/// matching walk function to keep going into the children.
+pub trait Visitor {
+    fn visit_decl(&mut self, decl: &Decl) {
+        walk_decl(self, decl);
+    }
+
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        walk_stmt(self, stmt);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr);
+    }
+}
+
+pub fn walk_decl<V: Visitor + ?Sized>(visitor: &mut V, decl: &Decl) {
+    if let Decl::Function(func) = decl {
+        visitor.visit_stmt(&func.body);
+    }
+}
+
+pub fn walk_stmt<V: Visitor + ?Sized>(visitor: &mut V, stmt: &Stmt) {
+    match stmt {
+        Stmt::Expr(expr) | Stmt::MemZero(expr) => visitor.visit_expr(expr),
+        Stmt::Return(expr) => {
+            if let Some(expr) = expr {
+                visitor.visit_expr(expr);
+            }
+        }
+        Stmt::If { cond, then, els } => {
+            visitor.visit_expr(cond);
+            visitor.visit_stmt(then);
+            if let Some(els) = els {
+                visitor.visit_stmt(els);
+            }
+        }
+        Stmt::While { cond, body } => {
+            if let Some(cond) = cond {
+                visitor.visit_expr(cond);
+            }
+            visitor.visit_stmt(body);
+        }
+        Stmt::For {
+            init,
+            cond,
+            inc,
+            body,
+        } => {
+            for expr in [init, cond, inc].into_iter().flatten() {
+                visitor.visit_expr(expr);
+            }
+            visitor.visit_stmt(body);
+        }
+        Stmt::Block(body) => {
+            for stmt in body {
+                visitor.visit_stmt(stmt);
+            }
+        }
+    }
+}
+
+pub fn walk_expr<V: Visitor + ?Sized>(visitor: &mut V, expr: &Expr) {
+    match &expr.kind {
+        ExprKind::Num(_) | ExprKind::Local(_) | ExprKind::Global(_) => {}
+        ExprKind::Binary(_, lhs, rhs) => {
+            visitor.visit_expr(lhs);
+            visitor.visit_expr(rhs);
+        }
+        ExprKind::Assign(lhs, rhs) => {
+            walk_lvalue(visitor, lhs);
+            visitor.visit_expr(rhs);
+        }
+        ExprKind::Addr(lhs) | ExprKind::VaStart(lhs, _) | ExprKind::VaArg(lhs) => {
+            walk_lvalue(visitor, lhs)
+        }
+        ExprKind::Deref(lhs) | ExprKind::Cast(lhs) | ExprKind::Member(lhs, _) => {
+            visitor.visit_expr(lhs)
+        }
+        ExprKind::Call { callee, args, .. } => {
+            if let Callee::Indirect(callee) = callee {
+                visitor.visit_expr(callee);
+            }
+            for arg in args {
+                visitor.visit_expr(arg);
+            }
+        }
+    }
+}
+
+fn walk_lvalue<V: Visitor + ?Sized>(visitor: &mut V, lvalue: &LValue) {
+    match lvalue {
+        LValue::Local(_) | LValue::Global(_) => {}
+        LValue::Deref(lhs) => visitor.visit_expr(lhs),
+        LValue::Member(lhs, _) => walk_lvalue(visitor, lhs),
+    }
+}
+
+/// A pass that rewrites the tree. Each method rebuilds its node from the
+/// folded children by default, so a pass only overrides the nodes it
+/// replaces.
+pub trait Folder {
+    fn fold_decl(&mut self, decl: Decl) -> Decl {
+        fold_decl_children(self, decl)
+    }
+
+    fn fold_stmt(&mut self, stmt: Stmt) -> Stmt {
+        fold_stmt_children(self, stmt)
+    }
+
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        fold_expr_children(self, expr)
+    }
+}
+
+pub fn fold_decl_children<F: Folder + ?Sized>(folder: &mut F, decl: Decl) -> Decl {
+    match decl {
+        Decl::Function(func) => Decl::Function(Box::new(Function {
+            body: folder.fold_stmt(func.body),
+            ..*func
+        })),
+        Decl::Var(gvar) => Decl::Var(gvar),
+    }
+}
+
+pub fn fold_stmt_children<F: Folder + ?Sized>(folder: &mut F, stmt: Stmt) -> Stmt {
+    match stmt {
+        Stmt::Expr(expr) => Stmt::Expr(folder.fold_expr(expr)),
+        Stmt::MemZero(expr) => Stmt::MemZero(folder.fold_expr(expr)),
+        Stmt::Return(expr) => Stmt::Return(expr.map(|expr| folder.fold_expr(expr))),
+        Stmt::If { cond, then, els } => Stmt::If {
+            cond: folder.fold_expr(cond),
+            then: Box::new(folder.fold_stmt(*then)),
+            els: els.map(|els| Box::new(folder.fold_stmt(*els))),
+        },
+        Stmt::While { cond, body } => Stmt::While {
+            cond: cond.map(|cond| folder.fold_expr(cond)),
+            body: Box::new(folder.fold_stmt(*body)),
+        },
+        Stmt::For {
+            init,
+            cond,
+            inc,
+            body,
+        } => Stmt::For {
+            init: init.map(|init| fold_box(folder, init)),
+            cond: cond.map(|cond| fold_box(folder, cond)),
+            inc: inc.map(|inc| fold_box(folder, inc)),
+            body: Box::new(folder.fold_stmt(*body)),
+        },
+        Stmt::Block(body) => Stmt::Block(
+            body.into_iter()
+                .map(|stmt| folder.fold_stmt(stmt))
+                .collect(),
+        ),
+    }
+}
+
+fn fold_box<F: Folder + ?Sized>(folder: &mut F, expr: Box<Expr>) -> Box<Expr> {
+    Box::new(folder.fold_expr(*expr))
+}
+
+fn fold_lvalue<F: Folder + ?Sized>(folder: &mut F, lvalue: LValue) -> LValue {
+    match lvalue {
+        LValue::Local(_) | LValue::Global(_) => lvalue,
+        LValue::Deref(lhs) => LValue::Deref(fold_box(folder, lhs)),
+        LValue::Member(lhs, offset) => LValue::Member(Box::new(fold_lvalue(folder, *lhs)), offset),
+    }
+}
+
+pub fn fold_expr_children<F: Folder + ?Sized>(folder: &mut F, expr: Expr) -> Expr {
+    let kind = match expr.kind {
+        ExprKind::Num(_) | ExprKind::Local(_) | ExprKind::Global(_) => expr.kind,
+        ExprKind::Binary(op, lhs, rhs) => {
+            ExprKind::Binary(op, fold_box(folder, lhs), fold_box(folder, rhs))
+        }
+        ExprKind::Assign(lhs, rhs) => {
+            ExprKind::Assign(fold_lvalue(folder, lhs), fold_box(folder, rhs))
+        }
+        ExprKind::Addr(lhs) => ExprKind::Addr(fold_lvalue(folder, lhs)),
+        ExprKind::Deref(lhs) => ExprKind::Deref(fold_box(folder, lhs)),
+        ExprKind::Cast(lhs) => ExprKind::Cast(fold_box(folder, lhs)),
+        ExprKind::Member(lhs, offset) => ExprKind::Member(fold_box(folder, lhs), offset),
+        ExprKind::VaStart(lhs, offset) => ExprKind::VaStart(fold_lvalue(folder, lhs), offset),
+        ExprKind::VaArg(lhs) => ExprKind::VaArg(fold_lvalue(folder, lhs)),
+        ExprKind::Call {
+            callee,
+            args,
+            func_ty,
+        } => ExprKind::Call {
+            callee: match callee {
+                Callee::Direct(name) => Callee::Direct(name),
+                Callee::Indirect(callee) => Callee::Indirect(fold_box(folder, callee)),
+            },
+            args: args.into_iter().map(|arg| folder.fold_expr(arg)).collect(),
+            func_ty,
+        },
+    };
+    Expr::new(kind, expr.ty)
+}
@@ -1,176 +1,703 @@
-use crate::parse::{Node, NodeKind};
+use crate::ast::{BinOp, GVar};
+use crate::diag::{bail, Diagnostic};
+use crate::ir::{Block, BlockId, Callee, Function, Inst, Program, Reg, Terminator};
+use crate::regalloc::{self, Allocation, Loc};
+use std::collections::HashSet;
+use std::io::{self, Write};
+
+// Emits KTC32 assembly for IR. Virtual registers are kept where the register
+// allocator puts them; spilled ones are loaded into t0-t2 by each instruction
+// that uses them, and stored back by the one that defines them.
+//
+// Below the locals, the frame holds the spilled values and then the s
+// registers the function uses, which are restored before it returns.
+
+// Appends a line of assembly to the generator's output.
+macro_rules! emit {
+    ($gen:expr, $($arg:tt)*) => {
+        $gen.lines.push(format!($($arg)*))
+    };
+}
+
+fn load(size: i64, is_unsigned: bool) -> &'static str {
+    match (size, is_unsigned) {
+        (1, false) => "lb",
+        (1, true) => "lbu",
+        (2, false) => "lh",
+        (2, true) => "lhu",
+        _ => "lw",
+    }
+}
+
+fn store(size: i64) -> &'static str {
+    match size {
+        1 => "sb",
+        2 => "sh",
+        _ => "sw",
+    }
+}
+
+// Frames are addressed with 16-bit offsets from fp.
+const MAX_FRAME: i64 = 0x8000;
+
+// A word that a value is moved to or from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Place {
+    Reg(&'static str),
+    // The word at fp + offset.
+    Frame(i64),
+}
 
 #[derive(Debug)]
 pub struct CodeGenerator {
-    pub count: i64,
+    count: i64,
+    use_mul: bool,
+    use_div: bool,
+    // Bytes of locals in the function being generated, below which its
+    // spilled values are kept.
+    stack_size: i64,
+    // Where the virtual registers of the function being generated are.
+    alloc: Allocation,
+    // The output, one line of assembly per entry.
+    lines: Vec<String>,
+}
+
+impl Default for CodeGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
+const ARGREG: [&str; 4] = ["a0", "a1", "a2", "a3"];
+
 impl CodeGenerator {
     pub fn new() -> Self {
-        Self { count: 0 }
+        Self {
+            count: 0,
+            use_mul: false,
+            use_div: false,
+            stack_size: 0,
+            alloc: Allocation::default(),
+            lines: Vec::new(),
+        }
     }
 
-    fn gen_lval(&self, node: &Node) {
-        if node.kind != NodeKind::Lvar {
-            panic!(" Left side value is not local value");
+    // Writes the assembly generated so far.
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        for line in &self.lines {
+            writeln!(out, "{}", line)?;
         }
-
-        println!("  mov t0, fp");
-        println!("  addi t0, t0, -{}", node.offset);
-        println!("  addi sp, sp, -4");
-        println!("  sw t0, sp, 0",);
+        Ok(())
     }
 
-    pub fn gen(&mut self, node: &Node) {
-        match node.kind {
-            NodeKind::Num => {
-                println!("  addi sp, sp, -4");
-                println!("  addi t0, r0, {}", node.val);
-                println!("  sw t0, sp, 0",);
-                return;
+    // Sign- or zero-extend the low bytes of `src` into `dst` to match what a
+    // load of `size` bytes would produce.
+    fn truncate(&mut self, dst: &str, src: &str, size: i64, is_unsigned: bool) {
+        if size == 4 {
+            if dst != src {
+                emit!(self, "  mov {}, {}", dst, src);
             }
-            NodeKind::Lvar => {
-                self.gen_lval(node);
-                println!("  lw t0, sp, 0");
-                println!("  lw t1, t0, 0");
-                println!("  sw t1, sp, 0");
-                return;
+            return;
+        }
+        let shift = 32 - size * 8;
+        emit!(self, "  slli {}, {}, {}", dst, src, shift);
+        if is_unsigned {
+            emit!(self, "  srli {}, {}, {}", dst, dst, shift);
+        } else {
+            emit!(self, "  srai {}, {}, {}", dst, dst, shift);
+        }
+    }
+
+    // Loads a 32-bit constant. Values outside the 16-bit immediate range are built
+    // with lui; the upper half is adjusted because addi sign-extends its immediate.
+    fn gen_imm(&mut self, reg: &str, val: i64) {
+        let val = val as u32;
+        let lo = val as u16 as i16;
+        if lo as i32 as u32 == val {
+            emit!(self, "  addi {}, zero, {}", reg, lo);
+            return;
+        }
+        let hi = val.wrapping_sub(lo as i32 as u32) >> 16;
+        emit!(self, "  lui {}, {}", reg, hi);
+        if lo != 0 {
+            emit!(self, "  addi {}, {}, {}", reg, reg, lo);
+        }
+    }
+
+    fn flush_bytes(&mut self, bytes: &mut Vec<i64>) {
+        if bytes.is_empty() {
+            return;
+        }
+        let list: Vec<String> = bytes.iter().map(|byte| byte.to_string()).collect();
+        emit!(self, "  .byte {}", list.join(", "));
+        bytes.clear();
+    }
+
+    fn gen_epilogue(&mut self) {
+        for (i, reg) in self.alloc.saved.clone().into_iter().enumerate() {
+            emit!(self, "  lw {}, fp, -{}", reg, self.saved_offset(i));
+        }
+        emit!(self, "  mov sp, fp");
+        emit!(self, "  lw fp, sp, 0");
+        emit!(self, "  lw ra, sp, 4");
+        emit!(self, "  addi sp, sp, 8");
+        emit!(self, "  jalr zero, ra, 0");
+    }
+
+    // Emits the assembly of a translation unit. Functions and non-static
+    // globals are visible to other objects; every other label is local.
+    pub fn gen_program(&mut self, program: &Program) -> Result<(), Diagnostic> {
+        emit!(self, ".text");
+        for func in &program.functions {
+            if !func.is_static {
+                emit!(self, ".global {}", func.name);
             }
-            NodeKind::Assign => {
-                self.gen_lval(node.lhs.as_ref().unwrap());
-                self.gen(node.rhs.as_ref().unwrap());
+            self.gen_function(func)?;
+        }
+        self.gen_runtime();
+        let globals: Vec<&GVar> = program.globals.iter().collect();
+        self.gen_data(&globals);
+        Ok(())
+    }
 
-                println!("  lw a1, sp, 0");
-                println!("  lw a0, sp, 4");
-                println!("  sw a1, a0, 0");
-                println!("  addi sp, sp, 4");
-                return;
+    // The offsets below fp of a spill slot and of the word an s register is
+    // saved in.
+    fn spill_offset(&self, slot: usize) -> i64 {
+        self.stack_size + 4 * (slot as i64 + 1)
+    }
+
+    fn saved_offset(&self, i: usize) -> i64 {
+        self.spill_offset(self.alloc.spills + i)
+    }
+
+    fn place(&self, reg: Reg) -> Place {
+        match self.alloc.locs[reg.0] {
+            Loc::Reg(phys) => Place::Reg(phys),
+            Loc::Spill(slot) => Place::Frame(-self.spill_offset(slot)),
+        }
+    }
+
+    // The register that holds `reg`, which is loaded into `scratch` if it
+    // was spilled.
+    fn operand(&mut self, reg: Reg, scratch: &'static str) -> &'static str {
+        match self.alloc.locs[reg.0] {
+            Loc::Reg(phys) => phys,
+            Loc::Spill(slot) => {
+                emit!(self, "  lw {}, fp, -{}", scratch, self.spill_offset(slot));
+                scratch
             }
-            NodeKind::If => {
-                self.gen(node.cond.as_ref().unwrap());
-                println!("  lw a0, sp, 0");
-                println!("  addi sp, sp, 4");
-                println!("  addi t0, zero, 1");
-                println!("  beq a0, t0, 4");
-                println!("  jal zero, else{}", self.count);
-                self.gen(node.then.as_ref().unwrap());
-                println!("  jal zero, end{}", self.count);
-                println!("else{}:", self.count);
-                match &node.els {
-                    Some(_) => {
-                        self.gen(node.els.as_ref().unwrap());
-                    }
-                    None => {
-                        println!("  jal zero, end{}", self.count);
-                    }
+        }
+    }
+
+    // The register to compute `reg` in, which is `scratch` if it was
+    // spilled; `commit` then stores it.
+    fn target(&self, reg: Reg, scratch: &'static str) -> &'static str {
+        match self.alloc.locs[reg.0] {
+            Loc::Reg(phys) => phys,
+            Loc::Spill(_) => scratch,
+        }
+    }
+
+    // Moves a result from `phys` to where `reg` is kept.
+    fn commit(&mut self, reg: Reg, phys: &str) {
+        match self.alloc.locs[reg.0] {
+            Loc::Reg(dst) => {
+                if dst != phys {
+                    emit!(self, "  mov {}, {}", dst, phys);
                 }
-                println!("end{}:", self.count);
-                self.count += 1;
-                return;
             }
-            NodeKind::While => {
-                println!("begin{}:", self.count);
-                self.gen(node.cond.as_ref().unwrap());
-                println!("  lw a0, sp, 0");
-                println!("  addi sp, sp, 4");
-                println!("  addi t0, zero, 1");
-                println!("  beq a0, t0, 4");
-                println!("  jal zero, end{}", self.count);
-                self.gen(node.then.as_ref().unwrap());
-                println!("  jal zero, begin{}", self.count);
-                println!("end{}:", self.count);
-                self.count += 1;
-                return;
-            }
-            NodeKind::For => {
-                self.gen(node.init.as_ref().unwrap());
-                println!("begin{}:", self.count);
-                self.gen(node.cond.as_ref().unwrap());
-                println!("  lw a0, sp, 0");
-                println!("  addi sp, sp, 4");
-                println!("  addi t0, zero, 1");
-                println!("  beq a0, t0, 4");
-                println!("  jal zero, end{}", self.count);
-                self.gen(node.then.as_ref().unwrap());
-                self.gen(node.inc.as_ref().unwrap());
-                println!("  jal zero, begin{}", self.count);
-                println!("end{}:", self.count);
-                self.count += 1;
-                return;
+            Loc::Spill(slot) => emit!(self, "  sw {}, fp, -{}", phys, self.spill_offset(slot)),
+        }
+    }
+
+    // Does the moves as if all at once. Stores to the frame come first, then
+    // the moves between registers, in an order that reads each register
+    // before it is written and with t0 to break cycles, and then loads.
+    fn gen_moves(&mut self, moves: &[(Place, Place)]) {
+        let mut regs = Vec::new();
+        for &(dst, src) in moves {
+            match (dst, src) {
+                (Place::Frame(dst), Place::Reg(src)) => emit!(self, "  sw {}, fp, {}", src, dst),
+                (Place::Frame(dst), Place::Frame(src)) => {
+                    emit!(self, "  lw t0, fp, {}", src);
+                    emit!(self, "  sw t0, fp, {}", dst);
+                }
+                (Place::Reg(dst), Place::Reg(src)) if dst != src => regs.push((dst, src)),
+                _ => {}
             }
-            NodeKind::Return => {
-                self.gen(node.lhs.as_ref().unwrap());
-                println!("  lw a0, sp, 0");
-                println!("  addi sp, sp, 4");
-                println!("  mov sp, fp");
-                println!("  lw fp, sp, 0");
-                println!("  lw ra, sp, 4");
-                println!("  addi sp, sp, 8");
-                println!("  jalr zero, ra, 0");
-                return;
+        }
+        while !regs.is_empty() {
+            let ready = regs
+                .iter()
+                .position(|&(dst, _)| regs.iter().all(|&(_, src)| src != dst));
+            let i = match ready {
+                Some(i) => i,
+                None => {
+                    let (dst, _) = regs[0];
+                    emit!(self, "  mov t0, {}", dst);
+                    for (_, src) in regs.iter_mut() {
+                        if *src == dst {
+                            *src = "t0";
+                        }
+                    }
+                    0
+                }
+            };
+            let (dst, src) = regs.remove(i);
+            emit!(self, "  mov {}, {}", dst, src);
+        }
+        for &(dst, src) in moves {
+            if let (Place::Reg(dst), Place::Frame(src)) = (dst, src) {
+                emit!(self, "  lw {}, fp, {}", dst, src);
             }
+        }
+    }
 
-            _ => {}
+    // The first four arguments are passed in a0-a3 and the rest on the stack,
+    // the fifth one at the lowest address. s0-s3 are preserved across calls.
+    fn gen_function(&mut self, func: &Function) -> Result<(), Diagnostic> {
+        self.stack_size = func.stack_size;
+        self.alloc = regalloc::allocate(func);
+        let frame = self.saved_offset(self.alloc.saved.len()) - 4;
+        if frame >= MAX_FRAME {
+            bail!("Stack frame of {} is too large", func.name);
         }
 
-        self.gen(node.lhs.as_ref().unwrap());
-        self.gen(node.rhs.as_ref().unwrap());
+        emit!(self, "{}:", func.name);
+        emit!(self, "  addi sp, sp, -8");
+        emit!(self, "  sw ra, sp, 4");
+        emit!(self, "  sw fp, sp, 0");
+        emit!(self, "  mov fp, sp");
+        emit!(self, "  addi sp, sp, -{}", frame);
+        for (i, reg) in self.alloc.saved.clone().into_iter().enumerate() {
+            emit!(self, "  sw {}, fp, -{}", reg, self.saved_offset(i));
+        }
 
-        println!("  addi sp, sp, 8");
-        println!("  lw a1, sp, -8");
-        println!("  lw a0, sp, -4");
+        let moves: Vec<(Place, Place)> = func
+            .params
+            .iter()
+            .enumerate()
+            .map(|(i, &param)| {
+                let src = match ARGREG.get(i) {
+                    Some(reg) => Place::Reg(reg),
+                    None => Place::Frame(8 + 4 * (i - ARGREG.len()) as i64),
+                };
+                (self.place(param), src)
+            })
+            .collect();
+        self.gen_moves(&moves);
+
+        // Only blocks that are jumped to need a label.
+        let targets: HashSet<BlockId> = func
+            .blocks
+            .iter()
+            .flat_map(|block| block.term.successors())
+            .collect();
+        for (i, block) in func.blocks.iter().enumerate() {
+            if targets.contains(&BlockId(i)) {
+                emit!(self, "{}:", block_label(func, BlockId(i)));
+            }
+            self.gen_block(func, block, BlockId(i + 1));
+        }
+        Ok(())
+    }
 
-        match node.kind {
-            NodeKind::Add => {
-                println!("  add a0, a1");
+    // `next` is the block laid out after this one, which is reached without
+    // a jump.
+    fn gen_block(&mut self, func: &Function, block: &Block, next: BlockId) {
+        for inst in &block.insts {
+            self.gen_inst(func, inst);
+        }
+        match &block.term {
+            Terminator::Jump(target) => {
+                if *target != next {
+                    emit!(self, "  jal zero, {}", block_label(func, *target));
+                }
+            }
+            // Conditional branches only reach 32 KiB, so they skip over a
+            // jump instead.
+            Terminator::Branch { cond, then, els } => {
+                let cond = self.operand(*cond, "t0");
+                if *els == next {
+                    emit!(self, "  beq {}, zero, 4", cond);
+                    emit!(self, "  jal zero, {}", block_label(func, *then));
+                    return;
+                }
+                emit!(self, "  bnq {}, zero, 4", cond);
+                emit!(self, "  jal zero, {}", block_label(func, *els));
+                if *then != next {
+                    emit!(self, "  jal zero, {}", block_label(func, *then));
+                }
+            }
+            Terminator::Return(val) => {
+                if let Some(val) = val {
+                    let src = self.place(*val);
+                    self.gen_moves(&[(Place::Reg("a0"), src)]);
+                }
+                self.gen_epilogue();
+            }
+        }
+    }
+
+    fn gen_inst(&mut self, func: &Function, inst: &Inst) {
+        match inst {
+            Inst::Imm { dst, val } => {
+                let reg = self.target(*dst, "t0");
+                self.gen_imm(reg, *val);
+                self.commit(*dst, reg);
+            }
+            Inst::Frame { dst, offset } => {
+                let reg = self.target(*dst, "t0");
+                emit!(self, "  addi {}, fp, {}", reg, offset);
+                self.commit(*dst, reg);
+            }
+            Inst::Label { dst, label } => {
+                let reg = self.target(*dst, "t0");
+                emit!(self, "  la {}, {}", reg, label);
+                self.commit(*dst, reg);
+            }
+            Inst::Move { dst, src } => {
+                let moves = [(self.place(*dst), self.place(*src))];
+                self.gen_moves(&moves);
+            }
+            Inst::Load {
+                dst,
+                addr,
+                size,
+                is_unsigned,
+            } => {
+                let addr = self.operand(*addr, "t0");
+                let reg = self.target(*dst, "t0");
+                emit!(self, "  {} {}, {}, 0", load(*size, *is_unsigned), reg, addr);
+                self.commit(*dst, reg);
+            }
+            Inst::Store { addr, src, size } => {
+                let addr = self.operand(*addr, "t0");
+                let src = self.operand(*src, "t1");
+                emit!(self, "  {} {}, {}, 0", store(*size), src, addr);
+            }
+            Inst::Ext {
+                dst,
+                src,
+                size,
+                is_unsigned,
+            } => {
+                let src = self.operand(*src, "t0");
+                let reg = self.target(*dst, "t0");
+                self.truncate(reg, src, *size, *is_unsigned);
+                self.commit(*dst, reg);
+            }
+            // The runtime routines take their operands in a0 and a1.
+            Inst::Binary {
+                op: op @ (BinOp::Mul | BinOp::Div | BinOp::Mod),
+                dst,
+                lhs,
+                rhs,
+                is_unsigned,
+            } => {
+                let moves = [
+                    (Place::Reg("a0"), self.place(*lhs)),
+                    (Place::Reg("a1"), self.place(*rhs)),
+                ];
+                self.gen_moves(&moves);
+                let result = self.gen_runtime_call(*op, *is_unsigned);
+                self.commit(*dst, result);
+            }
+            Inst::Binary {
+                op,
+                dst,
+                lhs,
+                rhs,
+                is_unsigned,
+            } => {
+                let lhs = self.operand(*lhs, "t0");
+                let rhs = self.operand(*rhs, "t1");
+                let reg = self.target(*dst, "t0");
+                self.gen_binary(*op, *is_unsigned, reg, lhs, rhs);
+                self.commit(*dst, reg);
+            }
+            // Structs are copied byte by byte.
+            Inst::Copy { dst, src, size } => {
+                let dst = self.operand(*dst, "t1");
+                let src = self.operand(*src, "t2");
+                for i in 0..*size {
+                    emit!(self, "  lb t0, {}, {}", src, i);
+                    emit!(self, "  sb t0, {}, {}", dst, i);
+                }
+            }
+            // Small objects are cleared inline.
+            Inst::Zero { addr, size, width } => {
+                let addr = self.operand(*addr, "t0");
+                let op = store(*width);
+                if size / width <= 8 {
+                    for i in (0..*size).step_by(*width as usize) {
+                        emit!(self, "  {} zero, {}, {}", op, addr, i);
+                    }
+                    return;
+                }
+                let c = self.count;
+                self.count += 1;
+                if addr != "t0" {
+                    emit!(self, "  mov t0, {}", addr);
+                }
+                self.gen_imm("t1", *size);
+                emit!(self, "  add t1, t0");
+                let label = format!(".L{}.memzero{}", func.name, c);
+                emit!(self, "{}:", label);
+                emit!(self, "  {} zero, t0, 0", op);
+                emit!(self, "  addi t0, t0, {}", width);
+                emit!(self, "  bnq t0, t1, {}", label);
             }
-            NodeKind::Sub => {
-                println!("  sub a0, a1");
+            // Calls through a function pointer jump to the address in t2,
+            // which is not used for passing arguments.
+            Inst::Call {
+                dst,
+                callee,
+                args,
+                nregs,
+            } => {
+                let stack = &args[*nregs..];
+                if !stack.is_empty() {
+                    emit!(self, "  addi sp, sp, -{}", stack.len() * 4);
+                    for (i, &arg) in stack.iter().enumerate() {
+                        let arg = self.operand(arg, "t0");
+                        emit!(self, "  sw {}, sp, {}", arg, i * 4);
+                    }
+                }
+                let mut moves: Vec<(Place, Place)> = args[..*nregs]
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &arg)| (Place::Reg(ARGREG[i]), self.place(arg)))
+                    .collect();
+                if let Callee::Indirect(reg) = callee {
+                    moves.push((Place::Reg("t2"), self.place(*reg)));
+                }
+                self.gen_moves(&moves);
+                match callee {
+                    Callee::Direct(name) => emit!(self, "  jal ra, {}", name),
+                    Callee::Indirect(_) => emit!(self, "  jalr ra, t2, 0"),
+                }
+                if !stack.is_empty() {
+                    emit!(self, "  addi sp, sp, {}", stack.len() * 4);
+                }
+                self.commit(*dst, "a0");
             }
-            NodeKind::Mul => {
-                println!("  mov t0, a0");
-                println!("  addi a1, a1, -1");
-                println!("  beq a1, zero, 6");
-                println!("  add a0, t0");
-                println!("  jal zero, -14");
+        }
+    }
+
+    // Calls the runtime routine for a0 op a1 and returns the register that
+    // holds the result.
+    fn gen_runtime_call(&mut self, op: BinOp, is_unsigned: bool) -> &'static str {
+        if op == BinOp::Mul {
+            self.use_mul = true;
+            emit!(self, "  jal ra, __mul");
+            return "a0";
+        }
+        self.use_div = true;
+        if is_unsigned {
+            emit!(self, "  jal ra, __udivmod");
+        } else {
+            emit!(self, "  jal ra, __sdivmod");
+        }
+        if op == BinOp::Mod {
+            "a1"
+        } else {
+            "a0"
+        }
+    }
+
+    // Computes dst = lhs op rhs, leaving lhs and rhs alone unless one of
+    // them is dst. A result that would overwrite an operand before it is
+    // read is built in t2.
+    fn gen_binary(&mut self, op: BinOp, is_unsigned: bool, dst: &str, lhs: &str, rhs: &str) {
+        let slt = if is_unsigned { "sltu" } else { "slt" };
+        // Register-register instructions overwrite their first operand, so
+        // they can be done in place when that is dst.
+        let in_place = matches!(op, BinOp::Add | BinOp::Sub | BinOp::Shl | BinOp::Shr);
+        let reg = if (dst == lhs && !in_place) || (dst == rhs && dst != lhs) {
+            "t2"
+        } else {
+            dst
+        };
+        match op {
+            BinOp::Add | BinOp::Sub | BinOp::Shl | BinOp::Shr => {
+                let name = match op {
+                    BinOp::Add => "add",
+                    BinOp::Sub => "sub",
+                    BinOp::Shl => "sll",
+                    _ if is_unsigned => "srl",
+                    _ => "sra",
+                };
+                if reg != lhs {
+                    emit!(self, "  mov {}, {}", reg, lhs);
+                }
+                emit!(self, "  {} {}, {}", name, reg, rhs);
             }
-            NodeKind::Div => {
-                println!("  mov t0, zero");
-                println!("  blt a0, a1, 10");
-                println!("  addi t0, t0, 1");
-                println!("  sub a0, a1");
-                println!("  jal zero, -14");
-                println!("  mov a0, t0");
+            BinOp::Eq | BinOp::Ne => {
+                let branch = if op == BinOp::Eq { "beq" } else { "bnq" };
+                emit!(self, "  mov {}, zero", reg);
+                emit!(self, "  {} {}, {}, 4", branch, lhs, rhs);
+                emit!(self, "  addi {}, {}, -1", reg, reg);
+                emit!(self, "  addi {}, {}, 1", reg, reg);
             }
-            NodeKind::Eq => {
-                println!("  mov t0, zero");
-                println!("  beq a0, a1, 4");
-                println!("  addi t0, t0, -1");
-                println!("  addi t0, t0, 1");
-                println!("  mov a0, t0");
+            BinOp::Lt => {
+                emit!(self, "  {} {}, {}", slt, lhs, rhs);
+                emit!(self, "  mov {}, flag", dst);
+                return;
             }
-            NodeKind::Ne => {
-                println!("  mov t0, zero");
-                println!("  bnq a0, a1, 4");
-                println!("  addi t0, t0, -1");
-                println!("  addi t0, t0, 1");
-                println!("  mov a0, t0");
+            BinOp::Le => {
+                emit!(self, "  mov {}, zero", reg);
+                emit!(self, "  {} {}, {}", slt, rhs, lhs);
+                emit!(self, "  bnq flag, zero, 4");
+                emit!(self, "  addi {}, zero, 1", reg);
             }
-            NodeKind::Lt => {
-                println!("  slt a0, a1");
-                println!("  mov a0, flag");
+            BinOp::Mul | BinOp::Div | BinOp::Mod => unreachable!(),
+        }
+        if reg != dst {
+            emit!(self, "  mov {}, {}", dst, reg);
+        }
+    }
+
+    // Static objects go to .data, const ones to .rodata so that writes trap on
+    // targets that map it read-only.
+    fn gen_data(&mut self, globals: &[&GVar]) {
+        for section in [".data", ".rodata"] {
+            let vars: Vec<&GVar> = globals
+                .iter()
+                .copied()
+                .filter(|gvar| (section == ".rodata") == gvar.ty.is_const)
+                .collect();
+            if vars.is_empty() {
+                continue;
             }
-            NodeKind::Le => {
-                println!("  mov t0, zero");
-                println!("  slt a1, a0");
-                println!("  bnq flag, zero, 4");
-                println!("  addi t0, zero, 1");
-                println!("  mov a0, t0");
+            emit!(self, "{}", section);
+            for gvar in vars {
+                if !gvar.is_static {
+                    emit!(self, ".global {}", gvar.label);
+                }
+                emit!(self, "  .align {}", gvar.ty.align);
+                emit!(self, "{}:", gvar.label);
+                // Runs of bytes are emitted on one line, as strings are.
+                let mut bytes = Vec::new();
+                let mut offset = 0;
+                for init in &gvar.init {
+                    if init.size != 1 || init.offset != offset {
+                        self.flush_bytes(&mut bytes);
+                    }
+                    if init.offset > offset {
+                        emit!(self, "  .zero {}", init.offset - offset);
+                    }
+                    offset = init.offset + init.size;
+                    match (&init.label, init.size) {
+                        (None, 1) => bytes.push(init.val),
+                        (None, 2) => emit!(self, "  .half {}", init.val),
+                        (None, _) => emit!(self, "  .word {}", init.val),
+                        (Some(label), _) if init.val > 0 => {
+                            emit!(self, "  .word {}+{}", label, init.val)
+                        }
+                        (Some(label), _) if init.val < 0 => {
+                            emit!(self, "  .word {}{}", label, init.val)
+                        }
+                        (Some(label), _) => emit!(self, "  .word {}", label),
+                    }
+                }
+                self.flush_bytes(&mut bytes);
+                if gvar.ty.size > offset {
+                    emit!(self, "  .zero {}", gvar.ty.size - offset);
+                }
             }
+        }
+    }
 
-            _ => {}
+    // Runtime routines for operations KTC32 has no instruction for.
+    // Arguments are passed in a0 and a1; t0-t2, a2 and flag are clobbered.
+    fn gen_runtime(&mut self) {
+        if self.use_mul {
+            // a0 = a0 * a1
+            emit!(self, "__mul:");
+            emit!(self, "  mov t0, zero");
+            emit!(self, "__mul_loop:");
+            emit!(self, "  beq a1, zero, __mul_end");
+            emit!(self, "  andi t1, a1, 1");
+            emit!(self, "  beq t1, zero, __mul_skip");
+            emit!(self, "  add t0, a0");
+            emit!(self, "__mul_skip:");
+            emit!(self, "  slli a0, a0, 1");
+            emit!(self, "  srli a1, a1, 1");
+            emit!(self, "  jal zero, __mul_loop");
+            emit!(self, "__mul_end:");
+            emit!(self, "  mov a0, t0");
+            emit!(self, "  jalr zero, ra, 0");
+        }
+        if self.use_div {
+            // a0 = a0 / a1, a1 = a0 % a1 (unsigned)
+            emit!(self, "__udivmod:");
+            emit!(self, "  mov t0, zero");
+            emit!(self, "  mov t1, zero");
+            emit!(self, "  addi t2, zero, 32");
+            emit!(self, "__udivmod_loop:");
+            emit!(self, "  slt t1, zero");
+            emit!(self, "  mov a2, flag");
+            emit!(self, "  slli t1, t1, 1");
+            emit!(self, "  slt a0, zero");
+            emit!(self, "  add t1, flag");
+            emit!(self, "  slli a0, a0, 1");
+            emit!(self, "  slli t0, t0, 1");
+            emit!(self, "  bnq a2, zero, __udivmod_sub");
+            emit!(self, "  sltu t1, a1");
+            emit!(self, "  bnq flag, zero, __udivmod_next");
+            emit!(self, "__udivmod_sub:");
+            emit!(self, "  sub t1, a1");
+            emit!(self, "  addi t0, t0, 1");
+            emit!(self, "__udivmod_next:");
+            emit!(self, "  addi t2, t2, -1");
+            emit!(self, "  bnq t2, zero, __udivmod_loop");
+            emit!(self, "  mov a0, t0");
+            emit!(self, "  mov a1, t1");
+            emit!(self, "  jalr zero, ra, 0");
+
+            // a0 = a0 / a1, a1 = a0 % a1 (signed, truncating toward zero)
+            emit!(self, "__sdivmod:");
+            emit!(self, "  addi sp, sp, -12");
+            emit!(self, "  sw ra, sp, 8");
+            emit!(self, "  sw a0, sp, 4");
+            emit!(self, "  mov t0, a0");
+            emit!(self, "  xor t0, a1");
+            emit!(self, "  sw t0, sp, 0");
+            emit!(self, "  blt zero, a0, __sdivmod_l0");
+            emit!(self, "  mov t0, zero");
+            emit!(self, "  sub t0, a0");
+            emit!(self, "  mov a0, t0");
+            emit!(self, "__sdivmod_l0:");
+            emit!(self, "  blt zero, a1, __sdivmod_l1");
+            emit!(self, "  mov t0, zero");
+            emit!(self, "  sub t0, a1");
+            emit!(self, "  mov a1, t0");
+            emit!(self, "__sdivmod_l1:");
+            emit!(self, "  jal ra, __udivmod");
+            emit!(self, "  addi t1, zero, -1");
+            emit!(self, "  lw t0, sp, 0");
+            emit!(self, "  blt t1, t0, __sdivmod_l2");
+            emit!(self, "  mov t0, zero");
+            emit!(self, "  sub t0, a0");
+            emit!(self, "  mov a0, t0");
+            emit!(self, "__sdivmod_l2:");
+            emit!(self, "  lw t0, sp, 4");
+            emit!(self, "  blt t1, t0, __sdivmod_l3");
+            emit!(self, "  mov t0, zero");
+            emit!(self, "  sub t0, a1");
+            emit!(self, "  mov a1, t0");
+            emit!(self, "__sdivmod_l3:");
+            emit!(self, "  lw ra, sp, 8");
+            emit!(self, "  addi sp, sp, 12");
+            emit!(self, "  jalr zero, ra, 0");
         }
-        println!("  addi sp, sp, -4");
-        println!("  sw a0, sp, 0");
     }
 }
+
+// Blocks are local labels named after their function, which keeps them
+// apart from the labels of globals and string literals. The loops that zero
+// memory are labelled the same way.
+fn block_label(func: &Function, block: BlockId) -> String {
+    format!(".L{}.{}", func.name, block.0)
+}
@@ -0,0 +1,86 @@
+use std::error::Error;
+use std::fmt;
+
+// Every stage returns errors in the program as a Diagnostic, and `?` passes
+// them on to the caller. Panics are left to bugs in the compiler.
+
+/// A line of a source file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// An error in the program being compiled, linked or assembled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    /// Where in the source the error is, if it comes from one.
+    pub location: Option<Location>,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>) -> Self {
+        Diagnostic {
+            message: message.into(),
+            location: None,
+        }
+    }
+
+    /// Places the error at `location`, unless it already has a location,
+    /// which is the more precise one.
+    pub fn at(mut self, location: &Location) -> Self {
+        if self.location.is_none() {
+            self.location = Some(location.clone());
+        }
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{}: {}", location, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl Error for Diagnostic {}
+
+/// The errors that stopped compilation. The compiler stops at the first
+/// error, so there is currently always exactly one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostics {
+    pub errors: Vec<Diagnostic>,
+}
+
+impl From<Diagnostic> for Diagnostics {
+    fn from(diag: Diagnostic) -> Self {
+        Diagnostics { errors: vec![diag] }
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let messages: Vec<String> = self.errors.iter().map(|diag| diag.to_string()).collect();
+        write!(f, "{}", messages.join("\n"))
+    }
+}
+
+impl Error for Diagnostics {}
+
+// Returns a diagnostic with a formatted message from the enclosing function.
+macro_rules! bail {
+    ($($arg:tt)*) => {
+        return Err($crate::diag::Diagnostic::new(format!($($arg)*)))
+    };
+}
+
+pub(crate) use bail;
@@ -0,0 +1,678 @@
+use std::fmt::Write;
+use std::panic;
+use std::sync::Mutex;
+
+use ktc32_com::interp::interpret;
+use ktc32_com::{compile, Options};
+
+// Random program generation for differential testing, and fuzzing of the
+// front end on arbitrary input.
+
+// xorshift64*, so that a seed always gives the same programs.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // A number in 0..n.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() >> 33) as usize % n
+    }
+
+    pub fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+#[derive(Clone)]
+enum Kind {
+    Scalar(&'static str),
+    // An int array of this length.
+    Array(usize),
+    // An int pointer, always pointing at a valid int.
+    Ptr,
+}
+
+#[derive(Clone)]
+struct Var {
+    name: String,
+    kind: Kind,
+}
+
+const SCALAR_TYPES: [&str; 6] = [
+    "int",
+    "int",
+    "unsigned int",
+    "char",
+    "unsigned char",
+    "short",
+];
+
+const COMPARISONS: [&str; 6] = ["==", "!=", "<", "<=", ">", ">="];
+
+const HELPERS: [&str; 8] = [
+    "safe_add",
+    "safe_sub",
+    "safe_mul",
+    "safe_shl",
+    "safe_shr",
+    "safe_div",
+    "safe_mod",
+    "safe_udiv",
+];
+
+// Arithmetic that is defined for every operand, in the style of Csmith.
+// Signed operations are done on unsigned values, which wrap around, and
+// converted back to int keeping their bits, as KTC32 does. Shift counts are
+// reduced to 0..31, and the divisions that would overflow or divide by zero
+// have fixed results.
+const PRELUDE: &str = "\
+int safe_add(int a, int b) {
+\tunsigned int x = a;
+\tunsigned int y = b;
+\treturn x + y;
+}
+int safe_sub(int a, int b) {
+\tunsigned int x = a;
+\tunsigned int y = b;
+\treturn x - y;
+}
+int safe_mul(int a, int b) {
+\tunsigned int x = a;
+\tunsigned int y = b;
+\treturn x * y;
+}
+int safe_shl(int a, int b) {
+\tunsigned int x = a;
+\treturn x << ((b % 32 + 32) % 32);
+}
+int safe_shr(int a, int b) {
+\treturn a >> ((b % 32 + 32) % 32);
+}
+int safe_div(int a, int b) {
+\tif (b == 0)
+\t\treturn a;
+\tif (b == -1)
+\t\treturn safe_sub(0, a);
+\treturn a / b;
+}
+int safe_mod(int a, int b) {
+\tif (b == 0)
+\t\treturn a;
+\tif (b == -1)
+\t\treturn 0;
+\treturn a % b;
+}
+unsigned int safe_udiv(unsigned int a, unsigned int b) {
+\tif (b == 0)
+\t\treturn a;
+\treturn a / b;
+}
+";
+
+// Generates programs whose behaviour is fully defined: arithmetic goes
+// through the helpers above, array indexes are reduced into range, pointers
+// always point at live ints, loops have small constant bounds, and functions
+// only call the ones defined before them, so every program terminates. Each
+// statement is on its own line so that failing programs can be minimized by
+// deleting lines.
+pub struct Generator {
+    rng: Rng,
+    out: String,
+    globals: Vec<Var>,
+    locals: Vec<Var>,
+    // Name and parameter count of the functions defined so far.
+    functions: Vec<(String, usize)>,
+    indent: usize,
+    loop_depth: usize,
+    count: usize,
+}
+
+impl Generator {
+    pub fn new(seed: u64) -> Generator {
+        Generator {
+            rng: Rng::new(seed),
+            out: String::new(),
+            globals: Vec::new(),
+            locals: Vec::new(),
+            functions: Vec::new(),
+            indent: 0,
+            loop_depth: 0,
+            count: 0,
+        }
+    }
+
+    fn fresh(&mut self, prefix: &str) -> String {
+        self.count += 1;
+        format!("{}{}", prefix, self.count)
+    }
+
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.indent {
+            self.out.push('\t');
+        }
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    fn vars(&self) -> impl Iterator<Item = &Var> {
+        self.locals.iter().chain(self.globals.iter())
+    }
+
+    fn literal(&mut self) -> String {
+        match self.rng.below(6) {
+            0 => format!("{:#x}", self.rng.next_u64() as u32),
+            1 => format!("{}", self.rng.next_u64() as u32 as i32),
+            2 => format!("{}", self.rng.below(40) as i64 - 20),
+            _ => format!("{}", self.rng.below(10)),
+        }
+    }
+
+    // A non-negative int below `len`, from any expression.
+    fn index(&mut self, depth: usize, len: usize) -> String {
+        // Elements used as leaves are indexed by a literal, so that indices
+        // do not nest without end.
+        let expr = if depth >= 3 {
+            self.literal()
+        } else {
+            self.expr(depth + 1)
+        };
+        format!("(({}) % {} + {}) % {}", expr, len, len, len)
+    }
+
+    fn expr(&mut self, depth: usize) -> String {
+        let leaf = depth >= 3 || self.rng.chance(30);
+        let choice = if leaf {
+            self.rng.below(3)
+        } else {
+            3 + self.rng.below(5)
+        };
+        match choice {
+            0 => self.literal(),
+            1 | 2 => {
+                let vars: Vec<Var> = self.vars().cloned().collect();
+                if vars.is_empty() {
+                    return self.literal();
+                }
+                let var = self.rng.pick(&vars).clone();
+                match var.kind {
+                    Kind::Scalar(_) => var.name,
+                    Kind::Array(len) => {
+                        let index = self.index(depth, len);
+                        format!("{}[{}]", var.name, index)
+                    }
+                    Kind::Ptr => format!("*{}", var.name),
+                }
+            }
+            3 => {
+                let expr = self.expr(depth + 1);
+                format!("safe_sub(0, {})", expr)
+            }
+            4..=6 => {
+                let helper = *self.rng.pick(&HELPERS);
+                let lhs = self.expr(depth + 1);
+                let rhs = self.expr(depth + 1);
+                format!("{}({}, {})", helper, lhs, rhs)
+            }
+            _ => {
+                let op = *self.rng.pick(&COMPARISONS);
+                let lhs = self.expr(depth + 1);
+                let rhs = self.expr(depth + 1);
+                format!("({} {} {})", lhs, op, rhs)
+            }
+        }
+    }
+
+    // An lvalue that can be assigned to.
+    fn target(&mut self) -> Option<String> {
+        let vars: Vec<Var> = self.vars().cloned().collect();
+        if vars.is_empty() {
+            return None;
+        }
+        let var = self.rng.pick(&vars).clone();
+        Some(match var.kind {
+            Kind::Scalar(_) => var.name,
+            Kind::Array(len) => {
+                let index = self.index(1, len);
+                format!("{}[{}]", var.name, index)
+            }
+            Kind::Ptr => format!("*{}", var.name),
+        })
+    }
+
+    // Calls are not made inside loops, which keeps the work done by a
+    // program linear in its size.
+    fn call(&mut self) -> Option<String> {
+        if self.functions.is_empty() || self.loop_depth > 0 {
+            return None;
+        }
+        let (name, nparams) = self.rng.pick(&self.functions).clone();
+        let args: Vec<String> = (0..nparams).map(|_| self.expr(1)).collect();
+        Some(format!("{}({})", name, args.join(", ")))
+    }
+
+    fn stmt(&mut self, depth: usize) {
+        match self.rng.below(10) {
+            0 | 1 if depth < 2 => {
+                let cond = self.expr(1);
+                self.line(&format!("if ({}) {{", cond));
+                self.block(depth + 1);
+                if self.rng.chance(50) {
+                    self.line("} else {");
+                    self.block(depth + 1);
+                }
+                self.line("}");
+            }
+            2 if depth < 2 && self.loop_depth < 2 => {
+                // The counter is not added to the variables, so that nothing
+                // in the body assigns to it.
+                let counter = self.fresh("i");
+                let bound = self.rng.below(5) + 1;
+                self.line(&format!("int {} = 0;", counter));
+                self.line(&format!(
+                    "for ({} = 0; {} < {}; {} = {} + 1) {{",
+                    counter, counter, bound, counter, counter
+                ));
+                self.loop_depth += 1;
+                self.loop_body(depth + 1, &counter);
+                self.loop_depth -= 1;
+                self.line("}");
+            }
+            3 => match (self.target(), self.call()) {
+                (Some(target), Some(call)) => self.line(&format!("{} = {};", target, call)),
+                _ => self.assign(),
+            },
+            _ => self.assign(),
+        }
+    }
+
+    fn assign(&mut self) {
+        if let Some(target) = self.target() {
+            let expr = self.expr(0);
+            self.line(&format!("{} = {};", target, expr));
+        }
+    }
+
+    fn block(&mut self, depth: usize) {
+        self.indent += 1;
+        for _ in 0..self.rng.below(3) + 1 {
+            self.stmt(depth);
+        }
+        self.indent -= 1;
+    }
+
+    // Some statements of the body accumulate the loop counter.
+    fn loop_body(&mut self, depth: usize, counter: &str) {
+        self.indent += 1;
+        for _ in 0..self.rng.below(3) + 1 {
+            if self.rng.chance(30) {
+                if let Some(target) = self.target() {
+                    self.line(&format!("{} = safe_add({}, {});", target, target, counter));
+                    continue;
+                }
+            }
+            self.stmt(depth);
+        }
+        self.indent -= 1;
+    }
+
+    // Declares a variable of a random kind in the current scope.
+    fn declare(&mut self, is_global: bool) {
+        let name = self.fresh(if is_global { "g" } else { "v" });
+        let ints: Vec<String> = self
+            .vars()
+            .filter(|var| matches!(var.kind, Kind::Scalar("int")))
+            .map(|var| var.name.clone())
+            .collect();
+        let kind = match self.rng.below(8) {
+            0 => Kind::Array(self.rng.below(6) + 1),
+            1 if !ints.is_empty() && !is_global => Kind::Ptr,
+            _ => Kind::Scalar(SCALAR_TYPES[self.rng.below(SCALAR_TYPES.len())]),
+        };
+        match kind {
+            Kind::Scalar(ty) => {
+                let init = self.literal();
+                self.line(&format!("{} {} = {};", ty, name, init));
+            }
+            Kind::Array(len) => {
+                let values: Vec<String> = (0..len).map(|_| self.literal()).collect();
+                self.line(&format!(
+                    "int {}[{}] = {{{}}};",
+                    name,
+                    len,
+                    values.join(", ")
+                ));
+            }
+            Kind::Ptr => {
+                let pointee = self.rng.pick(&ints).clone();
+                self.line(&format!("int *{} = &{};", name, pointee));
+            }
+        }
+        let var = Var { name, kind };
+        if is_global {
+            self.globals.push(var);
+        } else {
+            self.locals.push(var);
+        }
+    }
+
+    // Up to six parameters, so that some are passed on the stack.
+    fn function(&mut self) {
+        let name = self.fresh("f");
+        let nparams = self.rng.below(7);
+        let params: Vec<String> = (0..nparams).map(|_| self.fresh("p")).collect();
+        self.locals = params
+            .iter()
+            .map(|param| Var {
+                name: param.clone(),
+                kind: Kind::Scalar("int"),
+            })
+            .collect();
+        let decl: Vec<String> = params.iter().map(|p| format!("int {}", p)).collect();
+        self.line(&format!("int {}({}) {{", name, decl.join(", ")));
+        self.indent += 1;
+        for _ in 0..self.rng.below(4) {
+            self.declare(false);
+        }
+        for _ in 0..self.rng.below(6) + 1 {
+            self.stmt(0);
+        }
+        let ret = self.expr(0);
+        self.line(&format!("return {};", ret));
+        self.indent -= 1;
+        self.line("}");
+        self.functions.push((name, nparams));
+    }
+
+    // main folds every global into the value it returns.
+    pub fn program(mut self) -> String {
+        self.out.push_str(PRELUDE);
+        for _ in 0..self.rng.below(5) + 1 {
+            self.declare(true);
+        }
+        for _ in 0..self.rng.below(4) {
+            self.function();
+        }
+
+        self.locals.clear();
+        self.line("int main() {");
+        self.indent += 1;
+        for _ in 0..self.rng.below(6) + 1 {
+            self.stmt(0);
+        }
+        self.line("int h = 0;");
+        let globals = self.globals.clone();
+        for var in &globals {
+            match var.kind {
+                Kind::Array(len) => {
+                    for i in 0..len {
+                        self.line(&format!(
+                            "h = safe_add(safe_mul(h, 31), {}[{}]);",
+                            var.name, i
+                        ));
+                    }
+                }
+                _ => self.line(&format!("h = safe_add(safe_mul(h, 31), {});", var.name)),
+            }
+        }
+        self.line("return h;");
+        self.indent -= 1;
+        self.line("}");
+        self.out
+    }
+}
+
+pub fn generate(seed: u64) -> String {
+    Generator::new(seed).program()
+}
+
+// Deletes lines from a program for as long as it keeps failing, trying large
+// chunks first.
+pub fn minimize(program: &str, mut fails: impl FnMut(&str) -> bool) -> String {
+    let mut lines: Vec<&str> = program.lines().collect();
+    let mut chunk = lines.len() / 2;
+    while chunk > 0 {
+        let mut start = 0;
+        while start < lines.len() {
+            let end = (start + chunk).min(lines.len());
+            let candidate: Vec<&str> = [&lines[..start], &lines[end..]].concat();
+            if fails(&(candidate.join("\n") + "\n")) {
+                lines = candidate;
+            } else {
+                start += chunk;
+            }
+        }
+        chunk /= 2;
+    }
+    lines.join("\n") + "\n"
+}
+
+const TOKENS: [&str; 49] = [
+    "int",
+    "char",
+    "short",
+    "unsigned",
+    "void",
+    "struct",
+    "static",
+    "extern",
+    "const",
+    "if",
+    "else",
+    "for",
+    "while",
+    "return",
+    "sizeof",
+    "x",
+    "y",
+    "f",
+    "main",
+    "0",
+    "42",
+    "0x1f",
+    "'a'",
+    "\"s\"",
+    "+",
+    "-",
+    "*",
+    "/",
+    "&",
+    "=",
+    "==",
+    "<",
+    ">>",
+    "(",
+    ")",
+    "{",
+    "}",
+    "[",
+    "]",
+    ";",
+    "\n#define",
+    "\n#if",
+    "\n#ifdef",
+    "\n#else\n",
+    "\n#endif\n",
+    "#",
+    "##",
+    "defined",
+    "__LINE__",
+];
+
+// Characters that take more than one byte in UTF-8, whitespace among them.
+const WIDE_CHARS: [char; 6] = ['\u{a0}', '\u{3000}', '\u{2028}', 'é', 'λ', '😀'];
+
+fn push_wide_char(rng: &mut Rng, bytes: &mut Vec<u8>, at: usize) {
+    let mut buf = [0; 4];
+    let c = rng.pick(&WIDE_CHARS).encode_utf8(&mut buf);
+    bytes.splice(at..at, c.bytes());
+}
+
+// Arbitrary input for the front end: random bytes and characters, soups of
+// tokens, and generated programs with pieces cut out or repeated.
+pub fn random_input(rng: &mut Rng) -> Vec<u8> {
+    match rng.below(3) {
+        0 => {
+            let mut bytes = Vec::new();
+            for _ in 0..rng.below(64) {
+                if rng.chance(80) {
+                    bytes.push(*rng.pick(b" \n\t{}()[];,.+-*/&<>=!#'\"\\0123456789abcdefxyz_"));
+                } else if rng.chance(50) {
+                    let end = bytes.len();
+                    push_wide_char(rng, &mut bytes, end);
+                } else {
+                    bytes.push(rng.next_u64() as u8);
+                }
+            }
+            bytes
+        }
+        1 => {
+            let mut out = String::new();
+            for _ in 0..rng.below(40) {
+                out.push_str(TOKENS[rng.below(TOKENS.len())]);
+                out.push(if rng.chance(90) {
+                    ' '
+                } else {
+                    *rng.pick(&WIDE_CHARS)
+                });
+            }
+            out.into_bytes()
+        }
+        _ => {
+            let mut bytes = generate(rng.next_u64()).into_bytes();
+            for _ in 0..rng.below(4) + 1 {
+                let start = rng.below(bytes.len());
+                let end = (start + rng.below(16)).min(bytes.len());
+                match rng.below(3) {
+                    0 => {
+                        bytes.drain(start..end);
+                    }
+                    1 => {
+                        let piece = bytes[start..end].to_vec();
+                        bytes.splice(start..start, piece);
+                    }
+                    _ => push_wide_char(rng, &mut bytes, start),
+                }
+            }
+            bytes
+        }
+    }
+}
+
+static LAST_PANIC: Mutex<String> = Mutex::new(String::new());
+
+// Silences the report of caught panics, remembering the last one so that
+// bugs can be described.
+pub fn quiet_panics() {
+    panic::set_hook(Box::new(|info| {
+        *LAST_PANIC.lock().unwrap() = info.to_string();
+    }));
+}
+
+// The location and message of the last panic caught since quiet_panics.
+pub fn last_panic() -> String {
+    LAST_PANIC.lock().unwrap().clone()
+}
+
+// Compiles an input. Errors in it are expected; returns a description of
+// the bug if the compiler panics instead.
+pub fn check_front_end(input: &[u8]) -> Result<(), String> {
+    let source = String::from_utf8_lossy(input);
+    panic::catch_unwind(|| {
+        let _ = compile(&source, &Options::default());
+    })
+    .map_err(|_| last_panic())
+}
+
+// Fuzzes the front end with `iterations` inputs, returning the failing
+// inputs with what went wrong.
+pub fn fuzz_front_end(seed: u64, iterations: usize) -> Vec<(Vec<u8>, String)> {
+    let mut rng = Rng::new(seed);
+    let mut failures = Vec::new();
+    for _ in 0..iterations {
+        let input = random_input(&mut rng);
+        if let Err(report) = check_front_end(&input) {
+            let mut escaped = String::new();
+            for &byte in &input {
+                write!(escaped, "{}", (byte as char).escape_default()).unwrap();
+            }
+            failures.push((input, format!("{}\ninput: \"{}\"", report, escaped)));
+        }
+    }
+    failures
+}
+
+// What the interpreter says a program returns, or None if it is not a valid
+// program.
+fn interpret_source(source: &str) -> Option<i32> {
+    let output = compile(source, &Options::default()).ok()?;
+    interpret(&output.decls).ok()
+}
+
+enum Verdict {
+    Agree,
+    Invalid,
+    Mismatch(String),
+}
+
+fn check_program(source: &str, compile: &mut impl FnMut(&str) -> Result<i32, String>) -> Verdict {
+    let expected = match panic::catch_unwind(|| interpret_source(source)) {
+        Ok(Some(expected)) => expected,
+        Ok(None) => return Verdict::Invalid,
+        Err(_) => return Verdict::Mismatch(format!("compiler panicked: {}", last_panic())),
+    };
+    match compile(source) {
+        Ok(val) if val == expected => Verdict::Agree,
+        Ok(val) => Verdict::Mismatch(format!(
+            "interpreter returned {}, compiled program returned {}",
+            expected, val
+        )),
+        Err(err) => Verdict::Mismatch(format!(
+            "interpreter returned {}, compiled program failed: {}",
+            expected,
+            err.trim()
+        )),
+    }
+}
+
+// Runs generated programs both in the interpreter and through `compile`,
+// which builds and runs a program and returns what main returned. Returns a
+// report of each disagreement, with the program minimized.
+pub fn fuzz_programs(
+    seed: u64,
+    iterations: usize,
+    mut compile: impl FnMut(&str) -> Result<i32, String>,
+) -> Vec<String> {
+    let mut failures = Vec::new();
+    for i in 0..iterations as u64 {
+        let program = generate(seed + i);
+        match check_program(&program, &mut compile) {
+            Verdict::Agree => {}
+            Verdict::Invalid => failures.push(format!(
+                "seed {}: generated program is invalid\n{}",
+                seed + i,
+                program
+            )),
+            Verdict::Mismatch(report) => {
+                let program = minimize(&program, |candidate| {
+                    matches!(check_program(candidate, &mut compile), Verdict::Mismatch(_))
+                });
+                failures.push(format!("seed {}: {}\n{}", seed + i, report, program));
+            }
+        }
+    }
+    failures
+}
@@ -0,0 +1,108 @@
+use std::fmt::Write;
+
+use crate::diag::{bail, Diagnostic};
+
+/// How an assembled image is written out.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Format {
+    /// The bytes as they are.
+    #[default]
+    Bin,
+    /// Intel HEX records.
+    IHex,
+    /// One hex word per line, for Verilog's $readmemh.
+    ReadMemH,
+}
+
+impl Format {
+    /// The format named on the command line: bin, ihex or readmemh.
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "bin" => Some(Format::Bin),
+            "ihex" | "hex" => Some(Format::IHex),
+            "readmemh" | "memh" => Some(Format::ReadMemH),
+            _ => None,
+        }
+    }
+}
+
+/// Converts an image assembled for `base`. Words of `width` bits are used by
+/// readmemh; the raw binary has no address and ignores both.
+pub fn format_image(
+    bytes: &[u8],
+    format: Format,
+    width: u32,
+    base: u32,
+) -> Result<Vec<u8>, Diagnostic> {
+    Ok(match format {
+        Format::Bin => bytes.to_vec(),
+        Format::IHex => ihex(bytes, base)?.into_bytes(),
+        Format::ReadMemH => readmemh(bytes, width, base)?.into_bytes(),
+    })
+}
+
+fn ihex_record(out: &mut String, addr: u16, kind: u8, data: &[u8]) {
+    let mut sum = data.len() as u8;
+    sum = sum.wrapping_add((addr >> 8) as u8).wrapping_add(addr as u8);
+    sum = sum.wrapping_add(kind);
+    write!(out, ":{:02X}{:04X}{:02X}", data.len(), addr, kind).unwrap();
+    for &byte in data {
+        sum = sum.wrapping_add(byte);
+        write!(out, "{:02X}", byte).unwrap();
+    }
+    writeln!(out, "{:02X}", sum.wrapping_neg()).unwrap();
+}
+
+// Data records hold up to 16 bytes and never cross a 64 KiB boundary, so an
+// extended linear address record is emitted whenever the upper half changes.
+fn ihex(bytes: &[u8], base: u32) -> Result<String, Diagnostic> {
+    let mut out = String::new();
+    let mut upper = 0;
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let addr = match base.checked_add(offset as u32) {
+            Some(addr) => addr,
+            None => bail!("Image does not fit below 4 GiB at base {:#x}", base),
+        };
+        if addr >> 16 != upper {
+            upper = addr >> 16;
+            ihex_record(&mut out, 0, 4, &(upper as u16).to_be_bytes());
+        }
+        let len = 16
+            .min(bytes.len() - offset)
+            .min(0x10000 - (addr & 0xffff) as usize);
+        ihex_record(&mut out, addr as u16, 0, &bytes[offset..offset + len]);
+        offset += len;
+    }
+    ihex_record(&mut out, 0, 1, &[]);
+    Ok(out)
+}
+
+// Words are little-endian, like the memory they are loaded into, and the
+// @ address is counted in words.
+fn readmemh(bytes: &[u8], width: u32, base: u32) -> Result<String, Diagnostic> {
+    if !matches!(width, 8 | 16 | 32) {
+        bail!("Word width must be 8, 16 or 32, not {}", width);
+    }
+    let size = width as usize / 8;
+    if !(base as usize).is_multiple_of(size) {
+        bail!("Base address {:#x} is not aligned to {} bytes", base, size);
+    }
+
+    let mut out = String::new();
+    if base != 0 {
+        writeln!(out, "@{:x}", base as usize / size).unwrap();
+    }
+    for chunk in bytes.chunks(size) {
+        let mut word = [0; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+        writeln!(
+            out,
+            "{:0width$x}",
+            u32::from_le_bytes(word),
+            width = size * 2
+        )
+        .unwrap();
+    }
+    Ok(out)
+}
@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::panic;
+use std::thread;
+
+use crate::ast::{functions, globals, BinOp, Callee, Decl, Expr, ExprKind, Function, LValue, Stmt};
+use crate::types::{truncate, Type};
+
+// Tree-walking interpreter over the AST. It defines what a program means
+// independently of CodeGenerator, so the two can be compared.
+//
+// Objects live in a byte-addressed memory laid out like the compiled
+// program's: locals are at the same offsets from fp, and stack arguments are
+// where va_start expects them. Addresses below DATA_START are never valid, so
+// null dereferences are caught. Functions get addresses past the end of
+// memory, which can be stored and called but not read.
+
+const MEMORY_SIZE: u32 = 0x10000;
+const DATA_START: u32 = 0x100;
+const FUNC_START: u32 = MEMORY_SIZE;
+const ARGREGS: usize = 4;
+const HOST_STACK_SIZE: usize = 1 << 30;
+
+// Stops programs that never return.
+pub(crate) const MAX_STEPS: u64 = 10_000_000;
+
+/// Why the interpreter stopped a program before main returned.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trap {
+    DivisionByZero,
+    InvalidAddress(u32),
+    InvalidCall(u32),
+    UndefinedFunction(String),
+    UndefinedReference(String),
+    /// The globals do not fit below the stack.
+    OutOfMemory,
+    StackOverflow,
+    StepLimit,
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trap::DivisionByZero => write!(f, "division by zero"),
+            Trap::InvalidAddress(addr) => write!(f, "invalid memory access to {:#x}", addr),
+            Trap::InvalidCall(addr) => write!(f, "call to {:#x}, which is not a function", addr),
+            Trap::UndefinedFunction(name) => write!(f, "call to undefined function {}", name),
+            Trap::UndefinedReference(name) => write!(f, "undefined reference to {}", name),
+            Trap::OutOfMemory => write!(f, "global variables do not fit in memory"),
+            Trap::StackOverflow => write!(f, "stack overflow"),
+            Trap::StepLimit => write!(f, "no return after {} steps", MAX_STEPS),
+        }
+    }
+}
+
+// How a statement finished.
+enum Flow {
+    Normal,
+    Return(u32),
+}
+
+pub(crate) struct Interpreter<'a> {
+    functions: Vec<&'a Function>,
+    mem: Vec<u8>,
+    labels: HashMap<String, u32>,
+    data_end: u32,
+    sp: u32,
+    fp: u32,
+    // Return type of the function being run.
+    ret_ty: Type,
+    steps: u64,
+}
+
+impl<'a> Interpreter<'a> {
+    pub(crate) fn new(decls: &'a [Decl]) -> Result<Self, Trap> {
+        let mut interp = Interpreter {
+            functions: functions(decls).collect(),
+            mem: vec![0; MEMORY_SIZE as usize],
+            labels: HashMap::new(),
+            data_end: DATA_START,
+            sp: MEMORY_SIZE,
+            fp: MEMORY_SIZE,
+            ret_ty: Type::int(),
+            steps: 0,
+        };
+
+        for (i, func) in interp.functions.iter().enumerate() {
+            interp
+                .labels
+                .insert(func.name.clone(), FUNC_START + i as u32 * 4);
+        }
+        let mut addr = DATA_START;
+        for gvar in globals(decls) {
+            let align = gvar.ty.align as u32;
+            addr = addr.div_ceil(align) * align;
+            interp.labels.insert(gvar.label.clone(), addr);
+            addr += gvar.ty.size as u32;
+        }
+        if addr > MEMORY_SIZE {
+            return Err(Trap::OutOfMemory);
+        }
+        interp.data_end = addr;
+
+        for gvar in globals(decls) {
+            let base = interp.labels[&gvar.label];
+            for init in &gvar.init {
+                let val = match &init.label {
+                    Some(label) => match interp.labels.get(label) {
+                        Some(&addr) => addr.wrapping_add(init.val as u32),
+                        None => return Err(Trap::UndefinedReference(label.clone())),
+                    },
+                    None => init.val as u32,
+                };
+                interp
+                    .store(base + init.offset as u32, init.size, val)
+                    .unwrap();
+            }
+        }
+        Ok(interp)
+    }
+
+    fn load(&self, addr: u32, size: i64) -> Result<u32, Trap> {
+        let start = addr as usize;
+        match self.mem.get(start..start + size as usize) {
+            Some(bytes) if addr >= DATA_START => {
+                let mut word = [0; 4];
+                word[..bytes.len()].copy_from_slice(bytes);
+                Ok(u32::from_le_bytes(word))
+            }
+            _ => Err(Trap::InvalidAddress(addr)),
+        }
+    }
+
+    fn store(&mut self, addr: u32, size: i64, val: u32) -> Result<(), Trap> {
+        let start = addr as usize;
+        match self.mem.get_mut(start..start + size as usize) {
+            Some(bytes) if addr >= DATA_START => {
+                let len = bytes.len();
+                bytes.copy_from_slice(&val.to_le_bytes()[..len]);
+                Ok(())
+            }
+            _ => Err(Trap::InvalidAddress(addr)),
+        }
+    }
+
+    fn tick(&mut self) -> Result<(), Trap> {
+        self.steps += 1;
+        if self.steps > MAX_STEPS {
+            return Err(Trap::StepLimit);
+        }
+        Ok(())
+    }
+
+    // Runs main and returns its value.
+    pub(crate) fn run(&mut self) -> Result<i32, Trap> {
+        match self.functions.iter().position(|func| func.name == "main") {
+            Some(i) => Ok(self.call(i, &[], 0)? as i32),
+            None => Err(Trap::UndefinedFunction("main".to_string())),
+        }
+    }
+
+    // Arguments past the fourth, and all variadic ones, are passed on the
+    // stack with the first of them at the lowest address.
+    fn call(&mut self, index: usize, args: &[u32], nregs: usize) -> Result<u32, Trap> {
+        let func = self.functions[index];
+        if !func.is_definition {
+            return Err(Trap::UndefinedFunction(func.name.clone()));
+        }
+
+        let saved = (self.sp, self.fp, self.ret_ty.clone());
+        self.ret_ty = func.ty.clone();
+        let nstack = args.len() - nregs;
+        let size = nstack as u32 * 4 + 8 + func.stack_size as u32;
+        if self.sp < self.data_end + size {
+            return Err(Trap::StackOverflow);
+        }
+        self.sp -= nstack as u32 * 4;
+        for (i, &arg) in args[nregs..].iter().enumerate() {
+            self.store(self.sp + i as u32 * 4, 4, arg)?;
+        }
+        self.sp -= 8;
+        self.fp = self.sp;
+        self.sp -= func.stack_size as u32;
+
+        for (i, param) in func.params.iter().enumerate() {
+            let val = if i < ARGREGS {
+                args[i]
+            } else {
+                self.load(self.fp + 8 + (i - ARGREGS) as u32 * 4, 4)?
+            };
+            self.store(self.fp - param.offset as u32, param.ty.size, val)?;
+        }
+
+        let val = match self.exec(&func.body)? {
+            Flow::Return(val) => val,
+            Flow::Normal => 0,
+        };
+        (self.sp, self.fp, self.ret_ty) = saved;
+        Ok(val)
+    }
+
+    fn exec(&mut self, stmt: &Stmt) -> Result<Flow, Trap> {
+        self.tick()?;
+        match stmt {
+            Stmt::Block(body) => {
+                for stmt in body {
+                    if let Flow::Return(val) = self.exec(stmt)? {
+                        return Ok(Flow::Return(val));
+                    }
+                }
+            }
+            Stmt::Expr(expr) => {
+                self.eval(expr)?;
+            }
+            Stmt::Return(val) => {
+                let val = match val {
+                    Some(val) => {
+                        let val = self.eval(val)?;
+                        truncate(val, &self.ret_ty)
+                    }
+                    None => 0,
+                };
+                return Ok(Flow::Return(val));
+            }
+            Stmt::If { cond, then, els } => {
+                if self.eval(cond)? != 0 {
+                    return self.exec(then);
+                }
+                if let Some(els) = els {
+                    return self.exec(els);
+                }
+            }
+            Stmt::While { cond, body } => return self.run_loop(None, cond.as_ref(), None, body),
+            Stmt::For {
+                init,
+                cond,
+                inc,
+                body,
+            } => return self.run_loop(init.as_deref(), cond.as_deref(), inc.as_deref(), body),
+            Stmt::MemZero(dest) => {
+                let addr = self.eval(dest)?;
+                for i in 0..dest.ty.pointee().unwrap().size as u32 {
+                    self.store(addr + i, 1, 0)?;
+                }
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn run_loop(
+        &mut self,
+        init: Option<&Expr>,
+        cond: Option<&Expr>,
+        inc: Option<&Expr>,
+        body: &Stmt,
+    ) -> Result<Flow, Trap> {
+        if let Some(init) = init {
+            self.eval(init)?;
+        }
+        loop {
+            if let Some(cond) = cond {
+                if self.eval(cond)? == 0 {
+                    return Ok(Flow::Normal);
+                }
+            }
+            if let Flow::Return(val) = self.exec(body)? {
+                return Ok(Flow::Return(val));
+            }
+            if let Some(inc) = inc {
+                self.eval(inc)?;
+            }
+        }
+    }
+
+    fn global(&self, label: &str) -> Result<u32, Trap> {
+        match self.labels.get(label) {
+            Some(&addr) => Ok(addr),
+            None => Err(Trap::UndefinedFunction(label.to_string())),
+        }
+    }
+
+    fn addr(&mut self, lvalue: &LValue) -> Result<u32, Trap> {
+        match lvalue {
+            LValue::Local(offset) => Ok(self.fp.wrapping_sub(*offset as u32)),
+            LValue::Global(label) => self.global(label),
+            LValue::Deref(lhs) => self.eval(lhs),
+            LValue::Member(lhs, offset) => {
+                let base = self.addr(lhs)?;
+                Ok(base.wrapping_add(*offset as u32))
+            }
+        }
+    }
+
+    // Arrays, structs and functions evaluate to their address.
+    fn object(&mut self, addr: u32, ty: &Type) -> Result<u32, Trap> {
+        if ty.is_aggregate() {
+            return Ok(addr);
+        }
+        Ok(truncate(self.load(addr, ty.size)?, ty))
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<u32, Trap> {
+        self.tick()?;
+        let (op, lhs, rhs) = match &expr.kind {
+            ExprKind::Num(val) => return Ok(*val as u32),
+            ExprKind::Local(offset) => {
+                let addr = self.fp.wrapping_sub(*offset as u32);
+                return self.object(addr, &expr.ty);
+            }
+            ExprKind::Global(label) => {
+                let addr = self.global(label)?;
+                return self.object(addr, &expr.ty);
+            }
+            ExprKind::Deref(lhs) => {
+                let addr = self.eval(lhs)?;
+                return self.object(addr, &expr.ty);
+            }
+            // The struct evaluates to its address.
+            ExprKind::Member(lhs, offset) => {
+                let addr = self.eval(lhs)?.wrapping_add(*offset as u32);
+                return self.object(addr, &expr.ty);
+            }
+            ExprKind::Addr(lhs) => return self.addr(lhs),
+            ExprKind::Cast(lhs) => {
+                let val = self.eval(lhs)?;
+                return Ok(truncate(val, &expr.ty));
+            }
+            ExprKind::Assign(lhs, rhs) => {
+                let addr = self.addr(lhs)?;
+                let val = self.eval(rhs)?;
+                let ty = &expr.ty;
+                // Structs are copied and evaluate to the destination.
+                if ty.is_aggregate() {
+                    for i in 0..ty.size as u32 {
+                        let byte = self.load(val + i, 1)?;
+                        self.store(addr + i, 1, byte)?;
+                    }
+                    return Ok(addr);
+                }
+                self.store(addr, ty.size, val)?;
+                return Ok(truncate(val, ty));
+            }
+            ExprKind::VaStart(ap, offset) => {
+                let addr = self.addr(ap)?;
+                self.store(addr, 4, self.fp.wrapping_add(*offset as u32))?;
+                return Ok(0);
+            }
+            ExprKind::VaArg(ap) => {
+                let addr = self.addr(ap)?;
+                let next = self.load(addr, 4)?;
+                self.store(addr, 4, next + 4)?;
+                let val = self.load(next, 4)?;
+                return Ok(truncate(val, &expr.ty));
+            }
+            ExprKind::Call {
+                callee,
+                args,
+                func_ty,
+            } => return self.funcall(callee, args, func_ty),
+            ExprKind::Binary(op, lhs, rhs) => (*op, lhs, rhs),
+        };
+
+        let a = self.eval(lhs)?;
+        let b = self.eval(rhs)?;
+        let is_unsigned = op.is_unsigned(&lhs.ty, &rhs.ty);
+        let less = |a: u32, b: u32| {
+            if is_unsigned {
+                a < b
+            } else {
+                (a as i32) < (b as i32)
+            }
+        };
+
+        let val = match op {
+            BinOp::Add => a.wrapping_add(b),
+            BinOp::Sub => a.wrapping_sub(b),
+            BinOp::Mul => a.wrapping_mul(b),
+            BinOp::Div | BinOp::Mod if b == 0 => return Err(Trap::DivisionByZero),
+            BinOp::Div if is_unsigned => a / b,
+            BinOp::Div => (a as i32).wrapping_div(b as i32) as u32,
+            BinOp::Mod if is_unsigned => a % b,
+            BinOp::Mod => (a as i32).wrapping_rem(b as i32) as u32,
+            BinOp::Shl => a << (b & 31),
+            BinOp::Shr if is_unsigned => a >> (b & 31),
+            BinOp::Shr => ((a as i32) >> (b & 31)) as u32,
+            BinOp::Eq => (a == b) as u32,
+            BinOp::Ne => (a != b) as u32,
+            BinOp::Lt => less(a, b) as u32,
+            BinOp::Le => !less(b, a) as u32,
+        };
+        Ok(val)
+    }
+
+    // Arguments are evaluated from right to left, and the callee of an
+    // indirect call after them, as in the generated code.
+    fn funcall(&mut self, callee: &Callee, args: &[Expr], func_ty: &Type) -> Result<u32, Trap> {
+        let mut vals = vec![0; args.len()];
+        for (i, arg) in args.iter().enumerate().rev() {
+            vals[i] = self.eval(arg)?;
+        }
+
+        let index = match callee {
+            Callee::Indirect(callee) => {
+                let addr = self.eval(callee)?;
+                let index = addr.wrapping_sub(FUNC_START) / 4;
+                if addr < FUNC_START || addr % 4 != 0 || index as usize >= self.functions.len() {
+                    return Err(Trap::InvalidCall(addr));
+                }
+                index as usize
+            }
+            Callee::Direct(name) => match self.functions.iter().position(|func| &func.name == name)
+            {
+                Some(index) => index,
+                None => return Err(Trap::UndefinedFunction(name.clone())),
+            },
+        };
+
+        let nregs = if func_ty.is_variadic {
+            func_ty.params.len()
+        } else {
+            vals.len()
+        }
+        .min(ARGREGS);
+        self.call(index, &vals, nregs)
+    }
+}
+
+/// Interprets a translation unit and returns what main returned. Every call
+/// in the program nests a few calls of the interpreter, so it runs on a stack
+/// large enough for programs that fill the simulated one.
+pub fn interpret(decls: &[Decl]) -> Result<i32, Trap> {
+    thread::scope(|scope| {
+        thread::Builder::new()
+            .stack_size(HOST_STACK_SIZE)
+            .spawn_scoped(scope, || Interpreter::new(decls)?.run())
+            .unwrap()
+            .join()
+            .unwrap_or_else(|payload| panic::resume_unwind(payload))
+    })
+}
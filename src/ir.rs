@@ -0,0 +1,364 @@
+use std::fmt;
+
+use crate::ast::{BinOp, GVar};
+
+// Three-address code between the syntax tree and the assembly. Each function
+// is a list of basic blocks of instructions on virtual registers, each ending
+// in an explicit branch. Scalar locals whose address is never taken and that
+// are not volatile are kept in a virtual register of their own, which every
+// assignment to them writes. Other variables stay in the frame and are
+// reached through loads and stores.
+
+/// A virtual register. One that holds a local is assigned wherever the local
+/// is; any other holds the value of one expression and is assigned once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Reg(pub usize);
+
+/// A basic block, by its index in Function::blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockId(pub usize);
+
+#[derive(Debug, Clone)]
+pub enum Callee {
+    Direct(String),
+    Indirect(Reg),
+}
+
+#[derive(Debug, Clone)]
+pub enum Inst {
+    Imm {
+        dst: Reg,
+        val: i64,
+    },
+    /// The address fp + offset: locals are below fp and stack arguments above.
+    Frame {
+        dst: Reg,
+        offset: i64,
+    },
+    /// The address of a label.
+    Label {
+        dst: Reg,
+        label: String,
+    },
+    /// Copies a value from one register to another.
+    Move {
+        dst: Reg,
+        src: Reg,
+    },
+    /// Loads `size` bytes and sign- or zero-extends them.
+    Load {
+        dst: Reg,
+        addr: Reg,
+        size: i64,
+        is_unsigned: bool,
+    },
+    Store {
+        addr: Reg,
+        src: Reg,
+        size: i64,
+    },
+    /// Sign- or zero-extends the low `size` bytes of a value, like a load.
+    Ext {
+        dst: Reg,
+        src: Reg,
+        size: i64,
+        is_unsigned: bool,
+    },
+    Binary {
+        op: BinOp,
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+        is_unsigned: bool,
+    },
+    /// Copies `size` bytes from the address in `src` to the one in `dst`.
+    Copy {
+        dst: Reg,
+        src: Reg,
+        size: i64,
+    },
+    /// Clears `size` bytes at `addr`, `width` bytes at a time.
+    Zero {
+        addr: Reg,
+        size: i64,
+        width: i64,
+    },
+    /// The first `nregs` arguments are passed in registers and the rest on
+    /// the stack.
+    Call {
+        dst: Reg,
+        callee: Callee,
+        args: Vec<Reg>,
+        nregs: usize,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum Terminator {
+    Jump(BlockId),
+    /// Goes to `then` if `cond` is not zero and to `els` otherwise.
+    Branch {
+        cond: Reg,
+        then: BlockId,
+        els: BlockId,
+    },
+    Return(Option<Reg>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub insts: Vec<Inst>,
+    pub term: Terminator,
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    /// Not visible to other objects.
+    pub is_static: bool,
+    /// Hold the arguments on entry.
+    pub params: Vec<Reg>,
+    /// Bytes of locals below fp.
+    pub stack_size: i64,
+    /// Control starts at the first block.
+    pub blocks: Vec<Block>,
+    /// Number of virtual registers, which are numbered from 0.
+    pub nregs: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Program {
+    /// Definitions only; prototypes are resolved by the linker.
+    pub functions: Vec<Function>,
+    pub globals: Vec<GVar>,
+}
+
+impl Inst {
+    /// The register the instruction assigns, if any.
+    pub fn dst(&self) -> Option<Reg> {
+        match self {
+            Inst::Imm { dst, .. }
+            | Inst::Frame { dst, .. }
+            | Inst::Label { dst, .. }
+            | Inst::Move { dst, .. }
+            | Inst::Load { dst, .. }
+            | Inst::Ext { dst, .. }
+            | Inst::Binary { dst, .. }
+            | Inst::Call { dst, .. } => Some(*dst),
+            Inst::Store { .. } | Inst::Copy { .. } | Inst::Zero { .. } => None,
+        }
+    }
+
+    /// The register the instruction assigns, to have it assign another one.
+    pub fn dst_mut(&mut self) -> Option<&mut Reg> {
+        match self {
+            Inst::Imm { dst, .. }
+            | Inst::Frame { dst, .. }
+            | Inst::Label { dst, .. }
+            | Inst::Move { dst, .. }
+            | Inst::Load { dst, .. }
+            | Inst::Ext { dst, .. }
+            | Inst::Binary { dst, .. }
+            | Inst::Call { dst, .. } => Some(dst),
+            Inst::Store { .. } | Inst::Copy { .. } | Inst::Zero { .. } => None,
+        }
+    }
+
+    /// The registers the instruction reads.
+    pub fn uses(&self) -> Vec<Reg> {
+        match self {
+            Inst::Imm { .. } | Inst::Frame { .. } | Inst::Label { .. } => Vec::new(),
+            Inst::Load { addr, .. } | Inst::Zero { addr, .. } => vec![*addr],
+            Inst::Move { src, .. } | Inst::Ext { src, .. } => vec![*src],
+            Inst::Store { addr, src, .. } => vec![*addr, *src],
+            Inst::Binary { lhs, rhs, .. } => vec![*lhs, *rhs],
+            Inst::Copy { dst, src, .. } => vec![*dst, *src],
+            Inst::Call { callee, args, .. } => {
+                let mut uses = args.clone();
+                if let Callee::Indirect(reg) = callee {
+                    uses.push(*reg);
+                }
+                uses
+            }
+        }
+    }
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch { then, els, .. } => vec![*then, *els],
+            Terminator::Return(_) => Vec::new(),
+        }
+    }
+
+    pub fn uses(&self) -> Vec<Reg> {
+        match self {
+            Terminator::Jump(_) | Terminator::Return(None) => Vec::new(),
+            Terminator::Branch { cond, .. } => vec![*cond],
+            Terminator::Return(Some(val)) => vec![*val],
+        }
+    }
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "%{}", self.0)
+    }
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bb{}", self.0)
+    }
+}
+
+fn op_name(op: BinOp, is_unsigned: bool) -> &'static str {
+    match (op, is_unsigned) {
+        (BinOp::Add, _) => "add",
+        (BinOp::Sub, _) => "sub",
+        (BinOp::Mul, _) => "mul",
+        (BinOp::Div, false) => "div",
+        (BinOp::Div, true) => "divu",
+        (BinOp::Mod, false) => "mod",
+        (BinOp::Mod, true) => "modu",
+        (BinOp::Shl, _) => "shl",
+        (BinOp::Shr, false) => "shr",
+        (BinOp::Shr, true) => "shru",
+        (BinOp::Eq, _) => "eq",
+        (BinOp::Ne, _) => "ne",
+        (BinOp::Lt, false) => "lt",
+        (BinOp::Lt, true) => "ltu",
+        (BinOp::Le, false) => "le",
+        (BinOp::Le, true) => "leu",
+    }
+}
+
+// Loads and extensions are suffixed with their width, and with u when they
+// zero-extend.
+fn width(size: i64, is_unsigned: bool) -> String {
+    let width = match size {
+        1 => "b",
+        2 => "h",
+        _ => "w",
+    };
+    if is_unsigned && size < 4 {
+        format!("{}u", width)
+    } else {
+        width.to_string()
+    }
+}
+
+fn list(regs: &[Reg]) -> String {
+    let regs: Vec<String> = regs.iter().map(|reg| reg.to_string()).collect();
+    regs.join(", ")
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Inst::Imm { dst, val } => write!(f, "{} = imm {}", dst, val),
+            Inst::Frame { dst, offset } => write!(f, "{} = frame {}", dst, offset),
+            Inst::Label { dst, label } => write!(f, "{} = label {}", dst, label),
+            Inst::Move { dst, src } => write!(f, "{} = mov {}", dst, src),
+            Inst::Load {
+                dst,
+                addr,
+                size,
+                is_unsigned,
+            } => write!(f, "{} = load.{} {}", dst, width(*size, *is_unsigned), addr),
+            Inst::Store { addr, src, size } => {
+                write!(f, "store.{} {}, {}", width(*size, false), addr, src)
+            }
+            Inst::Ext {
+                dst,
+                src,
+                size,
+                is_unsigned,
+            } => write!(f, "{} = ext.{} {}", dst, width(*size, *is_unsigned), src),
+            Inst::Binary {
+                op,
+                dst,
+                lhs,
+                rhs,
+                is_unsigned,
+            } => write!(
+                f,
+                "{} = {} {}, {}",
+                dst,
+                op_name(*op, *is_unsigned),
+                lhs,
+                rhs
+            ),
+            Inst::Copy { dst, src, size } => write!(f, "copy {}, {}, {}", dst, src, size),
+            Inst::Zero { addr, size, width } => write!(f, "zero {}, {}, {}", addr, size, width),
+            Inst::Call {
+                dst,
+                callee,
+                args,
+                nregs,
+            } => {
+                match callee {
+                    Callee::Direct(name) => write!(f, "{} = call {}", dst, name)?,
+                    Callee::Indirect(reg) => write!(f, "{} = call {}", dst, reg)?,
+                }
+                write!(f, "({})", list(args))?;
+                if *nregs < args.len() {
+                    write!(f, " regs {}", nregs)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Terminator::Jump(target) => write!(f, "jmp {}", target),
+            Terminator::Branch { cond, then, els } => write!(f, "br {}, {}, {}", cond, then, els),
+            Terminator::Return(Some(val)) => write!(f, "ret {}", val),
+            Terminator::Return(None) => write!(f, "ret"),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_static {
+            write!(f, "static ")?;
+        }
+        writeln!(
+            f,
+            "function {}({}) frame {}",
+            self.name,
+            list(&self.params),
+            self.stack_size
+        )?;
+        for (i, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "{}:", BlockId(i))?;
+            for inst in &block.insts {
+                writeln!(f, "  {}", inst)?;
+            }
+            writeln!(f, "  {}", block.term)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for gvar in &self.globals {
+            let kind = if gvar.ty.is_const { "rodata" } else { "data" };
+            writeln!(f, "{} {} size {}", kind, gvar.label, gvar.ty.size)?;
+        }
+        for (i, func) in self.functions.iter().enumerate() {
+            if i > 0 || !self.globals.is_empty() {
+                writeln!(f)?;
+            }
+            write!(f, "{}", func)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+//! A compiler from a small dialect of C to the KTC32 processor.
+//!
+//! [`compile`] runs the front end and code generator on one translation
+//! unit and returns the result of every stage: the preprocessed text, the
+//! tokens, the syntax tree, the IR and the assembly. [`preprocess`],
+//! [`token::tokenize`] and [`generate`] run stages on their own, and the
+//! modules hold the linker, assembler, simulator and interpreter that the
+//! `ktc32-com` command is built from. The syntax tree in [`ast`] comes with
+//! [`ast::Visitor`] and [`ast::Folder`] traits for writing passes over it,
+//! and [`generate`] compiles a tree such a pass has rewritten.
+//!
+//! ```
+//! use ktc32_com::ast::Decl;
+//! use ktc32_com::{compile, Options};
+//!
+//! let output = compile("int main() { return 42; }", &Options::default()).unwrap();
+//! assert!(matches!(&output.decls[0], Decl::Function(func) if func.name == "main"));
+//! assert!(output.asm.contains("main:"));
+//!
+//! let err = compile("int main() { return 1 +; }", &Options::default()).unwrap_err();
+//! assert_eq!(err.errors.len(), 1);
+//! ```
+//!
+//! Errors in the program are returned as [`Diagnostics`]. A panic is a bug
+//! in the compiler.
+
+/// Encoding linked assembly into machine code.
+pub mod asm;
+/// The syntax tree and the traits for passes over it.
+pub mod ast;
+mod codegen;
+mod diag;
+/// Writing assembled images out for programmers and simulators.
+pub mod image;
+/// An interpreter for the syntax tree, which checks the generated code.
+pub mod interp;
+/// The three-address code between the syntax tree and the assembly.
+pub mod ir;
+/// Relocatable objects and the linker that joins them into an image.
+pub mod link;
+mod lower;
+mod parse;
+mod preprocess;
+mod regalloc;
+/// A simulator that runs assembled images.
+pub mod sim;
+/// The memory layout and image format of the board a program runs on.
+pub mod target;
+/// Splitting preprocessed text into tokens.
+pub mod token;
+/// The types of the dialect.
+pub mod types;
+
+use std::path::{Path, PathBuf};
+
+use ast::Decl;
+use codegen::CodeGenerator;
+pub use diag::{Diagnostic, Diagnostics, Location};
+use parse::Parser;
+use preprocess::Preprocessor;
+use token::Token;
+
+/// Settings for compiling a translation unit.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Directories searched for `#include` files. `#include "..."` looks
+    /// next to the including file before these.
+    pub include_paths: Vec<PathBuf>,
+    /// Macros defined (`true`, with `NAME` or `NAME=VALUE`) or undefined
+    /// (`false`, with `NAME`) before the source is read, in this order.
+    pub macros: Vec<(bool, String)>,
+}
+
+/// A compiled translation unit, with the result of each stage.
+#[derive(Debug, Clone)]
+pub struct Output {
+    /// The source after preprocessing.
+    pub preprocessed: String,
+    /// The tokens of the preprocessed text, ending with an `Eof` token.
+    pub tokens: Vec<Token>,
+    /// The syntax tree: function definitions and prototypes in the order
+    /// they first appear, followed by the objects in static storage.
+    pub decls: Vec<Decl>,
+    /// The syntax tree lowered to three-address code.
+    pub ir: ir::Program,
+    /// Assembly text, which [`link::Object::from_asm`] reads.
+    pub asm: String,
+    /// Problems that did not stop compilation.
+    pub warnings: Vec<String>,
+}
+
+fn preprocessor(options: &Options) -> Result<Preprocessor, Diagnostic> {
+    let mut preprocessor = Preprocessor::new(options.include_paths.clone());
+    for (is_define, name) in &options.macros {
+        if *is_define {
+            preprocessor.define(name)?;
+        } else {
+            preprocessor.undefine(name);
+        }
+    }
+    Ok(preprocessor)
+}
+
+/// Preprocesses source that is not read from a file. `name` is used for
+/// `__FILE__` and to find includes relative to it.
+pub fn preprocess(name: &Path, source: &str, options: &Options) -> Result<String, Diagnostics> {
+    Ok(preprocessor(options)?.preprocess_source(name, source)?)
+}
+
+/// Reads and preprocesses a file.
+pub fn preprocess_file(path: &Path, options: &Options) -> Result<String, Diagnostics> {
+    Ok(preprocessor(options)?.preprocess_file(path)?)
+}
+
+/// Lowers a syntax tree to IR and generates its assembly. This is the back
+/// end of [`compile`], for trees that a pass has rewritten.
+pub fn generate(decls: &[Decl]) -> Result<(ir::Program, String), Diagnostics> {
+    let ir = lower::lower(decls);
+    let mut codegen = CodeGenerator::new();
+    codegen.gen_program(&ir)?;
+    let mut asm = Vec::new();
+    codegen.write(&mut asm).unwrap();
+    Ok((ir, String::from_utf8(asm).unwrap()))
+}
+
+/// Compiles a translation unit that has already been preprocessed.
+pub fn compile_preprocessed(text: String) -> Result<Output, Diagnostics> {
+    let tokens = token::tokenize(text.clone())?;
+    let mut parser = Parser::new(tokens.clone());
+    let decls = parser.program()?;
+    let (ir, asm) = generate(&decls)?;
+    Ok(Output {
+        preprocessed: text,
+        tokens,
+        decls,
+        ir,
+        asm,
+        warnings: parser.warnings,
+    })
+}
+
+/// Preprocesses and compiles a translation unit. `__FILE__` expands to
+/// `"<input>"` and `#include "..."` looks in the current directory.
+pub fn compile(source: &str, options: &Options) -> Result<Output, Diagnostics> {
+    compile_preprocessed(preprocess(Path::new("<input>"), source, options)?)
+}
+
+/// Reads, preprocesses and compiles a source file.
+pub fn compile_file(path: &Path, options: &Options) -> Result<Output, Diagnostics> {
+    compile_preprocessed(preprocess_file(path, options)?)
+}
@@ -0,0 +1,446 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::diag::{bail, Diagnostic};
+
+// Sections in the order they are laid out in the image.
+const SECTIONS: [&str; 3] = [".text", ".data", ".rodata"];
+
+pub(crate) const REGISTERS: [&str; 16] = [
+    "zero", "ra", "sp", "fp", "flag", "t0", "t1", "t2", "a0", "a1", "a2", "a3", "s0", "s1", "s2",
+    "s3",
+];
+
+fn is_register(name: &str) -> bool {
+    REGISTERS.contains(&name)
+        || name
+            .strip_prefix('r')
+            .is_some_and(|n| n.parse::<u8>().is_ok_and(|n| n < 16))
+}
+
+fn is_ident1(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '.'
+}
+
+// `$` only appears in the names the linker gives clashing local symbols.
+fn is_ident2(c: char) -> bool {
+    is_ident1(c) || c.is_ascii_digit() || c == '$'
+}
+
+// Splits an operand list into identifiers and the text between them, so that
+// symbols can be found and renamed without touching anything else.
+fn split_idents(s: &str) -> Vec<(bool, &str)> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut chars = s.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if !is_ident1(c) {
+            continue;
+        }
+        let mut end = i + c.len_utf8();
+        while let Some(&(j, c)) = chars.peek() {
+            if !is_ident2(c) {
+                break;
+            }
+            end = j + c.len_utf8();
+            chars.next();
+        }
+        // Numbers such as 0x10 are not identifiers.
+        if s[..i].ends_with(|c: char| c.is_ascii_digit()) {
+            continue;
+        }
+        parts.push((false, &s[start..i]));
+        parts.push((true, &s[i..end]));
+        start = end;
+    }
+    parts.push((false, &s[start..]));
+    parts
+}
+
+// The symbols an instruction or directive refers to.
+fn symbol_refs(line: &str) -> Vec<String> {
+    let operands = match line.trim().split_once(char::is_whitespace) {
+        Some((_, operands)) => operands,
+        None => return Vec::new(),
+    };
+    split_idents(operands)
+        .into_iter()
+        .filter(|&(is_ident, part)| is_ident && !is_register(part))
+        .map(|(_, part)| part.to_string())
+        .collect()
+}
+
+fn rename_symbol(line: &str, from: &str, to: &str) -> String {
+    split_idents(line)
+        .into_iter()
+        .map(|(is_ident, part)| if is_ident && part == from { to } else { part })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Binding {
+    Local,
+    Global,
+    Undefined,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Symbol {
+    pub(crate) name: String,
+    pub(crate) binding: Binding,
+    // Where the label is defined; unused for undefined symbols.
+    pub(crate) section: String,
+    pub(crate) line: usize,
+}
+
+// A reference to `symbol` from a line of a section, which has to be patched
+// when the symbol is renamed or resolved.
+#[derive(Debug, Clone)]
+pub(crate) struct Reloc {
+    pub(crate) section: String,
+    pub(crate) line: usize,
+    pub(crate) symbol: String,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Section {
+    pub(crate) name: String,
+    pub(crate) lines: Vec<String>,
+}
+
+/// A relocatable object. Its sections hold assembly, which is only encoded
+/// once objects have been linked into an image.
+#[derive(Debug, Clone, Default)]
+pub struct Object {
+    pub(crate) name: String,
+    pub(crate) sections: Vec<Section>,
+    pub(crate) symbols: Vec<Symbol>,
+    pub(crate) relocs: Vec<Reloc>,
+}
+
+fn malformed(name: &str) -> Diagnostic {
+    Diagnostic::new(format!("Malformed object {}", name))
+}
+
+impl Object {
+    fn section_mut(&mut self, name: &str) -> &mut Section {
+        match self
+            .sections
+            .iter()
+            .position(|section| section.name == name)
+        {
+            Some(i) => &mut self.sections[i],
+            None => {
+                self.sections.push(Section {
+                    name: name.to_string(),
+                    lines: Vec::new(),
+                });
+                self.sections.last_mut().unwrap()
+            }
+        }
+    }
+
+    fn find_symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|sym| sym.name == name)
+    }
+
+    fn defines_global(&self, name: &str) -> bool {
+        self.find_symbol(name)
+            .is_some_and(|sym| sym.binding == Binding::Global)
+    }
+
+    /// Builds an object from assembly, as generated by the compiler or written
+    /// by hand. Labels are local unless named by a .global directive.
+    pub fn from_asm(name: &str, text: &str) -> Result<Object, Diagnostic> {
+        let mut obj = Object {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        let mut globals = HashSet::new();
+        let mut current = ".text".to_string();
+        let mut refs = Vec::new();
+
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(section) = line.strip_prefix(".section") {
+                current = section.trim().to_string();
+                obj.section_mut(&current);
+                continue;
+            }
+            if SECTIONS.contains(&line) {
+                current = line.to_string();
+                obj.section_mut(&current);
+                continue;
+            }
+            if let Some(name) = line
+                .strip_prefix(".global ")
+                .or(line.strip_prefix(".globl "))
+            {
+                globals.insert(name.trim().to_string());
+                continue;
+            }
+
+            let section = obj.section_mut(&current);
+            let index = section.lines.len();
+            if let Some(label) = line.strip_suffix(':') {
+                if obj.find_symbol(label).is_some() {
+                    bail!("Duplicate label {} in {}", label, name);
+                }
+                obj.section_mut(&current).lines.push(line.to_string());
+                obj.symbols.push(Symbol {
+                    name: label.to_string(),
+                    binding: Binding::Local,
+                    section: current.clone(),
+                    line: index,
+                });
+                continue;
+            }
+            section.lines.push(format!("  {}", line));
+            for symbol in symbol_refs(line) {
+                refs.push(Reloc {
+                    section: current.clone(),
+                    line: index,
+                    symbol,
+                });
+            }
+        }
+
+        for sym in obj.symbols.iter_mut() {
+            if globals.contains(&sym.name) {
+                sym.binding = Binding::Global;
+            }
+        }
+        for reloc in &refs {
+            if obj.find_symbol(&reloc.symbol).is_none() {
+                obj.symbols.push(Symbol {
+                    name: reloc.symbol.clone(),
+                    binding: Binding::Undefined,
+                    section: String::new(),
+                    line: 0,
+                });
+            }
+        }
+        obj.relocs = refs;
+        Ok(obj)
+    }
+
+    /// Reads an object written by Display.
+    pub fn parse(name: &str, text: &str) -> Result<Object, Diagnostic> {
+        let mut obj = Object {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        let mut lines = text.lines();
+        if lines.next() != Some(".object") {
+            bail!("{} is not a KTC32 object", name);
+        }
+
+        let mut table = "";
+        for line in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [".section", section] => {
+                    table = "";
+                    obj.section_mut(section);
+                }
+                [".symtab"] | [".reloc"] => table = fields[0],
+                _ if table.is_empty() => match obj.sections.last_mut() {
+                    Some(section) => section.lines.push(line.to_string()),
+                    None => bail!("Malformed object {}", name),
+                },
+                [sym, "undefined"] if table == ".symtab" => obj.symbols.push(Symbol {
+                    name: sym.to_string(),
+                    binding: Binding::Undefined,
+                    section: String::new(),
+                    line: 0,
+                }),
+                [sym, binding @ ("local" | "global"), section, index] if table == ".symtab" => {
+                    obj.symbols.push(Symbol {
+                        name: sym.to_string(),
+                        binding: if *binding == "global" {
+                            Binding::Global
+                        } else {
+                            Binding::Local
+                        },
+                        section: section.to_string(),
+                        line: index.parse().map_err(|_| malformed(name))?,
+                    })
+                }
+                [section, index, sym] if table == ".reloc" => obj.relocs.push(Reloc {
+                    section: section.to_string(),
+                    line: index.parse().map_err(|_| malformed(name))?,
+                    symbol: sym.to_string(),
+                }),
+                _ => bail!("Malformed object {}", name),
+            }
+        }
+        Ok(obj)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) {
+        for reloc in self.relocs.iter_mut().filter(|reloc| reloc.symbol == from) {
+            let section = self
+                .sections
+                .iter_mut()
+                .find(|section| section.name == reloc.section)
+                .unwrap();
+            let line = &mut section.lines[reloc.line];
+            *line = rename_symbol(line, from, to);
+            reloc.symbol = to.to_string();
+        }
+        for sym in self.symbols.iter_mut().filter(|sym| sym.name == from) {
+            if sym.binding != Binding::Undefined {
+                let section = self
+                    .sections
+                    .iter_mut()
+                    .find(|section| section.name == sym.section)
+                    .unwrap();
+                section.lines[sym.line] = format!("{}:", to);
+            }
+            sym.name = to.to_string();
+        }
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, ".object")?;
+        for section in &self.sections {
+            writeln!(f, ".section {}", section.name)?;
+            for line in &section.lines {
+                writeln!(f, "{}", line)?;
+            }
+        }
+        writeln!(f, ".symtab")?;
+        for sym in &self.symbols {
+            match sym.binding {
+                Binding::Undefined => writeln!(f, "{} undefined", sym.name)?,
+                binding => writeln!(
+                    f,
+                    "{} {} {} {}",
+                    sym.name,
+                    if binding == Binding::Global {
+                        "global"
+                    } else {
+                        "local"
+                    },
+                    sym.section,
+                    sym.line
+                )?,
+            }
+        }
+        writeln!(f, ".reloc")?;
+        for reloc in &self.relocs {
+            writeln!(f, "{} {} {}", reloc.section, reloc.line, reloc.symbol)?;
+        }
+        Ok(())
+    }
+}
+
+/// The label the _start added by [`link`] spins at once main returns.
+pub const HALT: &str = "__halt";
+
+/// Links objects into the assembly of a single image. Global symbols must be
+/// defined exactly once; local ones that clash with a symbol of another object
+/// are renamed. Unless an object defines _start, one is added at the start of
+/// the image, where execution starts. It sets up the stack to grow down from
+/// `stack_top`, calls main and then spins.
+pub fn link(mut objects: Vec<Object>, stack_top: u32) -> Result<String, Diagnostic> {
+    let start = if objects.iter().any(|obj| obj.defines_global("_start")) {
+        "jal zero, _start".to_string()
+    } else {
+        let set_sp = if stack_top & 0xffff == 0 {
+            format!("lui sp, {}", stack_top >> 16)
+        } else {
+            format!("la sp, {:#x}", stack_top)
+        };
+        format!(
+            ".global _start\n_start:\n  {}\n  jal ra, main\n{halt}:\n  jal zero, {halt}",
+            set_sp,
+            halt = HALT
+        )
+    };
+    objects.insert(0, Object::from_asm("<start>", &start)?);
+
+    let mut defined: HashMap<String, usize> = HashMap::new();
+    for (i, obj) in objects.iter().enumerate() {
+        for sym in obj
+            .symbols
+            .iter()
+            .filter(|sym| sym.binding == Binding::Global)
+        {
+            if let Some(&j) = defined.get(&sym.name) {
+                bail!(
+                    "Duplicate symbol {} in {} and {}",
+                    sym.name,
+                    objects[j].name,
+                    obj.name
+                );
+            }
+            defined.insert(sym.name.clone(), i);
+        }
+    }
+
+    for i in 0..objects.len() {
+        let clashes: Vec<String> = objects[i]
+            .symbols
+            .iter()
+            .filter(|sym| sym.binding == Binding::Local)
+            .filter(|sym| {
+                objects.iter().enumerate().any(|(j, other)| {
+                    j != i
+                        && other
+                            .find_symbol(&sym.name)
+                            .is_some_and(|other| other.binding != Binding::Undefined)
+                })
+            })
+            .map(|sym| sym.name.clone())
+            .collect();
+        for name in clashes {
+            objects[i].rename(&name, &format!("{}${}", name, i));
+        }
+
+        let obj = &objects[i];
+        for sym in obj
+            .symbols
+            .iter()
+            .filter(|sym| sym.binding == Binding::Undefined)
+        {
+            if !defined.contains_key(&sym.name) {
+                bail!("Undefined reference to {} in {}", sym.name, obj.name);
+            }
+        }
+    }
+
+    let mut names: Vec<&str> = SECTIONS.to_vec();
+    for obj in &objects {
+        for section in &obj.sections {
+            if !names.contains(&section.name.as_str()) {
+                names.push(&section.name);
+            }
+        }
+    }
+
+    let mut image = String::new();
+    for name in names {
+        let sections: Vec<&Section> = objects
+            .iter()
+            .flat_map(|obj| obj.sections.iter().filter(|section| section.name == name))
+            .collect();
+        if sections.iter().all(|section| section.lines.is_empty()) {
+            continue;
+        }
+        image.push_str(name);
+        image.push('\n');
+        for section in sections {
+            for line in &section.lines {
+                image.push_str(line);
+                image.push('\n');
+            }
+        }
+    }
+    Ok(image)
+}
@@ -0,0 +1,610 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::ast::{self, walk_expr, BinOp, Decl, Expr, ExprKind, LValue, Stmt, Visitor};
+use crate::ir::{Block, BlockId, Callee, Function, Inst, Program, Reg, Terminator};
+use crate::types::{Type, TypeKind};
+
+// Lowers the syntax tree to IR. Expressions are evaluated in the same order
+// as the interpreter does, so that side effects happen alike.
+
+const ARGREGS: usize = 4;
+
+// The local an lvalue is part of, if it is not reached through a pointer.
+fn base_local(lvalue: &LValue) -> Option<i64> {
+    match lvalue {
+        LValue::Local(offset) => Some(*offset),
+        LValue::Member(lhs, _) => base_local(lhs),
+        LValue::Global(_) | LValue::Deref(_) => None,
+    }
+}
+
+// Finds the locals that can be kept in virtual registers: scalars that are
+// not volatile and are only read and assigned as a whole.
+#[derive(Default)]
+struct Variables {
+    // The type of each local that is read or assigned by name, by offset.
+    types: BTreeMap<i64, Type>,
+    in_frame: HashSet<i64>,
+}
+
+impl Variables {
+    fn note(&mut self, offset: i64, ty: &Type) {
+        if ty.is_aggregate() || ty.is_volatile {
+            self.in_frame.insert(offset);
+        }
+        self.types.entry(offset).or_insert_with(|| ty.clone());
+    }
+}
+
+impl Visitor for Variables {
+    fn visit_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Local(offset) | ExprKind::Assign(LValue::Local(offset), _) => {
+                self.note(*offset, &expr.ty)
+            }
+            ExprKind::Assign(lvalue, _)
+            | ExprKind::Addr(lvalue)
+            | ExprKind::VaStart(lvalue, _)
+            | ExprKind::VaArg(lvalue) => {
+                if let Some(offset) = base_local(lvalue) {
+                    self.in_frame.insert(offset);
+                }
+            }
+            _ => {}
+        }
+        walk_expr(self, expr);
+    }
+}
+
+// Whether an expression assigns to one of `vars`.
+struct Assigns<'a> {
+    vars: &'a HashMap<i64, Reg>,
+    found: bool,
+}
+
+impl Visitor for Assigns<'_> {
+    fn visit_expr(&mut self, expr: &Expr) {
+        if let ExprKind::Assign(LValue::Local(offset), _) = &expr.kind {
+            self.found |= self.vars.contains_key(offset);
+        }
+        walk_expr(self, expr);
+    }
+}
+
+// A block whose terminator has not been reached yet.
+struct Pending {
+    insts: Vec<Inst>,
+    term: Option<Terminator>,
+}
+
+struct Lowerer {
+    blocks: Vec<Pending>,
+    // Blocks in the order they were started, which is the order they are
+    // laid out in.
+    order: Vec<usize>,
+    current: usize,
+    nregs: usize,
+    ret_ty: Type,
+    // The registers of the locals kept in one, by offset.
+    vars: HashMap<i64, Reg>,
+}
+
+impl Lowerer {
+    fn new_reg(&mut self) -> Reg {
+        self.nregs += 1;
+        Reg(self.nregs - 1)
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        self.blocks.push(Pending {
+            insts: Vec::new(),
+            term: None,
+        });
+        BlockId(self.blocks.len() - 1)
+    }
+
+    // Continues in `block`, which must not have been started before.
+    fn start(&mut self, block: BlockId) {
+        self.order.push(block.0);
+        self.current = block.0;
+    }
+
+    fn push(&mut self, inst: Inst) {
+        self.blocks[self.current].insts.push(inst);
+    }
+
+    // Ends the current block. Code that follows it, such as statements after
+    // a return, goes to a new block that is removed if nothing jumps to it.
+    fn terminate(&mut self, term: Terminator) {
+        self.blocks[self.current].term = Some(term);
+        let next = self.new_block();
+        self.start(next);
+    }
+
+    fn imm(&mut self, val: i64) -> Reg {
+        let dst = self.new_reg();
+        self.push(Inst::Imm { dst, val });
+        dst
+    }
+
+    fn binary(&mut self, op: BinOp, lhs: Reg, rhs: Reg, is_unsigned: bool) -> Reg {
+        let dst = self.new_reg();
+        self.push(Inst::Binary {
+            op,
+            dst,
+            lhs,
+            rhs,
+            is_unsigned,
+        });
+        dst
+    }
+
+    fn load(&mut self, addr: Reg, ty: &Type) -> Reg {
+        let dst = self.new_reg();
+        self.push(Inst::Load {
+            dst,
+            addr,
+            size: ty.size.min(4),
+            is_unsigned: ty.is_unsigned,
+        });
+        dst
+    }
+
+    // Converts a value to `ty` as storing and loading it again would.
+    fn ext(&mut self, src: Reg, ty: &Type) -> Reg {
+        if ty.size >= 4 {
+            return src;
+        }
+        let dst = self.new_reg();
+        self.push(Inst::Ext {
+            dst,
+            src,
+            size: ty.size,
+            is_unsigned: ty.is_unsigned,
+        });
+        dst
+    }
+
+    // Assigns a value to a local kept in `var`, converting it to `ty` as
+    // storing it would. A value just computed is computed in `var` instead.
+    fn assign_var(&mut self, var: Reg, src: Reg, ty: &Type) {
+        if ty.size < 4 {
+            self.push(Inst::Ext {
+                dst: var,
+                src,
+                size: ty.size,
+                is_unsigned: ty.is_unsigned,
+            });
+            return;
+        }
+        let is_var = self.vars.values().any(|&reg| reg == src);
+        let last = self.blocks[self.current].insts.last_mut();
+        match last.and_then(Inst::dst_mut) {
+            Some(dst) if *dst == src && !is_var => *dst = var,
+            _ => self.push(Inst::Move { dst: var, src }),
+        }
+    }
+
+    // A value read from a local kept in a register changes if the local is
+    // assigned, so it is copied when one of the expressions evaluated before
+    // it is used does that.
+    fn keep(&mut self, reg: Reg, later: &[&Expr]) -> Reg {
+        if !self.vars.values().any(|&var| var == reg) {
+            return reg;
+        }
+        let mut assigns = Assigns {
+            vars: &self.vars,
+            found: false,
+        };
+        for expr in later {
+            assigns.visit_expr(expr);
+        }
+        if !assigns.found {
+            return reg;
+        }
+        let dst = self.new_reg();
+        self.push(Inst::Move { dst, src: reg });
+        dst
+    }
+
+    fn local(&mut self, offset: i64) -> Reg {
+        let dst = self.new_reg();
+        self.push(Inst::Frame {
+            dst,
+            offset: -offset,
+        });
+        dst
+    }
+
+    fn global(&mut self, label: &str) -> Reg {
+        let dst = self.new_reg();
+        self.push(Inst::Label {
+            dst,
+            label: label.to_string(),
+        });
+        dst
+    }
+
+    fn member(&mut self, base: Reg, offset: i64) -> Reg {
+        if offset == 0 {
+            return base;
+        }
+        let offset = self.imm(offset);
+        self.binary(BinOp::Add, base, offset, true)
+    }
+
+    fn addr(&mut self, lvalue: &LValue) -> Reg {
+        match lvalue {
+            LValue::Local(offset) => self.local(*offset),
+            LValue::Global(label) => self.global(label),
+            LValue::Deref(lhs) => self.expr(lhs),
+            LValue::Member(lhs, offset) => {
+                let base = self.addr(lhs);
+                self.member(base, *offset)
+            }
+        }
+    }
+
+    // Reads the object at an address. Aggregates are left as the address.
+    fn object(&mut self, addr: Reg, ty: &Type) -> Reg {
+        if ty.is_aggregate() {
+            return addr;
+        }
+        self.load(addr, ty)
+    }
+
+    // Arrays, structs and functions evaluate to their address.
+    fn expr(&mut self, expr: &Expr) -> Reg {
+        match &expr.kind {
+            ExprKind::Num(val) => self.imm(*val),
+            ExprKind::Local(offset) if self.vars.contains_key(offset) => self.vars[offset],
+            ExprKind::Local(offset) => {
+                let addr = self.local(*offset);
+                self.object(addr, &expr.ty)
+            }
+            ExprKind::Global(label) => {
+                let addr = self.global(label);
+                self.object(addr, &expr.ty)
+            }
+            ExprKind::Deref(lhs) => {
+                let addr = self.expr(lhs);
+                self.object(addr, &expr.ty)
+            }
+            // The struct evaluates to its address.
+            ExprKind::Member(lhs, offset) => {
+                let base = self.expr(lhs);
+                let addr = self.member(base, *offset);
+                self.object(addr, &expr.ty)
+            }
+            ExprKind::Addr(lhs) => self.addr(lhs),
+            // Conversions to char and short keep the low bytes, and all
+            // others leave the value as it is.
+            ExprKind::Cast(lhs) => {
+                let src = self.expr(lhs);
+                let ty = &expr.ty;
+                if ty.kind == TypeKind::Void || ty.size == 4 {
+                    return src;
+                }
+                let dst = self.new_reg();
+                self.push(Inst::Ext {
+                    dst,
+                    src,
+                    size: ty.size,
+                    is_unsigned: ty.is_unsigned,
+                });
+                dst
+            }
+            ExprKind::Assign(LValue::Local(offset), rhs) if self.vars.contains_key(offset) => {
+                let var = self.vars[offset];
+                let src = self.expr(rhs);
+                self.assign_var(var, src, &expr.ty);
+                var
+            }
+            // Structs are copied and evaluate to the destination.
+            ExprKind::Assign(lhs, rhs) => {
+                let addr = self.addr(lhs);
+                let addr = self.keep(addr, &[rhs]);
+                let src = self.expr(rhs);
+                let ty = &expr.ty;
+                if ty.is_aggregate() {
+                    self.push(Inst::Copy {
+                        dst: addr,
+                        src,
+                        size: ty.size,
+                    });
+                    return addr;
+                }
+                self.push(Inst::Store {
+                    addr,
+                    src,
+                    size: ty.size,
+                });
+                self.ext(src, ty)
+            }
+            ExprKind::VaStart(ap, offset) => {
+                let addr = self.addr(ap);
+                let args = self.new_reg();
+                self.push(Inst::Frame {
+                    dst: args,
+                    offset: *offset,
+                });
+                self.push(Inst::Store {
+                    addr,
+                    src: args,
+                    size: 4,
+                });
+                addr
+            }
+            // Every variadic argument takes a word.
+            ExprKind::VaArg(ap) => {
+                let addr = self.addr(ap);
+                let arg = self.load(addr, &Type::int());
+                let four = self.imm(4);
+                let next = self.binary(BinOp::Add, arg, four, true);
+                self.push(Inst::Store {
+                    addr,
+                    src: next,
+                    size: 4,
+                });
+                let val = self.load(arg, &Type::int());
+                self.ext(val, &expr.ty)
+            }
+            // Arguments are evaluated from right to left, and the callee of
+            // an indirect call after them. Variadic arguments are never
+            // passed in registers, so that the callee finds all of them on
+            // the stack right after the named ones.
+            ExprKind::Call {
+                callee,
+                args,
+                func_ty,
+            } => {
+                let indirect = match callee {
+                    ast::Callee::Direct(_) => None,
+                    ast::Callee::Indirect(callee) => Some(&**callee),
+                };
+                let mut regs = Vec::new();
+                for (i, arg) in args.iter().enumerate().rev() {
+                    let reg = self.expr(arg);
+                    let later: Vec<&Expr> = args[..i].iter().chain(indirect).collect();
+                    regs.push(self.keep(reg, &later));
+                }
+                regs.reverse();
+                let callee = match callee {
+                    ast::Callee::Direct(name) => Callee::Direct(name.clone()),
+                    ast::Callee::Indirect(callee) => Callee::Indirect(self.expr(callee)),
+                };
+                let nregs = if func_ty.is_variadic {
+                    func_ty.params.len()
+                } else {
+                    regs.len()
+                }
+                .min(ARGREGS);
+                let dst = self.new_reg();
+                self.push(Inst::Call {
+                    dst,
+                    callee,
+                    args: regs,
+                    nregs,
+                });
+                dst
+            }
+            ExprKind::Binary(op, lhs, rhs) => {
+                let is_unsigned = op.is_unsigned(&lhs.ty, &rhs.ty);
+                let lhs_reg = self.expr(lhs);
+                let lhs_reg = self.keep(lhs_reg, &[rhs]);
+                let rhs = self.expr(rhs);
+                self.binary(*op, lhs_reg, rhs, is_unsigned)
+            }
+        }
+    }
+
+    // Continues in `then` if `cond` is not zero and in a new block, which is
+    // returned, otherwise.
+    fn branch(&mut self, cond: &Expr, then: BlockId) -> BlockId {
+        let cond = self.expr(cond);
+        let els = self.new_block();
+        self.blocks[self.current].term = Some(Terminator::Branch { cond, then, els });
+        self.start(then);
+        els
+    }
+
+    fn jump(&mut self, target: BlockId) {
+        self.blocks[self.current].term = Some(Terminator::Jump(target));
+    }
+
+    // A loop without a condition runs until it returns.
+    fn lower_loop(&mut self, cond: Option<&Expr>, inc: Option<&Expr>, body: &Stmt) {
+        let begin = self.new_block();
+        self.jump(begin);
+        self.start(begin);
+        let body_block = self.new_block();
+        let end = match cond {
+            Some(cond) => Some(self.branch(cond, body_block)),
+            None => {
+                self.jump(body_block);
+                self.start(body_block);
+                None
+            }
+        };
+        self.stmt(body);
+        if let Some(inc) = inc {
+            self.expr(inc);
+        }
+        self.jump(begin);
+        let end = end.unwrap_or_else(|| self.new_block());
+        self.start(end);
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Expr(expr) => {
+                self.expr(expr);
+            }
+            Stmt::Block(body) => {
+                for stmt in body {
+                    self.stmt(stmt);
+                }
+            }
+            Stmt::If { cond, then, els } => {
+                let then_block = self.new_block();
+                let els_block = self.branch(cond, then_block);
+                self.stmt(then);
+                let end = self.new_block();
+                self.jump(end);
+                self.start(els_block);
+                if let Some(els) = els {
+                    self.stmt(els);
+                }
+                self.jump(end);
+                self.start(end);
+            }
+            Stmt::While { cond, body } => self.lower_loop(cond.as_ref(), None, body),
+            Stmt::For {
+                init,
+                cond,
+                inc,
+                body,
+            } => {
+                if let Some(init) = init {
+                    self.expr(init);
+                }
+                self.lower_loop(cond.as_deref(), inc.as_deref(), body);
+            }
+            Stmt::Return(val) => {
+                let val = val.as_ref().map(|val| {
+                    let val = self.expr(val);
+                    let ty = self.ret_ty.clone();
+                    self.ext(val, &ty)
+                });
+                self.terminate(Terminator::Return(val));
+            }
+            Stmt::MemZero(dest) => {
+                let addr = self.expr(dest);
+                let ty = dest.ty.pointee().unwrap();
+                self.push(Inst::Zero {
+                    addr,
+                    size: ty.size,
+                    width: ty.align.min(4),
+                });
+            }
+        }
+    }
+
+    // Lays the blocks out in the order they were started, leaving out the
+    // ones that cannot be reached, and numbers them in that order.
+    fn finish(self) -> Vec<Block> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut work = vec![self.order[0]];
+        while let Some(block) = work.pop() {
+            if reachable[block] {
+                continue;
+            }
+            reachable[block] = true;
+            if let Some(term) = &self.blocks[block].term {
+                work.extend(term.successors().iter().map(|target| target.0));
+            }
+        }
+
+        let order: Vec<usize> = self
+            .order
+            .into_iter()
+            .filter(|&block| reachable[block])
+            .collect();
+        let mut index = vec![0; self.blocks.len()];
+        for (i, &block) in order.iter().enumerate() {
+            index[block] = i;
+        }
+        let renumber = |target: BlockId| BlockId(index[target.0]);
+
+        let mut blocks: Vec<Option<Pending>> = self.blocks.into_iter().map(Some).collect();
+        order
+            .iter()
+            .map(|&block| {
+                let pending = blocks[block].take().unwrap();
+                let term = match pending.term.unwrap() {
+                    Terminator::Jump(target) => Terminator::Jump(renumber(target)),
+                    Terminator::Branch { cond, then, els } => Terminator::Branch {
+                        cond,
+                        then: renumber(then),
+                        els: renumber(els),
+                    },
+                    Terminator::Return(val) => Terminator::Return(val),
+                };
+                Block {
+                    insts: pending.insts,
+                    term,
+                }
+            })
+            .collect()
+    }
+}
+
+// Parameters are copied into their local so that the body can treat them
+// like any other. Falling off the end of main returns 0; for other functions
+// the return value is undefined.
+fn lower_function(func: &ast::Function) -> Function {
+    let mut lowerer = Lowerer {
+        blocks: Vec::new(),
+        order: Vec::new(),
+        current: 0,
+        nregs: 0,
+        ret_ty: func.ty.clone(),
+        vars: HashMap::new(),
+    };
+    let entry = lowerer.new_block();
+    lowerer.start(entry);
+
+    let params: Vec<Reg> = func.params.iter().map(|_| lowerer.new_reg()).collect();
+    let mut variables = Variables::default();
+    for param in &func.params {
+        variables.note(param.offset, &param.ty);
+    }
+    variables.visit_stmt(&func.body);
+    for &offset in variables.types.keys() {
+        if !variables.in_frame.contains(&offset) {
+            let var = lowerer.new_reg();
+            lowerer.vars.insert(offset, var);
+        }
+    }
+
+    for (param, &src) in func.params.iter().zip(params.iter()) {
+        if let Some(&var) = lowerer.vars.get(&param.offset) {
+            lowerer.assign_var(var, src, &param.ty);
+            continue;
+        }
+        let addr = lowerer.new_reg();
+        lowerer.push(Inst::Frame {
+            dst: addr,
+            offset: -param.offset,
+        });
+        lowerer.push(Inst::Store {
+            addr,
+            src,
+            size: param.ty.size,
+        });
+    }
+
+    lowerer.stmt(&func.body);
+    let val = (func.name == "main").then(|| lowerer.imm(0));
+    lowerer.terminate(Terminator::Return(val));
+
+    let nregs = lowerer.nregs;
+    Function {
+        name: func.name.clone(),
+        is_static: func.is_static,
+        params,
+        stack_size: func.stack_size,
+        blocks: lowerer.finish(),
+        nregs,
+    }
+}
+
+pub fn lower(decls: &[Decl]) -> Program {
+    Program {
+        functions: ast::functions(decls)
+            .filter(|func| func.is_definition)
+            .map(lower_function)
+            .collect(),
+        globals: ast::globals(decls).cloned().collect(),
+    }
+}
@@ -1,37 +1,519 @@
-mod codegen;
-mod parse;
-mod token;
-use codegen::CodeGenerator;
-use parse::Parser;
-use std::env;
-use token::tokenize;
+use clap::error::ErrorKind;
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use ktc32_com::asm::{assemble, label_address};
+use ktc32_com::ast::{functions, globals};
+use ktc32_com::image::{format_image, Format};
+use ktc32_com::link::{link, Object, HALT};
+use ktc32_com::target::{parse_number, Target};
+use ktc32_com::token::tokenize;
+use ktc32_com::{compile, compile_preprocessed, interp, sim};
+use ktc32_com::{preprocess, preprocess_file, Diagnostic, Diagnostics, Options, Output};
+use std::any::Any;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process;
 
-fn main() {
-    if env::args().len() != 2 {
-        panic!("Incorrect number of arguments");
+mod fuzz;
+
+// Exit statuses besides 0. Invalid command lines exit with 2, as clap does,
+// and bugs in the compiler with 101, as any panic does.
+const EXIT_ERROR: i32 = 1;
+const EXIT_FAULT: i32 = 3;
+
+// What to stop after and write out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Emit {
+    Tokens,
+    Ast,
+    Ir,
+    Asm,
+    Obj,
+    Bin,
+}
+
+const EMIT_NAMES: [(&str, Emit); 6] = [
+    ("tokens", Emit::Tokens),
+    ("ast", Emit::Ast),
+    ("ir", Emit::Ir),
+    ("asm", Emit::Asm),
+    ("obj", Emit::Obj),
+    ("bin", Emit::Bin),
+];
+
+impl Emit {
+    fn name(self) -> &'static str {
+        EMIT_NAMES.iter().find(|(_, emit)| *emit == self).unwrap().0
+    }
+}
+
+#[derive(Debug, Default)]
+struct Args {
+    // -I, -D and -U.
+    compiler: Options,
+    preprocess_only: bool,
+    // Without --emit, inputs are linked and the image's assembly is written,
+    // or the assembled image if -o or --format is given.
+    emit: Option<Emit>,
+    // Run the image in the simulator instead of writing it.
+    run: bool,
+    // Run the program in the AST interpreter instead of compiling it.
+    interpret: bool,
+    // Compare compiled random programs against the interpreter, or with
+    // --front-end, feed random input to the tokenizer and parser.
+    fuzz: bool,
+    front_end: bool,
+    seed: u32,
+    iterations: u32,
+    output: Option<PathBuf>,
+    // Set when --format is given; the target's format is used otherwise.
+    format: Option<Format>,
+    target: Target,
+    inputs: Vec<PathBuf>,
+}
+
+fn number(s: &str) -> Result<u32, String> {
+    parse_number(s).ok_or(format!("{} is not a number", s))
+}
+
+fn cli() -> Command {
+    let preprocessor = [
+        Arg::new("include")
+            .short('I')
+            .value_name("DIR")
+            .action(ArgAction::Append)
+            .value_parser(value_parser!(PathBuf))
+            .help("Search DIR for #include files"),
+        Arg::new("define")
+            .short('D')
+            .value_name("NAME[=VALUE]")
+            .action(ArgAction::Append)
+            .help("Define a macro, as 1 if no value is given"),
+        Arg::new("undefine")
+            .short('U')
+            .value_name("NAME")
+            .action(ArgAction::Append)
+            .help("Undefine a macro"),
+    ];
+    let compiler = [
+        Arg::new("opt-level")
+            .short('O')
+            .value_name("LEVEL")
+            .value_parser(value_parser!(u32).range(0..=3))
+            .help("Optimization level from 0 to 3; only 0 is implemented"),
+        Arg::new("target-config")
+            .long("target-config")
+            .value_name("FILE")
+            .value_parser(value_parser!(PathBuf))
+            .help("Read the memory size, base, format and width from FILE"),
+        Arg::new("base")
+            .long("base")
+            .value_name("ADDR")
+            .value_parser(number)
+            .help("Address the image is assembled for and loaded at"),
+    ];
+    let inputs = Arg::new("inputs")
+        .value_name("FILE")
+        .num_args(1..)
+        .required(true)
+        .value_parser(value_parser!(PathBuf))
+        .help(".ktc sources, assembly or objects; - reads a source from stdin");
+
+    Command::new("ktc32-com")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("C compiler for the KTC32 processor")
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .args(&preprocessor)
+        .args(&compiler)
+        .args([
+            Arg::new("output")
+                .short('o')
+                .value_name("FILE")
+                .value_parser(value_parser!(PathBuf))
+                .help("Write the output to FILE, or to stdout for -"),
+            Arg::new("emit")
+                .long("emit")
+                .value_name("KIND")
+                .value_parser(EMIT_NAMES.map(|(name, _)| name))
+                .help("Stop after this stage and write its output"),
+            Arg::new("E")
+                .short('E')
+                .action(ArgAction::SetTrue)
+                .conflicts_with("emit")
+                .help("Preprocess only"),
+            Arg::new("S")
+                .short('S')
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["emit", "E"])
+                .help("Same as --emit asm"),
+            Arg::new("c")
+                .short('c')
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["emit", "E", "S"])
+                .help("Same as --emit obj"),
+            Arg::new("format")
+                .long("format")
+                .value_name("FORMAT")
+                .value_parser(["bin", "ihex", "hex", "readmemh", "memh"])
+                .help("Write the assembled image as raw bytes, Intel HEX or readmemh"),
+            Arg::new("width")
+                .long("width")
+                .value_name("BITS")
+                .value_parser(number)
+                .help("Word size of readmemh output: 8, 16 or 32"),
+        ])
+        .arg(inputs.clone())
+        .subcommand(
+            Command::new("run")
+                .about("Run the linked program in the simulator and print what main returns")
+                .args(&preprocessor)
+                .args(&compiler)
+                .arg(inputs.clone()),
+        )
+        .subcommand(
+            Command::new("interp")
+                .about("Run a source file in the AST interpreter")
+                .args(&preprocessor)
+                .arg(inputs.num_args(1)),
+        )
+        .subcommand(
+            Command::new("fuzz")
+                .about("Compare random programs between the compiler and the interpreter")
+                .args([
+                    Arg::new("front-end")
+                        .long("front-end")
+                        .action(ArgAction::SetTrue)
+                        .help("Feed random input to the tokenizer and parser instead"),
+                    Arg::new("seed")
+                        .long("seed")
+                        .value_name("N")
+                        .value_parser(number)
+                        .default_value("0"),
+                    Arg::new("iterations")
+                        .long("iterations")
+                        .value_name("N")
+                        .value_parser(number)
+                        .default_value("100"),
+                ]),
+        )
+}
+
+// Options that a subcommand does not take read as unset.
+fn flag(matches: &ArgMatches, id: &str) -> bool {
+    matches.try_get_one::<bool>(id).ok().flatten() == Some(&true)
+}
+
+fn value<T: Any + Clone + Send + Sync>(matches: &ArgMatches, id: &str) -> Option<T> {
+    matches.try_get_one::<T>(id).ok().flatten().cloned()
+}
+
+fn values<T: Any + Clone + Send + Sync>(matches: &ArgMatches, id: &str) -> Vec<T> {
+    match matches.try_get_many::<T>(id) {
+        Ok(Some(values)) => values.cloned().collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn parse_args() -> Args {
+    let matches = cli().get_matches();
+    let mut opts = Args::default();
+    let matches = match matches.subcommand() {
+        Some(("run", matches)) => {
+            opts.run = true;
+            matches
+        }
+        Some(("interp", matches)) => {
+            opts.interpret = true;
+            matches
+        }
+        Some((_, matches)) => {
+            opts.fuzz = true;
+            matches
+        }
+        None => &matches,
+    };
+
+    opts.compiler.include_paths = values(matches, "include");
+    // -D and -U apply in the order they appear on the command line.
+    let mut macros = Vec::new();
+    for (id, is_define) in [("define", true), ("undefine", false)] {
+        if matches.try_contains_id(id).is_ok_and(|present| present) {
+            let names = matches.get_many::<String>(id).unwrap();
+            let indices = matches.indices_of(id).unwrap();
+            macros.extend(
+                indices
+                    .zip(names)
+                    .map(|(i, name)| (i, is_define, name.clone())),
+            );
+        }
+    }
+    macros.sort();
+    opts.compiler.macros = macros.into_iter().map(|(_, d, name)| (d, name)).collect();
+
+    // There are no optimizations yet, so -O1 and above compile as -O0 does.
+    if let Some(level) = value::<u32>(matches, "opt-level").filter(|&level| level > 0) {
+        eprintln!(
+            "warning: -O{} has no effect, as no optimizations are implemented",
+            level
+        );
+    }
+    opts.preprocess_only = flag(matches, "E");
+    opts.emit = match value::<String>(matches, "emit") {
+        Some(name) => EMIT_NAMES.iter().find(|(n, _)| *n == name).map(|(_, e)| *e),
+        None if flag(matches, "S") => Some(Emit::Asm),
+        None if flag(matches, "c") => Some(Emit::Obj),
+        None => None,
+    };
+    opts.front_end = flag(matches, "front-end");
+    opts.seed = value(matches, "seed").unwrap_or(0);
+    opts.iterations = value(matches, "iterations").unwrap_or(0);
+    opts.output = value(matches, "output");
+    opts.format = value::<String>(matches, "format").and_then(|name| Format::from_name(&name));
+    opts.inputs = values(matches, "inputs");
+
+    let target_config: Option<PathBuf> = value(matches, "target-config");
+    opts.target = match target_config.as_deref().map(Target::load).transpose() {
+        Ok(target) => target.unwrap_or_default(),
+        Err(err) => exit_with(err.into()),
+    };
+    if let Some(base) = value(matches, "base") {
+        opts.target.base = base;
+    }
+    if let Some(width) = value(matches, "width") {
+        opts.target.width = width;
+    }
+
+    let single = match opts.emit {
+        _ if opts.preprocess_only => Some("-E".to_string()),
+        _ if flag(matches, "S") => Some("-S".to_string()),
+        _ if flag(matches, "c") => Some("-c".to_string()),
+        None | Some(Emit::Bin) => None,
+        Some(emit) => Some(format!("--emit {}", emit.name())),
+    };
+    if let Some(option) = single {
+        if opts.inputs.len() != 1 {
+            let message = format!("{} takes a single input file", option);
+            cli().error(ErrorKind::WrongNumberOfValues, message).exit();
+        }
+    }
+    if opts.inputs.iter().filter(|path| is_stdin(path)).count() > 1 {
+        let message = "- can only be given once";
+        cli().error(ErrorKind::ArgumentConflict, message).exit();
+    }
+    opts
+}
+
+fn is_stdin(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+fn is_source(path: &Path) -> bool {
+    is_stdin(path) || path.extension().is_some_and(|ext| ext == "ktc")
+}
+
+// Reports the errors that stopped compilation and exits.
+fn exit_with(diags: Diagnostics) -> ! {
+    for diag in &diags.errors {
+        match &diag.location {
+            Some(location) => eprintln!("{}: error: {}", location, diag.message),
+            None => eprintln!("error: {}", diag.message),
+        }
     }
-    let filepath = env::args().nth(1).unwrap();
-    let p = std::fs::read_to_string(filepath).unwrap();
+    process::exit(EXIT_ERROR);
+}
 
-    let token_list = tokenize(p);
-    //  println!("{:?}", token_list);
+fn error(message: String) -> Diagnostics {
+    Diagnostic::new(message).into()
+}
 
-    let mut parser = Parser::new(token_list);
-    let node_list = parser.program();
+fn read_source(opts: &Args, path: &Path) -> Result<String, Diagnostics> {
+    if !is_stdin(path) {
+        return preprocess_file(path, &opts.compiler);
+    }
+    let mut src = String::new();
+    if io::stdin().read_to_string(&mut src).is_err() {
+        return Err(error("Cannot read stdin".to_string()));
+    }
+    preprocess(Path::new("<stdin>"), &src, &opts.compiler)
+}
 
-    let mut codegen = CodeGenerator::new();
+fn compile_unit(p: String) -> Result<Output, Diagnostics> {
+    let output = compile_preprocessed(p)?;
+    for warning in &output.warnings {
+        eprintln!("warning: {}", warning);
+    }
+    Ok(output)
+}
 
-    // println!("{:?}", node_list);
+// Compiles a preprocessed translation unit up to `emit` and returns the text
+// of that stage: its tokens, its syntax tree, its IR or its assembly.
+fn compile_to(p: String, emit: Emit) -> Result<String, Diagnostics> {
+    if emit == Emit::Tokens {
+        return Ok(tokenize(p)?
+            .iter()
+            .map(|token| format!("{}\n", token))
+            .collect());
+    }
+    let output = compile_unit(p)?;
+    if emit == Emit::Ast {
+        let globals = globals(&output.decls).map(|var| format!("{:#?}\n", var));
+        let definitions = functions(&output.decls)
+            .filter(|func| func.is_definition)
+            .map(|func| format!("{:#?}\n", func));
+        return Ok(globals.chain(definitions).collect());
+    }
+    if emit == Emit::Ir {
+        return Ok(output.ir.to_string());
+    }
+    Ok(output.asm)
+}
 
-    println!("main:");
-    println!("  addi sp, sp, -8");
-    println!("  sw ra, sp, 4");
-    println!("  sw fp, sp, 0");
-    println!("  mov fp, sp");
-    println!("  addi sp, sp -{}", parser.locals.offset);
+// Source files are compiled and assembly files are read as objects.
+fn read_object(opts: &Args, path: &Path) -> Result<Object, Diagnostics> {
+    let name = path.display().to_string();
+    if is_source(path) {
+        let asm = compile_to(read_source(opts, path)?, Emit::Asm)?;
+        return Ok(Object::from_asm(&name, &asm)?);
+    }
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(_) => return Err(error(format!("Cannot read {}", name))),
+    };
+    if text.starts_with(".object") {
+        Ok(Object::parse(&name, &text)?)
+    } else {
+        Ok(Object::from_asm(&name, &text)?)
+    }
+}
+
+// Compiles and links a program like the default mode does, and runs it.
+fn compile_and_run(source: &str) -> Result<i32, String> {
+    let target = Target::default();
+    let (bytes, halt) = panic::catch_unwind(|| -> Result<_, Diagnostics> {
+        let output = compile(source, &Options::default())?;
+        let objects = vec![Object::from_asm("<fuzz>", &output.asm)?];
+        let image = link(objects, target.memory)?;
+        let halt = label_address(&image, target.base, HALT)?;
+        Ok((assemble(&image, target.base)?, halt))
+    })
+    .map_err(|_| fuzz::last_panic())?
+    .map_err(|err| err.to_string())?;
+    sim::run(&bytes, target.base, target.memory, halt).map_err(|fault| fault.to_string())
+}
 
-    for node in node_list {
-        codegen.gen(&node);
-        println!("  addi sp, sp, 4");
+fn run_fuzzer(opts: &Args) {
+    fuzz::quiet_panics();
+    let (kind, failures) = if opts.front_end {
+        let failures = fuzz::fuzz_front_end(opts.seed as u64, opts.iterations as usize);
+        let reports = failures.into_iter().map(|(_, report)| report).collect();
+        ("inputs", reports)
+    } else {
+        let failures =
+            fuzz::fuzz_programs(opts.seed as u64, opts.iterations as usize, compile_and_run);
+        ("programs", failures)
+    };
+    for failure in &failures {
+        println!("{}\n", failure);
+    }
+    println!("{} {}, {} failed", opts.iterations, kind, failures.len());
+    if !failures.is_empty() {
+        process::exit(EXIT_ERROR);
+    }
+}
+
+// Writes to the file given by -o, or to stdout without -o or for -o -.
+fn write_output(opts: &Args, contents: &[u8]) -> Result<(), Diagnostics> {
+    let path = opts.output.as_deref().unwrap_or(Path::new("-"));
+    let result = if is_stdin(path) {
+        io::stdout().write_all(contents)
+    } else {
+        fs::write(path, contents)
+    };
+    result.map_err(|_| error(format!("Cannot write {}", path.display())))
+}
+
+fn build(opts: &Args) -> Result<(), Diagnostics> {
+    let target = &opts.target;
+
+    if opts.interpret {
+        let path = &opts.inputs[0];
+        if !is_source(path) {
+            return Err(error(format!("{} is not a .ktc file", path.display())));
+        }
+        let output = compile_unit(read_source(opts, path)?)?;
+        match interp::interpret(&output.decls) {
+            Ok(val) => println!("{}", val),
+            Err(trap) => {
+                eprintln!("error: {}", trap);
+                process::exit(EXIT_FAULT);
+            }
+        }
+        return Ok(());
+    }
+
+    if opts.preprocess_only
+        || matches!(
+            opts.emit,
+            Some(Emit::Tokens | Emit::Ast | Emit::Ir | Emit::Asm)
+        )
+    {
+        let path = &opts.inputs[0];
+        if !is_source(path) {
+            return Err(error(format!("{} is not a .ktc file", path.display())));
+        }
+        let p = read_source(opts, path)?;
+        let text = match opts.emit {
+            Some(emit) if !opts.preprocess_only => compile_to(p, emit)?,
+            _ => p,
+        };
+        return write_output(opts, text.as_bytes());
+    }
+
+    if opts.emit == Some(Emit::Obj) {
+        let obj = read_object(opts, &opts.inputs[0])?.to_string();
+        return write_output(opts, obj.as_bytes());
+    }
+
+    let objects = opts
+        .inputs
+        .iter()
+        .map(|path| read_object(opts, path))
+        .collect::<Result<_, _>>()?;
+    let image = link(objects, target.memory)?;
+    if opts.run {
+        let bytes = assemble(&image, target.base)?;
+        let halt = label_address(&image, target.base, HALT)?;
+        match sim::run(&bytes, target.base, target.memory, halt) {
+            Ok(val) => println!("{}", val),
+            Err(fault) => {
+                eprintln!("error: {}", fault);
+                process::exit(EXIT_FAULT);
+            }
+        }
+        return Ok(());
+    }
+    if opts.emit.is_none() && opts.output.is_none() && opts.format.is_none() {
+        print!("{}", image);
+        return Ok(());
+    }
+    let contents = format_image(
+        &assemble(&image, target.base)?,
+        opts.format.unwrap_or(target.format),
+        target.width,
+        target.base,
+    )?;
+    write_output(opts, &contents)
+}
+
+fn main() {
+    let opts = parse_args();
+    if opts.fuzz {
+        run_fuzzer(&opts);
+        return;
+    }
+    if let Err(diags) = build(&opts) {
+        exit_with(diags);
     }
 }
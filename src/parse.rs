@@ -1,4 +1,7 @@
+use crate::ast::{BinOp, Callee, Decl, Expr, ExprKind, Function, GInit, GVar, LValue, LVar, Stmt};
+use crate::diag::{bail, Diagnostic};
 use crate::token::{Token, TokenKind};
+use crate::types::{is_compatible, truncate, Member, Type, TypeKind};
 
 fn consume(token: &Token, op: &str) -> bool {
     if token.kind != TokenKind::Reserved || token.str != op {
@@ -7,6 +10,13 @@ fn consume(token: &Token, op: &str) -> bool {
     true
 }
 
+fn consume_keyword(token: &Token, keyword: &str) -> bool {
+    if token.kind != TokenKind::Keyword || token.str != keyword {
+        return false;
+    }
+    true
+}
+
 fn consume_ident(token: &Token) -> bool {
     if token.kind != TokenKind::Ident {
         return false;
@@ -14,128 +24,269 @@ fn consume_ident(token: &Token) -> bool {
     true
 }
 
-fn expect(token: &Token, op: &str) {
+fn expect(token: &Token, op: &str) -> Result<(), Diagnostic> {
     if token.kind != TokenKind::Reserved || token.str != op {
-        panic!(" It is not {}", op);
+        bail!("It is not {}", op);
     }
+    Ok(())
 }
 
-fn expect_number(token: &Token) -> i64 {
+fn expect_number(token: &Token) -> Result<i64, Diagnostic> {
     if token.kind != TokenKind::Num {
-        panic!(" It is not number {}", token.str);
+        bail!("It is not number {}", token.str);
     }
-    token.val
+    Ok(token.val)
 }
 
 fn at_eof(token: &Token) -> bool {
     token.kind == TokenKind::Eof
 }
 
-#[derive(Debug, PartialEq, Clone)]
-pub enum NodeKind {
-    Add,
-    Sub,
-    Mul,
-    Div,
-    Eq,
-    Ne,
-    Lt,
-    Le,
-    Assign,
-    Lvar,
-    If,
-    For,
-    While,
-    Return,
-    Num,
+fn new_lvar_expr(lvar: &LVar) -> Expr {
+    let kind = match &lvar.label {
+        Some(label) => ExprKind::Global(label.clone()),
+        None => ExprKind::Local(lvar.offset),
+    };
+    Expr::new(kind, lvar.ty.clone())
+}
+
+fn is_typename(token: &Token) -> bool {
+    if token.kind == TokenKind::Ident {
+        return token.str == "va_list";
+    }
+    if token.kind != TokenKind::Keyword {
+        return false;
+    }
+    matches!(
+        token.str.as_str(),
+        "void"
+            | "char"
+            | "short"
+            | "int"
+            | "long"
+            | "signed"
+            | "unsigned"
+            | "const"
+            | "volatile"
+            | "static"
+            | "extern"
+            | "struct"
+    )
 }
-#[derive(Debug, Clone)]
-pub struct Node {
-    pub kind: NodeKind,
-    pub lhs: Option<Box<Node>>,
-    pub rhs: Option<Box<Node>>,
-    pub val: i64,
-    pub offset: i64,
-    pub cond: Option<Box<Node>>,
-    pub then: Option<Box<Node>>,
-    pub els: Option<Box<Node>>,
-    pub init: Option<Box<Node>>,
-    pub inc: Option<Box<Node>>,
+
+// The keywords the parser implements. The others are reserved all the same,
+// and are rejected before parsing starts.
+const SUPPORTED_KEYWORDS: [&str; 17] = [
+    "char", "const", "else", "extern", "for", "if", "int", "long", "return", "short", "signed",
+    "static", "struct", "unsigned", "void", "volatile", "while",
+];
+
+fn align_to(n: i64, align: i64) -> i64 {
+    (n + align - 1) / align * align
 }
 
-fn new_node(kind: NodeKind, lhs: Node, rhs: Node) -> Node {
-    Node {
-        kind,
-        lhs: Some(Box::new(lhs)),
-        rhs: Some(Box::new(rhs)),
-        val: 0,
-        offset: 0,
-        cond: None,
-        then: None,
-        els: None,
-        init: None,
-        inc: None,
+// Evaluates a constant expression, or returns None if it is not one.
+fn eval(expr: &Expr) -> Option<i64> {
+    let val = match &expr.kind {
+        ExprKind::Num(val) => *val,
+        ExprKind::Binary(op, lhs, rhs) => {
+            let (lhs, rhs) = if op.is_unsigned(&lhs.ty, &rhs.ty) {
+                (eval(lhs)? as u32 as i64, eval(rhs)? as u32 as i64)
+            } else {
+                (eval(lhs)? as i32 as i64, eval(rhs)? as i32 as i64)
+            };
+            match op {
+                BinOp::Add => lhs + rhs,
+                BinOp::Sub => lhs - rhs,
+                BinOp::Mul => lhs * rhs,
+                BinOp::Div | BinOp::Mod if rhs == 0 => return None,
+                BinOp::Div => lhs / rhs,
+                BinOp::Mod => lhs % rhs,
+                BinOp::Shl => lhs << (rhs & 31),
+                BinOp::Shr => lhs >> (rhs & 31),
+                BinOp::Eq => (lhs == rhs) as i64,
+                BinOp::Ne => (lhs != rhs) as i64,
+                BinOp::Lt => (lhs < rhs) as i64,
+                BinOp::Le => (lhs <= rhs) as i64,
+            }
+        }
+        ExprKind::Cast(lhs) if expr.ty.kind != TypeKind::Void => {
+            truncate(eval(lhs)? as u32, &expr.ty) as i64
+        }
+        _ => return None,
+    };
+    if expr.ty.is_unsigned {
+        Some(val as u32 as i64)
+    } else {
+        Some(val as i32 as i64)
     }
 }
 
-fn new_node_none(kind: NodeKind) -> Node {
-    Node {
-        kind,
-        lhs: None,
-        rhs: None,
-        val: 0,
-        offset: 0,
-        cond: None,
-        then: None,
-        els: None,
-        init: None,
-        inc: None,
+// A value written by an initializer, `offset` bytes into the object.
+#[derive(Debug)]
+struct InitItem {
+    offset: i64,
+    ty: Type,
+    expr: Expr,
+}
+
+// Evaluates an expression that may refer to the address of a global, as
+// (label, addend). Without a label it is a plain constant.
+fn eval_reloc(expr: &Expr) -> Option<(Option<String>, i64)> {
+    match &expr.kind {
+        ExprKind::Binary(BinOp::Add, lhs, rhs) => {
+            let (label, val) = eval_reloc(lhs)?;
+            Some((label, val + eval(rhs)?))
+        }
+        ExprKind::Binary(BinOp::Sub, lhs, rhs) if expr.ty.is_pointer() => {
+            let (label, val) = eval_reloc(lhs)?;
+            Some((label, val - eval(rhs)?))
+        }
+        ExprKind::Addr(lhs) => eval_addr(lhs),
+        ExprKind::Cast(lhs) if expr.ty.size == 4 => eval_reloc(lhs),
+        ExprKind::Global(label) if expr.ty.is_aggregate() => Some((Some(label.clone()), 0)),
+        _ => eval(expr).map(|val| (None, val)),
     }
 }
 
-fn new_node_lvar(offset: i64) -> Node {
-    Node {
-        kind: NodeKind::Lvar,
-        lhs: None,
-        rhs: None,
-        val: 0,
-        offset,
-        cond: None,
-        then: None,
-        els: None,
-        init: None,
-        inc: None,
+fn eval_addr(lvalue: &LValue) -> Option<(Option<String>, i64)> {
+    match lvalue {
+        LValue::Global(label) => Some((Some(label.clone()), 0)),
+        LValue::Deref(lhs) => eval_reloc(lhs),
+        LValue::Member(lhs, offset) => {
+            let (label, val) = eval_addr(lhs)?;
+            Some((label, val + offset))
+        }
+        LValue::Local(_) => None,
     }
 }
 
-fn new_node_num(val: i64) -> Node {
-    Node {
-        kind: NodeKind::Num,
-        lhs: None,
-        rhs: None,
-        val,
-        offset: 0,
-        cond: None,
-        then: None,
-        els: None,
-        init: None,
-        inc: None,
+// The static image of an initializer, or None if it is not constant.
+fn static_data(items: &[InitItem]) -> Option<Vec<GInit>> {
+    let mut data = Vec::new();
+    for item in items {
+        if item.ty.kind == TypeKind::Struct {
+            return None;
+        }
+        let (label, val) = eval_reloc(&item.expr)?;
+        if label.is_some() && item.ty.size != 4 {
+            return None;
+        }
+        // Constants are converted to the type of the object, as a store
+        // would do.
+        let shift = 64 - item.ty.size.clamp(1, 4) * 8;
+        let val = if label.is_some() {
+            val
+        } else if item.ty.is_unsigned || item.ty.is_pointer() {
+            ((val as u64) << shift >> shift) as i64
+        } else {
+            val << shift >> shift
+        };
+        data.push(GInit {
+            offset: item.offset,
+            size: item.ty.size,
+            val,
+            label,
+        });
+    }
+    data.sort_by_key(|init| init.offset);
+    Some(data)
+}
+
+// A later initializer for the same bytes, e.g. through a designator, wins.
+fn add_init_item(items: &mut Vec<InitItem>, item: InitItem) {
+    items.retain(|old| {
+        old.offset + old.ty.size <= item.offset || item.offset + item.ty.size <= old.offset
+    });
+    items.push(item);
+}
+
+fn check_complete(ty: &Type, name: &str) -> Result<(), Diagnostic> {
+    if ty.kind == TypeKind::Array && ty.array_len == 0 {
+        bail!("Array size of {} is unknown", name);
     }
+    if ty.kind == TypeKind::Struct && ty.members.is_empty() && !ty.tag.is_empty() {
+        bail!("Variable {} has incomplete type", name);
+    }
+    Ok(())
 }
 
-#[derive(Debug, Clone)]
-pub struct LVar {
-    pub name: String,
-    pub offset: i64,
+// Whether control can never reach the end of a statement.
+fn returns(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Return(_) => true,
+        Stmt::Block(body) => body.iter().any(returns),
+        Stmt::If {
+            then,
+            els: Some(els),
+            ..
+        } => returns(then) && returns(els),
+        Stmt::While { cond, .. } => cond.is_none(),
+        Stmt::For { cond, .. } => cond.is_none(),
+        _ => false,
+    }
+}
+
+// Rejects storing a value of the type of `expr` into an object of type `ty`.
+fn check_assignable(ty: &Type, expr: &Expr) -> Result<(), Diagnostic> {
+    let rhs = &expr.ty;
+    if rhs.kind == TypeKind::Void {
+        bail!("Void value not ignored as it ought to be");
+    }
+    if ty.kind == TypeKind::Ptr {
+        if rhs.is_integer() {
+            // Only a constant 0 is a null pointer.
+            if eval(expr) != Some(0) {
+                bail!("Making pointer from integer without a cast");
+            }
+            return Ok(());
+        }
+        let (base, pointee) = match rhs.pointee() {
+            Some(pointee) => (ty.base.as_ref().unwrap(), pointee),
+            None => bail!("Incompatible types in assignment"),
+        };
+        if pointee.is_const && !base.is_const {
+            bail!("Assignment discards const qualifier");
+        }
+        if base.kind == TypeKind::Void || pointee.kind == TypeKind::Void {
+            return Ok(());
+        }
+        if !is_compatible(base, &pointee) {
+            bail!("Incompatible pointer types in assignment");
+        }
+        return Ok(());
+    }
+    if ty.kind == TypeKind::Struct {
+        if !is_compatible(ty, rhs) {
+            bail!("Incompatible types in assignment");
+        }
+        return Ok(());
+    }
+    if ty.is_integer() && !rhs.is_integer() {
+        bail!("Making integer from pointer without a cast");
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+pub struct VarAttr {
+    pub is_static: bool,
+    pub is_extern: bool,
 }
 
 #[derive(Debug)]
 pub struct Parser {
     pub token_list: Vec<Token>,
     pub i: usize,
-    pub node_list: Vec<Node>,
+    pub functions: Vec<Function>,
     pub lvar_list: Vec<LVar>,
     pub locals: LVar,
+    pub globals: Vec<GVar>,
+    // Index into lvar_list where the innermost scope begins.
+    pub scope: usize,
+    pub tags: Vec<Type>,
+    // Problems that do not stop compilation.
+    pub warnings: Vec<String>,
 }
 
 impl Parser {
@@ -143,228 +294,1518 @@ impl Parser {
         Self {
             token_list,
             i: 0,
-            node_list: Vec::new(),
+            functions: Vec::new(),
             lvar_list: Vec::new(),
             locals: LVar {
                 name: "".to_string(),
                 offset: 0,
+                ty: Type::int(),
+                label: None,
             },
+            globals: Vec::new(),
+            scope: 0,
+            tags: Vec::new(),
+            warnings: Vec::new(),
         }
     }
 
     fn find_lvar(&self, token: &Token) -> Option<&LVar> {
-        self.lvar_list.iter().find(|&lvar| lvar.name == token.str)
+        self.lvar_list
+            .iter()
+            .rev()
+            .find(|&lvar| lvar.name == token.str)
     }
 
-    pub fn program(&mut self) -> Vec<Node> {
+    fn find_function(&self, name: &str) -> Option<&Function> {
+        self.functions.iter().find(|&func| func.name == name)
+    }
+
+    fn find_tag(&self, tag: &str) -> Option<&Type> {
+        self.tags.iter().rev().find(|&ty| ty.tag == tag)
+    }
+
+    // Pointers to a struct declared before its members only carry the tag,
+    // so look the full definition up again.
+    fn complete(&self, ty: Type) -> Type {
+        if ty.kind == TypeKind::Struct && ty.members.is_empty() && !ty.tag.is_empty() {
+            if let Some(def) = self.find_tag(&ty.tag) {
+                let mut def = def.clone();
+                def.is_const = ty.is_const;
+                def.is_volatile = ty.is_volatile;
+                return def;
+            }
+        }
+        ty
+    }
+
+    fn new_lvar(&mut self, name: String, ty: Type) -> LVar {
+        self.locals = LVar {
+            name,
+            offset: align_to(self.locals.offset + ty.size, ty.align),
+            ty,
+            label: None,
+        };
+        self.lvar_list.push(self.locals.clone());
+        self.locals.clone()
+    }
+
+    // Static locals and const objects with constant initializers live in the
+    // data and read-only data sections; the label is unique to keep shadowed
+    // names from clashing.
+    fn new_static_lvar(&mut self, name: String, ty: Type, init: Vec<GInit>) -> LVar {
+        let label = format!("{}.{}", name, self.globals.len());
+        self.globals.push(GVar {
+            label: label.clone(),
+            ty: ty.clone(),
+            init,
+            is_static: true,
+        });
+        let lvar = LVar {
+            name,
+            offset: 0,
+            ty,
+            label: Some(label),
+        };
+        self.lvar_list.push(lvar.clone());
+        lvar
+    }
+
+    // Parses the translation unit. Errors are placed at the token the parser
+    // stopped at.
+    pub fn program(&mut self) -> Result<Vec<Decl>, Diagnostic> {
+        let unsupported = self.token_list.iter().find(|token| {
+            token.kind == TokenKind::Keyword && !SUPPORTED_KEYWORDS.contains(&token.str.as_str())
+        });
+        if let Some(token) = unsupported {
+            let message = format!("Unsupported keyword {}", token.str);
+            return Err(Diagnostic::new(message).at(&token.location));
+        }
+        self.decls()
+            .map_err(|err| err.at(&self.token_list[self.i].location))
+    }
+
+    // program = (function | global-variable)*
+    fn decls(&mut self) -> Result<Vec<Decl>, Diagnostic> {
         while !at_eof(&self.token_list[self.i]) {
-            let node = self.stmt();
-            self.node_list.push(node);
+            let mut attr = VarAttr::default();
+            let basety = self.declspec(&mut attr)?;
+            if consume(&self.token_list[self.i], ";") {
+                self.i += 1;
+                continue;
+            }
+
+            let start = self.i;
+            let (ty, name) = self.declarator(basety.clone())?;
+            if ty.kind == TypeKind::Func {
+                if consume(&self.token_list[self.i], "{") {
+                    self.function(ty, name, &attr)?;
+                } else {
+                    expect(&self.token_list[self.i], ";")?;
+                    self.i += 1;
+                    self.declare_function(&ty, &name, &attr)?;
+                }
+                continue;
+            }
+            self.i = start;
+            self.global_variable(basety, &attr)?;
+        }
+        let functions = self
+            .functions
+            .iter()
+            .cloned()
+            .map(|func| Decl::Function(Box::new(func)));
+        let globals = self.globals.iter().cloned().map(Decl::Var);
+        Ok(functions.chain(globals).collect())
+    }
+
+    // Registers a prototype, or checks it against an earlier declaration.
+    // The body of a definition is filled in once it has been parsed.
+    fn declare_function(
+        &mut self,
+        ty: &Type,
+        name: &str,
+        attr: &VarAttr,
+    ) -> Result<(), Diagnostic> {
+        if name.is_empty() {
+            bail!("Function name omitted");
+        }
+        let params = ty
+            .params
+            .iter()
+            .zip(ty.param_names.iter())
+            .map(|(param_ty, param_name)| LVar {
+                name: param_name.clone(),
+                offset: 0,
+                ty: param_ty.clone(),
+                label: None,
+            })
+            .collect();
+        let func = Function {
+            name: name.to_string(),
+            ty: *ty.return_ty.clone().unwrap(),
+            params,
+            is_variadic: ty.is_variadic,
+            is_definition: false,
+            is_static: attr.is_static,
+            body: Stmt::Block(Vec::new()),
+            stack_size: 0,
+        };
+
+        match self.functions.iter_mut().find(|func| func.name == name) {
+            Some(prev) => {
+                if !is_compatible(&prev.func_ty(), ty) {
+                    bail!("Conflicting types for {}", name);
+                }
+                // A function stays static once declared so.
+                prev.is_static |= attr.is_static;
+            }
+            None => self.functions.push(func),
         }
-        self.node_list.clone()
+        Ok(())
     }
 
-    fn stmt(&mut self) -> Node {
-        if consume(&self.token_list[self.i], "return") {
-            let mut node = new_node_none(NodeKind::Return);
+    // function = declspec declarator compound-stmt
+    fn function(&mut self, ty: Type, name: String, attr: &VarAttr) -> Result<(), Diagnostic> {
+        self.declare_function(&ty, &name, attr)?;
+        if self.find_function(&name).unwrap().is_definition {
+            bail!("Redefinition of function {}", name);
+        }
+
+        let globals_len = self.lvar_list.len();
+        self.scope = globals_len;
+        self.locals.offset = 0;
+
+        let mut params = Vec::new();
+        for (param_ty, param_name) in ty.params.iter().zip(ty.param_names.iter()) {
+            if param_name.is_empty() {
+                bail!("Parameter name omitted in {}", name);
+            }
+            params.push(self.new_lvar(param_name.clone(), param_ty.clone()));
+        }
+
+        // Marked as defined before the body is parsed so that it can call
+        // itself. The body refers to the parameters of the definition.
+        let idx = self
+            .functions
+            .iter()
+            .position(|func| func.name == name)
+            .unwrap();
+        let return_ty = *ty.return_ty.unwrap();
+        let func = &mut self.functions[idx];
+        func.params = params;
+        func.is_definition = true;
+
+        let body = self.compound_stmt()?;
+        if return_ty.kind != TypeKind::Void && name != "main" && !returns(&body) {
+            self.warnings
+                .push(format!("control reaches end of non-void function {}", name));
+        }
+
+        let func = &mut self.functions[idx];
+        func.body = body;
+        func.stack_size = align_to(self.locals.offset, 4);
+
+        self.lvar_list.truncate(globals_len);
+        self.scope = globals_len;
+        Ok(())
+    }
+
+    // global-variable = declarator ("=" expr)? ("," declarator ("=" expr)?)* ";"
+    //
+    // extern declarations only make a variable known; it is defined by this
+    // or another translation unit.
+    fn global_variable(&mut self, basety: Type, attr: &VarAttr) -> Result<(), Diagnostic> {
+        let mut first = true;
+        while !consume(&self.token_list[self.i], ";") {
+            if !first {
+                expect(&self.token_list[self.i], ",")?;
+                self.i += 1;
+            }
+            first = false;
+
+            let (ty, name) = self.declarator(basety.clone())?;
+            if ty.kind == TypeKind::Void {
+                bail!("Variable declared void");
+            }
+            if name.is_empty() {
+                bail!("Variable name omitted");
+            }
+
+            let mut ty = ty;
+            let has_init = consume(&self.token_list[self.i], "=");
+            let mut items = Vec::new();
+            if has_init {
+                if attr.is_extern {
+                    bail!("extern variable {} has an initializer", name);
+                }
+                self.i += 1;
+                self.initializer(&mut ty, 0, &mut items)?;
+            }
+
+            let prev = self.lvar_list.iter().position(|lvar| lvar.name == name);
+            if let Some(prev) = prev {
+                let prev_ty = &self.lvar_list[prev].ty;
+                if !is_compatible(prev_ty, &ty) {
+                    bail!("Conflicting types for {}", name);
+                }
+                // Take the length of an array declared without one before.
+                if prev_ty.kind == TypeKind::Array && prev_ty.array_len != 0 {
+                    ty = prev_ty.clone();
+                }
+            }
+            let gvar = self.globals.iter().position(|gvar| gvar.label == name);
+            if attr.is_extern {
+                if prev.is_none() {
+                    self.lvar_list.push(LVar {
+                        name: name.clone(),
+                        offset: 0,
+                        ty,
+                        label: Some(name),
+                    });
+                }
+                continue;
+            }
+            if gvar.is_some() {
+                bail!("Redefinition of {}", name);
+            }
+
+            check_complete(&ty, &name)?;
+            let init = match static_data(&items) {
+                Some(init) => init,
+                None => bail!("Initializer of {} is not a constant", name),
+            };
+            self.globals.push(GVar {
+                label: name.clone(),
+                ty: ty.clone(),
+                init,
+                is_static: attr.is_static,
+            });
+            let lvar = LVar {
+                name: name.clone(),
+                offset: 0,
+                ty,
+                label: Some(name),
+            };
+            match prev {
+                Some(prev) => self.lvar_list[prev] = lvar,
+                None => self.lvar_list.push(lvar),
+            }
+        }
+        self.i += 1;
+        Ok(())
+    }
+
+    // declspec = ("signed" | "unsigned" | "char" | "short" | "int" | "long"
+    //             | "const" | "volatile" | "static" | "extern" | "struct" struct-decl)+
+    fn declspec(&mut self, attr: &mut VarAttr) -> Result<Type, Diagnostic> {
+        let mut is_signed = false;
+        let mut is_unsigned = false;
+        let mut is_const = false;
+        let mut is_volatile = false;
+        let mut kind = None;
+        let mut int_seen = false;
+        let mut struct_ty = None;
+
+        while is_typename(&self.token_list[self.i]) {
+            if struct_ty.is_some()
+                && !consume_keyword(&self.token_list[self.i], "const")
+                && !consume_keyword(&self.token_list[self.i], "volatile")
+                && !consume_keyword(&self.token_list[self.i], "static")
+                && !consume_keyword(&self.token_list[self.i], "extern")
+            {
+                bail!("Invalid type");
+            }
+            match self.token_list[self.i].str.as_str() {
+                "struct" => {
+                    if int_seen || is_signed || is_unsigned || kind.is_some() {
+                        bail!("Invalid type");
+                    }
+                    self.i += 1;
+                    struct_ty = Some(self.struct_decl()?);
+                    continue;
+                }
+                "const" => is_const = true,
+                "volatile" => is_volatile = true,
+                "static" | "extern" => {
+                    if attr.is_static || attr.is_extern {
+                        bail!("Multiple storage classes");
+                    }
+                    if self.token_list[self.i].str == "static" {
+                        attr.is_static = true;
+                    } else {
+                        attr.is_extern = true;
+                    }
+                }
+                "signed" => {
+                    if is_signed || is_unsigned || kind == Some(TypeKind::Void) {
+                        bail!("Invalid type");
+                    }
+                    is_signed = true;
+                }
+                "unsigned" => {
+                    if is_signed || is_unsigned || kind == Some(TypeKind::Void) {
+                        bail!("Invalid type");
+                    }
+                    is_unsigned = true;
+                }
+                "va_list" => {
+                    if int_seen || is_signed || is_unsigned || kind.is_some() {
+                        bail!("Invalid type");
+                    }
+                    kind = Some(TypeKind::VaList);
+                }
+                "void" => {
+                    if int_seen || is_signed || is_unsigned || kind.is_some() {
+                        bail!("Invalid type");
+                    }
+                    kind = Some(TypeKind::Void);
+                }
+                "int" => {
+                    if int_seen
+                        || matches!(
+                            kind,
+                            Some(TypeKind::Char | TypeKind::Void | TypeKind::VaList)
+                        )
+                    {
+                        bail!("Invalid type");
+                    }
+                    int_seen = true;
+                }
+                "char" => {
+                    if int_seen || kind.is_some() {
+                        bail!("Invalid type");
+                    }
+                    kind = Some(TypeKind::Char);
+                }
+                "short" => {
+                    if kind.is_some() {
+                        bail!("Invalid type");
+                    }
+                    kind = Some(TypeKind::Short);
+                }
+                _ => {
+                    if kind.is_some() {
+                        bail!("Invalid type");
+                    }
+                    kind = Some(TypeKind::Long);
+                }
+            }
+            self.i += 1;
+        }
+
+        let mut ty = match struct_ty {
+            Some(ty) => ty,
+            None => Type::new(kind.unwrap_or(TypeKind::Int), is_unsigned),
+        };
+        ty.is_const = is_const;
+        ty.is_volatile = is_volatile;
+        Ok(ty)
+    }
+
+    // struct-decl = ident? ("{" (declspec declarator ("," declarator)* ";")* "}")?
+    fn struct_decl(&mut self) -> Result<Type, Diagnostic> {
+        let mut tag = String::new();
+        if consume_ident(&self.token_list[self.i]) {
+            tag = self.token_list[self.i].str.clone();
+            self.i += 1;
+        }
+
+        if !tag.is_empty() && !consume(&self.token_list[self.i], "{") {
+            if let Some(ty) = self.find_tag(&tag) {
+                return Ok(ty.clone());
+            }
+            // Incomplete until the definition is seen.
+            let mut ty = Type::new(TypeKind::Struct, false);
+            ty.tag = tag;
+            return Ok(ty);
+        }
+
+        expect(&self.token_list[self.i], "{")?;
+        self.i += 1;
+
+        let mut ty = Type::new(TypeKind::Struct, false);
+        ty.tag = tag;
+        let mut offset = 0;
+        while !consume(&self.token_list[self.i], "}") {
+            let basety = self.declspec(&mut VarAttr::default())?;
+            let mut first = true;
+            while !consume(&self.token_list[self.i], ";") {
+                if !first {
+                    expect(&self.token_list[self.i], ",")?;
+                    self.i += 1;
+                }
+                first = false;
+
+                let (member_ty, name) = self.declarator(basety.clone())?;
+                if member_ty.kind == TypeKind::Void || member_ty.kind == TypeKind::Func {
+                    bail!("Invalid type of member {}", name);
+                }
+                if ty.members.iter().any(|member| member.name == name) {
+                    bail!("Duplicate member {}", name);
+                }
+                offset = align_to(offset, member_ty.align);
+                ty.align = ty.align.max(member_ty.align);
+                ty.members.push(Member {
+                    name,
+                    offset,
+                    ty: member_ty.clone(),
+                });
+                offset += member_ty.size;
+            }
+            self.i += 1;
+        }
+        self.i += 1;
+
+        ty.size = align_to(offset, ty.align);
+        self.tags.push(ty.clone());
+        Ok(ty)
+    }
+
+    // declarator = "*" ("const" | "volatile")* declarator
+    //            | "(" declarator ")" type-suffix
+    //            | ident? type-suffix
+    fn declarator(&mut self, mut ty: Type) -> Result<(Type, String), Diagnostic> {
+        while consume(&self.token_list[self.i], "*") {
+            self.i += 1;
+            ty = Type::pointer_to(ty);
+            loop {
+                if consume_keyword(&self.token_list[self.i], "const") {
+                    ty.is_const = true;
+                } else if consume_keyword(&self.token_list[self.i], "volatile") {
+                    ty.is_volatile = true;
+                } else {
+                    break;
+                }
+                self.i += 1;
+            }
+        }
+
+        // In "int (*fp)(int)" the suffix after the parentheses applies first,
+        // so skip over the nested declarator, read the suffix and come back.
+        if consume(&self.token_list[self.i], "(")
+            && (consume(&self.token_list[self.i + 1], "*")
+                || consume(&self.token_list[self.i + 1], "("))
+        {
+            let start = self.i;
+            self.i += 1;
+            self.declarator(Type::int())?;
+            expect(&self.token_list[self.i], ")")?;
+            self.i += 1;
+            let ty = self.type_suffix(ty)?;
+            let end = self.i;
+
+            self.i = start + 1;
+            let (ty, name) = self.declarator(ty)?;
+            expect(&self.token_list[self.i], ")")?;
+            self.i = end;
+            return Ok((ty, name));
+        }
+
+        let mut name = String::new();
+        if consume_ident(&self.token_list[self.i]) {
+            name = self.token_list[self.i].str.clone();
             self.i += 1;
+        }
+        Ok((self.type_suffix(ty)?, name))
+    }
 
-            node.lhs = Some(Box::new(self.expr()));
+    // type-name = declspec declarator, where the declarator has no name
+    fn type_name(&mut self) -> Result<Type, Diagnostic> {
+        let mut attr = VarAttr::default();
+        let ty = self.declspec(&mut attr)?;
+        if attr.is_static || attr.is_extern {
+            bail!("Storage class in type name");
+        }
+        let (ty, name) = self.declarator(ty)?;
+        if !name.is_empty() {
+            bail!("Unexpected name {} in type name", name);
+        }
+        Ok(ty)
+    }
 
-            expect(&self.token_list[self.i], ";");
+    // type-suffix = "(" func-params | "[" expr? "]" type-suffix | ε
+    fn type_suffix(&mut self, ty: Type) -> Result<Type, Diagnostic> {
+        if consume(&self.token_list[self.i], "(") {
             self.i += 1;
+            return self.func_params(ty);
+        }
+        if consume(&self.token_list[self.i], "[") {
+            self.i += 1;
+            let mut len = 0;
+            if !consume(&self.token_list[self.i], "]") {
+                let expr = self.expr()?;
+                len = match eval(&expr) {
+                    Some(len) if len >= 0 => len,
+                    _ => bail!("Array size is not a constant"),
+                };
+            }
+            expect(&self.token_list[self.i], "]")?;
+            self.i += 1;
+            let base = self.type_suffix(ty)?;
+            return Ok(Type::array_of(base, len));
+        }
+        Ok(ty)
+    }
 
-            node
-        } else if consume(&self.token_list[self.i], "if") {
-            let mut node = new_node_none(NodeKind::If);
+    // func-params = ("void" | param ("," param)* ("," "...")?)? ")"
+    // param = declspec declarator
+    fn func_params(&mut self, return_ty: Type) -> Result<Type, Diagnostic> {
+        let mut params = Vec::new();
+        let mut names = Vec::new();
+        let mut is_variadic = false;
+        if consume_keyword(&self.token_list[self.i], "void")
+            && consume(&self.token_list[self.i + 1], ")")
+        {
             self.i += 1;
+        }
+        while !consume(&self.token_list[self.i], ")") {
+            if !params.is_empty() {
+                expect(&self.token_list[self.i], ",")?;
+                self.i += 1;
+            }
+            if consume(&self.token_list[self.i], "...") {
+                is_variadic = true;
+                self.i += 1;
+                expect(&self.token_list[self.i], ")")?;
+                break;
+            }
+            let basety = self.declspec(&mut VarAttr::default())?;
+            let (ty, name) = self.declarator(basety)?;
+            // Array and function parameters are adjusted to pointers.
+            let ty = match ty.kind {
+                TypeKind::Void => bail!("Parameter declared void"),
+                TypeKind::Array => Type::pointer_to(*ty.base.unwrap()),
+                TypeKind::Func => Type::pointer_to(ty),
+                TypeKind::Struct => bail!("Struct parameters are not supported"),
+                _ => ty,
+            };
+            params.push(ty);
+            names.push(name);
+        }
+        self.i += 1;
+
+        if return_ty.kind == TypeKind::Struct || return_ty.kind == TypeKind::Array {
+            bail!("Invalid return type");
+        }
+        let mut ty = Type::func(return_ty, params, is_variadic);
+        ty.param_names = names;
+        Ok(ty)
+    }
+
+    // declaration = declspec (declarator ("=" expr)? ("," declarator ("=" expr)?)*)? ";"
+    fn declaration(&mut self) -> Result<Stmt, Diagnostic> {
+        let mut attr = VarAttr::default();
+        let basety = self.declspec(&mut attr)?;
+        let mut body = Vec::new();
+
+        let mut first = true;
+        while !consume(&self.token_list[self.i], ";") {
+            if !first {
+                expect(&self.token_list[self.i], ",")?;
+                self.i += 1;
+            }
+            first = false;
+
+            let (ty, name) = self.declarator(basety.clone())?;
+            if ty.kind == TypeKind::Void {
+                bail!("Variable declared void");
+            }
+            if ty.kind == TypeKind::Func {
+                if attr.is_static {
+                    bail!("Invalid storage class for function {}", name);
+                }
+                self.declare_function(&ty, &name, &attr)?;
+                continue;
+            }
+            if name.is_empty() {
+                bail!("Variable name omitted");
+            }
+            if self.lvar_list[self.scope..]
+                .iter()
+                .any(|lvar| lvar.name == name)
+            {
+                bail!("Redefinition of {}", name);
+            }
+
+            // A block-scope extern refers to the global of the same name.
+            if attr.is_extern {
+                if consume(&self.token_list[self.i], "=") {
+                    bail!("extern variable {} has an initializer", name);
+                }
+                self.lvar_list.push(LVar {
+                    name: name.clone(),
+                    offset: 0,
+                    ty,
+                    label: Some(name),
+                });
+                continue;
+            }
+
+            let mut ty = ty;
+            let mut items = Vec::new();
+            let has_init = consume(&self.token_list[self.i], "=");
+            if has_init {
+                self.i += 1;
+                self.initializer(&mut ty, 0, &mut items)?;
+            }
+            check_complete(&ty, &name)?;
+
+            // Only static locals are shared between calls, so a const one
+            // goes to read-only data while every activation of a function
+            // gets its own automatic const objects.
+            if attr.is_static {
+                let Some(data) = static_data(&items) else {
+                    bail!("Initializer of {} is not a constant", name);
+                };
+                self.new_static_lvar(name, ty, data);
+                continue;
+            }
+
+            // Local initializers zero the object unless every byte is
+            // written, then store each value into its place in the frame.
+            let lvar = self.new_lvar(name, ty.clone());
+            if has_init {
+                let covered: i64 = items.iter().map(|item| item.ty.size).sum();
+                if covered < ty.size {
+                    body.push(Stmt::MemZero(Expr::addr(new_lvar_expr(&lvar))?));
+                }
+            }
+            for item in items {
+                let dest = if ty.is_aggregate() {
+                    Expr::member(new_lvar_expr(&lvar), item.offset, item.ty)
+                } else {
+                    new_lvar_expr(&lvar)
+                };
+                body.push(Stmt::Expr(Expr::assign(dest, item.expr)?));
+            }
+        }
+        self.i += 1;
+        Ok(Stmt::Block(body))
+    }
+
+    // Whether the next tokens close a brace-enclosed initializer list.
+    fn is_end(&self) -> bool {
+        consume(&self.token_list[self.i], "}")
+            || (consume(&self.token_list[self.i], ",")
+                && consume(&self.token_list[self.i + 1], "}"))
+    }
 
-            expect(&self.token_list[self.i], "(");
+    fn consume_end(&mut self) -> bool {
+        if consume(&self.token_list[self.i], "}") {
             self.i += 1;
+            return true;
+        }
+        if self.is_end() {
+            self.i += 2;
+            return true;
+        }
+        false
+    }
 
-            node.cond = Some(Box::new(self.expr()));
+    // initializer = string-initializer | array-initializer
+    //             | struct-initializer | "{" assign "}" | assign
+    //
+    // Flattens the initializer into the values to be written to the object
+    // of type `ty`. Arrays of unknown length get their length from it.
+    fn initializer(
+        &mut self,
+        ty: &mut Type,
+        offset: i64,
+        items: &mut Vec<InitItem>,
+    ) -> Result<(), Diagnostic> {
+        let token = &self.token_list[self.i];
+        if ty.kind == TypeKind::Array {
+            if token.kind == TokenKind::Str && ty.base.as_ref().unwrap().kind == TypeKind::Char {
+                self.string_initializer(ty, offset, items);
+            } else if consume(token, "{") {
+                self.i += 1;
+                self.array_initializer(ty, offset, items, true)?;
+            } else if ty.array_len == 0 {
+                bail!("Invalid initializer");
+            } else {
+                self.array_initializer(ty, offset, items, false)?;
+            }
+            return Ok(());
+        }
+
+        if ty.kind == TypeKind::Struct {
+            *ty = self.complete(ty.clone());
+            if consume(token, "{") {
+                self.i += 1;
+                self.struct_initializer(ty, offset, items, true)?;
+                return Ok(());
+            }
+            // A struct may be initialized by another one; otherwise the
+            // braces around its members have been left out.
+            let start = self.i;
+            let expr = self.assign()?;
+            if expr.ty.kind == TypeKind::Struct {
+                check_assignable(ty, &expr)?;
+                add_init_item(
+                    items,
+                    InitItem {
+                        offset,
+                        ty: ty.clone(),
+                        expr,
+                    },
+                );
+                return Ok(());
+            }
+            self.i = start;
+            self.struct_initializer(ty, offset, items, false)?;
+            return Ok(());
+        }
 
-            expect(&self.token_list[self.i], ")");
+        let braced = consume(token, "{");
+        if braced {
             self.i += 1;
+        }
+        let expr = self.assign()?;
+        check_assignable(ty, &expr)?;
+        if braced && !self.consume_end() {
+            bail!("Excess elements in scalar initializer");
+        }
+        add_init_item(
+            items,
+            InitItem {
+                offset,
+                ty: ty.unqualified(),
+                expr,
+            },
+        );
+        Ok(())
+    }
 
-            node.then = Some(Box::new(self.stmt()));
+    // string-initializer = string-literal
+    fn string_initializer(&mut self, ty: &mut Type, offset: i64, items: &mut Vec<InitItem>) {
+        let token = &self.token_list[self.i];
+        let base = *ty.base.clone().unwrap();
+        if ty.array_len == 0 {
+            *ty = Type::array_of(base.clone(), token.contents.len() as i64 + 1);
+        }
+        // The terminating NUL is dropped if it does not fit, and written by
+        // the zero fill otherwise.
+        let len = (ty.array_len as usize).min(token.contents.len());
+        for (i, &c) in token.contents[..len].iter().enumerate() {
+            let expr = Expr::num(c as i8 as i64);
+            add_init_item(
+                items,
+                InitItem {
+                    offset: offset + i as i64,
+                    ty: base.unqualified(),
+                    expr,
+                },
+            );
+        }
+        self.i += 1;
+    }
 
-            if consume(&self.token_list[self.i], "else") {
+    // array-initializer = "{" (designator? initializer ("," designator? initializer)*)? ","? "}"
+    //                   | initializer ("," initializer)*
+    // designator = "[" const-expr "]" "="
+    //
+    // Without braces, only as many elements as the array holds are consumed.
+    fn array_initializer(
+        &mut self,
+        ty: &mut Type,
+        offset: i64,
+        items: &mut Vec<InitItem>,
+        braced: bool,
+    ) -> Result<(), Diagnostic> {
+        let base = *ty.base.clone().unwrap();
+        let is_flexible = ty.array_len == 0;
+        let mut idx = 0;
+        let mut len = 0;
+        let mut first = true;
+        loop {
+            if braced {
+                if self.consume_end() {
+                    break;
+                }
+            } else if idx >= ty.array_len || self.is_end() {
+                break;
+            }
+            if !first {
+                expect(&self.token_list[self.i], ",")?;
+                self.i += 1;
+            }
+            first = false;
+
+            if braced && consume(&self.token_list[self.i], "[") {
+                self.i += 1;
+                let expr = self.expr()?;
+                idx = match eval(&expr) {
+                    Some(idx) if idx >= 0 => idx,
+                    _ => bail!("Array designator is not a non-negative constant"),
+                };
+                expect(&self.token_list[self.i], "]")?;
+                self.i += 1;
+                expect(&self.token_list[self.i], "=")?;
+                self.i += 1;
+            }
+            if !is_flexible && idx >= ty.array_len {
+                bail!("Excess elements in array initializer");
+            }
+
+            let mut elem = base.clone();
+            self.initializer(&mut elem, offset + idx * base.size, items)?;
+            idx += 1;
+            len = len.max(idx);
+        }
+
+        if is_flexible {
+            *ty = Type::array_of(base, len);
+        }
+        Ok(())
+    }
+
+    // struct-initializer = "{" (designator? initializer ("," designator? initializer)*)? ","? "}"
+    //                    | initializer ("," initializer)*
+    // designator = "." ident "="
+    fn struct_initializer(
+        &mut self,
+        ty: &Type,
+        offset: i64,
+        items: &mut Vec<InitItem>,
+        braced: bool,
+    ) -> Result<(), Diagnostic> {
+        let mut idx = 0;
+        let mut first = true;
+        loop {
+            if braced {
+                if self.consume_end() {
+                    break;
+                }
+            } else if idx >= ty.members.len() || self.is_end() {
+                break;
+            }
+            if !first {
+                expect(&self.token_list[self.i], ",")?;
+                self.i += 1;
+            }
+            first = false;
+
+            if braced && consume(&self.token_list[self.i], ".") {
+                self.i += 1;
+                let name = &self.token_list[self.i].str;
+                idx = match ty.members.iter().position(|member| &member.name == name) {
+                    Some(idx) => idx,
+                    None => bail!("No such member {}", name),
+                };
                 self.i += 1;
-                node.els = Some(Box::new(self.stmt()));
-                node
+                expect(&self.token_list[self.i], "=")?;
+                self.i += 1;
+            }
+            if idx >= ty.members.len() {
+                bail!("Excess elements in struct initializer");
+            }
+
+            let member = &ty.members[idx];
+            let mut member_ty = member.ty.clone();
+            self.initializer(&mut member_ty, offset + member.offset, items)?;
+            idx += 1;
+        }
+        Ok(())
+    }
+
+    // compound-stmt = "{" (declaration | stmt)* "}"
+    fn compound_stmt(&mut self) -> Result<Stmt, Diagnostic> {
+        expect(&self.token_list[self.i], "{")?;
+        self.i += 1;
+
+        let scope = self.scope;
+        let len = self.lvar_list.len();
+        let tags_len = self.tags.len();
+        self.scope = len;
+
+        let mut body = Vec::new();
+        while !consume(&self.token_list[self.i], "}") {
+            let stmt = if is_typename(&self.token_list[self.i]) {
+                self.declaration()?
             } else {
-                node
+                self.stmt()?
+            };
+            body.push(stmt);
+        }
+        self.i += 1;
+
+        self.lvar_list.truncate(len);
+        self.tags.truncate(tags_len);
+        self.scope = scope;
+        Ok(Stmt::Block(body))
+    }
+
+    fn stmt(&mut self) -> Result<Stmt, Diagnostic> {
+        if consume(&self.token_list[self.i], "{") {
+            self.compound_stmt()
+        } else if consume(&self.token_list[self.i], ";") {
+            // An empty statement does nothing, like an empty block.
+            self.i += 1;
+            Ok(Stmt::Block(Vec::new()))
+        } else if consume_keyword(&self.token_list[self.i], "return") {
+            self.i += 1;
+
+            let func = self.functions.last().unwrap();
+            let is_void = func.ty.kind == TypeKind::Void;
+            let mut val = None;
+            if consume(&self.token_list[self.i], ";") {
+                if !is_void {
+                    bail!("Non-void function {} should return a value", func.name);
+                }
+            } else {
+                if is_void {
+                    bail!("Void function {} should not return a value", func.name);
+                }
+                val = Some(self.expr()?);
+                expect(&self.token_list[self.i], ";")?;
+            }
+            self.i += 1;
+
+            Ok(Stmt::Return(val))
+        } else if consume_keyword(&self.token_list[self.i], "if") {
+            self.i += 1;
+
+            expect(&self.token_list[self.i], "(")?;
+            self.i += 1;
+
+            let cond = self.expr()?;
+
+            expect(&self.token_list[self.i], ")")?;
+            self.i += 1;
+
+            let then = Box::new(self.stmt()?);
+
+            let mut els = None;
+            if consume_keyword(&self.token_list[self.i], "else") {
+                self.i += 1;
+                els = Some(Box::new(self.stmt()?));
             }
-        } else if consume(&self.token_list[self.i], "while") {
-            let mut node = new_node_none(NodeKind::While);
+            Ok(Stmt::If { cond, then, els })
+        } else if consume_keyword(&self.token_list[self.i], "while") {
             self.i += 1;
 
-            expect(&self.token_list[self.i], "(");
+            expect(&self.token_list[self.i], "(")?;
             self.i += 1;
 
-            node.cond = Some(Box::new(self.expr()));
+            let cond = self.loop_cond()?;
 
-            expect(&self.token_list[self.i], ")");
+            expect(&self.token_list[self.i], ")")?;
             self.i += 1;
 
-            node.then = Some(Box::new(self.stmt()));
-            node
-        } else if consume(&self.token_list[self.i], "for") {
-            let mut node = new_node_none(NodeKind::For);
+            let body = Box::new(self.stmt()?);
+            Ok(Stmt::While { cond, body })
+        } else if consume_keyword(&self.token_list[self.i], "for") {
             self.i += 1;
 
-            expect(&self.token_list[self.i], "(");
+            expect(&self.token_list[self.i], "(")?;
             self.i += 1;
 
+            let mut init = None;
             if !consume(&self.token_list[self.i], ";") {
-                node.init = Some(Box::new(self.expr()));
+                init = Some(Box::new(self.expr()?));
 
-                expect(&self.token_list[self.i], ";");
+                expect(&self.token_list[self.i], ";")?;
             }
             self.i += 1;
 
+            let mut cond = None;
             if !consume(&self.token_list[self.i], ";") {
-                node.cond = Some(Box::new(self.expr()));
+                cond = self.loop_cond()?.map(Box::new);
 
-                expect(&self.token_list[self.i], ";");
+                expect(&self.token_list[self.i], ";")?;
             }
             self.i += 1;
 
+            let mut inc = None;
             if !consume(&self.token_list[self.i], ")") {
-                node.inc = Some(Box::new(self.expr()));
+                inc = Some(Box::new(self.expr()?));
 
-                expect(&self.token_list[self.i], ")");
+                expect(&self.token_list[self.i], ")")?;
             }
             self.i += 1;
 
-            node.then = Some(Box::new(self.stmt()));
-            node
+            let body = Box::new(self.stmt()?);
+            Ok(Stmt::For {
+                init,
+                cond,
+                inc,
+                body,
+            })
         } else {
-            let node = self.expr();
-            expect(&self.token_list[self.i], ";");
+            let stmt = Stmt::Expr(self.expr()?);
+            expect(&self.token_list[self.i], ";")?;
             self.i += 1;
-            node
+            Ok(stmt)
+        }
+    }
+
+    // A condition that is always true is dropped, leaving the same loop as
+    // an empty for-clause.
+    fn loop_cond(&mut self) -> Result<Option<Expr>, Diagnostic> {
+        let cond = self.expr()?;
+        match eval(&cond) {
+            Some(val) if val != 0 => Ok(None),
+            _ => Ok(Some(cond)),
         }
     }
 
-    fn expr(&mut self) -> Node {
+    fn expr(&mut self) -> Result<Expr, Diagnostic> {
         self.assign()
     }
 
-    fn assign(&mut self) -> Node {
-        let mut node = self.equality();
+    fn assign(&mut self) -> Result<Expr, Diagnostic> {
+        let mut node = self.equality()?;
         if consume(&self.token_list[self.i], "=") {
+            if node.ty.is_const {
+                bail!("Cannot assign to const variable");
+            }
             self.i += 1;
-            node = new_node(NodeKind::Assign, node, self.assign());
+            let rhs = self.assign()?;
+            check_assignable(&node.ty, &rhs)?;
+            node = Expr::assign(node, rhs)?;
         }
-        node
+        Ok(node)
     }
 
-    fn equality(&mut self) -> Node {
-        let mut node = self.relational();
+    fn equality(&mut self) -> Result<Expr, Diagnostic> {
+        let mut node = self.relational()?;
         loop {
             if consume(&self.token_list[self.i], "==") {
                 self.i += 1;
-                node = new_node(NodeKind::Eq, node, self.relational());
+                node = Expr::binary(BinOp::Eq, node, self.relational()?);
             } else if consume(&self.token_list[self.i], "!=") {
                 self.i += 1;
-                node = new_node(NodeKind::Ne, node, self.relational());
+                node = Expr::binary(BinOp::Ne, node, self.relational()?);
             } else {
-                return node;
+                return Ok(node);
             }
         }
     }
 
-    fn relational(&mut self) -> Node {
-        let mut node = self.add();
+    fn relational(&mut self) -> Result<Expr, Diagnostic> {
+        let mut node = self.shift()?;
         loop {
             if consume(&self.token_list[self.i], "<") {
                 self.i += 1;
-                node = new_node(NodeKind::Lt, node, self.add());
+                node = Expr::binary(BinOp::Lt, node, self.shift()?);
             } else if consume(&self.token_list[self.i], "<=") {
                 self.i += 1;
-                node = new_node(NodeKind::Le, node, self.add());
+                node = Expr::binary(BinOp::Le, node, self.shift()?);
             } else if consume(&self.token_list[self.i], ">") {
                 self.i += 1;
-                node = new_node(NodeKind::Lt, self.add(), node);
+                node = Expr::binary(BinOp::Lt, self.shift()?, node);
             } else if consume(&self.token_list[self.i], ">=") {
                 self.i += 1;
-                node = new_node(NodeKind::Le, self.add(), node);
+                node = Expr::binary(BinOp::Le, self.shift()?, node);
             } else {
-                return node;
+                return Ok(node);
             }
         }
     }
 
-    fn add(&mut self) -> Node {
-        let mut node = self.mul();
+    fn shift(&mut self) -> Result<Expr, Diagnostic> {
+        let mut node = self.add()?;
+        loop {
+            if consume(&self.token_list[self.i], "<<") {
+                self.i += 1;
+                node = Expr::binary(BinOp::Shl, node, self.add()?);
+            } else if consume(&self.token_list[self.i], ">>") {
+                self.i += 1;
+                node = Expr::binary(BinOp::Shr, node, self.add()?);
+            } else {
+                return Ok(node);
+            }
+        }
+    }
+
+    fn add(&mut self) -> Result<Expr, Diagnostic> {
+        let mut node = self.mul()?;
         loop {
             if consume(&self.token_list[self.i], "+") {
                 self.i += 1;
-                node = new_node(NodeKind::Add, node, self.mul());
+                let rhs = self.mul()?;
+                node = self.new_add(node, rhs)?;
             } else if consume(&self.token_list[self.i], "-") {
                 self.i += 1;
-                node = new_node(NodeKind::Sub, node, self.mul());
+                let rhs = self.mul()?;
+                node = self.new_sub(node, rhs)?;
             } else {
-                return node;
+                return Ok(node);
             }
         }
     }
 
-    fn mul(&mut self) -> Node {
-        let mut node = self.unary();
+    fn mul(&mut self) -> Result<Expr, Diagnostic> {
+        let mut node = self.cast()?;
         loop {
             if consume(&self.token_list[self.i], "*") {
                 self.i += 1;
-                node = new_node(NodeKind::Mul, node, self.mul());
+                node = Expr::binary(BinOp::Mul, node, self.cast()?);
             } else if consume(&self.token_list[self.i], "/") {
                 self.i += 1;
-                node = new_node(NodeKind::Div, node, self.mul());
+                node = Expr::binary(BinOp::Div, node, self.cast()?);
+            } else if consume(&self.token_list[self.i], "%") {
+                self.i += 1;
+                node = Expr::binary(BinOp::Mod, node, self.cast()?);
             } else {
-                return node;
+                return Ok(node);
             }
         }
     }
 
-    fn unary(&mut self) -> Node {
+    // Pointer offsets are scaled by the size of the pointee; the shift
+    // avoids calling __mul for the common power-of-two sizes.
+    fn scale(&self, node: Expr, ptr: &Type) -> Expr {
+        let size = self.complete(ptr.pointee().unwrap()).size;
+        if size == 1 {
+            return node;
+        }
+        if size > 0 && size & (size - 1) == 0 {
+            Expr::binary(BinOp::Shl, node, Expr::num(size.trailing_zeros() as i64))
+        } else {
+            Expr::binary(BinOp::Mul, node, Expr::num(size))
+        }
+    }
+
+    fn new_add(&mut self, mut lhs: Expr, mut rhs: Expr) -> Result<Expr, Diagnostic> {
+        let lhs_ptr = lhs.ty.is_pointer();
+        let rhs_ptr = rhs.ty.is_pointer();
+        if lhs_ptr && rhs_ptr {
+            bail!("Invalid operands to +");
+        }
+        if !lhs_ptr && !rhs_ptr {
+            return Ok(Expr::binary(BinOp::Add, lhs, rhs));
+        }
+        // Canonicalize num + ptr to ptr + num.
+        if rhs_ptr {
+            std::mem::swap(&mut lhs, &mut rhs);
+        }
+        let rhs = self.scale(rhs, &lhs.ty);
+        Ok(Expr::binary(BinOp::Add, lhs, rhs))
+    }
+
+    fn new_sub(&mut self, lhs: Expr, rhs: Expr) -> Result<Expr, Diagnostic> {
+        let lhs_ty = lhs.ty.clone();
+        let rhs_ty = rhs.ty.clone();
+        if !lhs_ty.is_pointer() {
+            if rhs_ty.is_pointer() {
+                bail!("Invalid operands to -");
+            }
+            return Ok(Expr::binary(BinOp::Sub, lhs, rhs));
+        }
+        if !rhs_ty.is_pointer() {
+            let rhs = self.scale(rhs, &lhs_ty);
+            return Ok(Expr::binary(BinOp::Sub, lhs, rhs));
+        }
+
+        // ptr - ptr returns the number of elements between the two.
+        if !is_compatible(&lhs_ty.pointee().unwrap(), &rhs_ty.pointee().unwrap()) {
+            bail!("Invalid operands to -");
+        }
+        let size = self.complete(lhs_ty.pointee().unwrap()).size;
+        let diff = Expr::new(
+            ExprKind::Binary(BinOp::Sub, Box::new(lhs), Box::new(rhs)),
+            Type::int(),
+        );
+        if size > 0 && size & (size - 1) == 0 {
+            Ok(Expr::binary(
+                BinOp::Shr,
+                diff,
+                Expr::num(size.trailing_zeros() as i64),
+            ))
+        } else {
+            Ok(Expr::binary(BinOp::Div, diff, Expr::num(size)))
+        }
+    }
+
+    // cast = "(" type-name ")" cast | unary
+    fn cast(&mut self) -> Result<Expr, Diagnostic> {
+        if !consume(&self.token_list[self.i], "(") || !is_typename(&self.token_list[self.i + 1]) {
+            return self.unary();
+        }
+        self.i += 1;
+        let ty = self.type_name()?;
+        expect(&self.token_list[self.i], ")")?;
+        self.i += 1;
+        let node = self.cast()?;
+        if ty.kind != TypeKind::Void && !ty.is_integer() && ty.kind != TypeKind::Ptr {
+            bail!("Cast to a non-scalar type");
+        }
+        if !node.ty.is_integer() && !node.ty.is_pointer() {
+            bail!("Cast of a non-scalar value");
+        }
+        Ok(Expr::new(ExprKind::Cast(Box::new(node)), ty.unqualified()))
+    }
+
+    // unary = ("+" | "-" | "&" | "*") cast | postfix
+    fn unary(&mut self) -> Result<Expr, Diagnostic> {
         if consume(&self.token_list[self.i], "+") {
             self.i += 1;
-            return self.primary();
+            return self.cast();
         }
         if consume(&self.token_list[self.i], "-") {
             self.i += 1;
-            return new_node(NodeKind::Sub, new_node_num(0), self.primary());
+            return Ok(Expr::binary(BinOp::Sub, Expr::num(0), self.cast()?));
+        }
+        if consume(&self.token_list[self.i], "&") {
+            self.i += 1;
+            return Expr::addr(self.cast()?);
+        }
+        if consume(&self.token_list[self.i], "*") {
+            self.i += 1;
+            return Expr::deref(self.cast()?);
         }
-        self.primary()
+        self.postfix()
+    }
+
+    // postfix = primary ("[" expr "]" | "." ident | "->" ident | "(" func-args)*
+    fn postfix(&mut self) -> Result<Expr, Diagnostic> {
+        let mut node = self.primary()?;
+        loop {
+            if consume(&self.token_list[self.i], "[") {
+                self.i += 1;
+                let idx = self.expr()?;
+                expect(&self.token_list[self.i], "]")?;
+                self.i += 1;
+                node = Expr::deref(self.new_add(node, idx)?)?;
+            } else if consume(&self.token_list[self.i], ".") {
+                self.i += 1;
+                node = self.struct_ref(node)?;
+            } else if consume(&self.token_list[self.i], "->") {
+                self.i += 1;
+                node = self.struct_ref(Expr::deref(node)?)?;
+            } else if consume(&self.token_list[self.i], "(") {
+                self.i += 1;
+                node = self.funcall(node)?;
+            } else {
+                return Ok(node);
+            }
+        }
+    }
+
+    fn struct_ref(&mut self, lhs: Expr) -> Result<Expr, Diagnostic> {
+        let ty = self.complete(lhs.ty.clone());
+        if ty.kind != TypeKind::Struct {
+            bail!("Not a struct");
+        }
+        if !consume_ident(&self.token_list[self.i]) {
+            bail!("It is not identifier {}", self.token_list[self.i].str);
+        }
+        let name = &self.token_list[self.i].str;
+        let member = match ty.members.iter().find(|member| &member.name == name) {
+            Some(member) => member.clone(),
+            None => bail!("No such member {}", name),
+        };
+        self.i += 1;
+
+        let mut member_ty = member.ty;
+        member_ty.is_const |= ty.is_const;
+        member_ty.is_volatile |= ty.is_volatile;
+        Ok(Expr::member(lhs, member.offset, member_ty))
     }
 
-    fn primary(&mut self) -> Node {
+    fn primary(&mut self) -> Result<Expr, Diagnostic> {
         if consume(&self.token_list[self.i], "(") {
             self.i += 1;
-            let node = self.expr();
-            expect(&self.token_list[self.i], ")");
+            let node = self.expr()?;
+            expect(&self.token_list[self.i], ")")?;
             self.i += 1;
-            return node;
+            return Ok(node);
+        }
+        if consume_ident(&self.token_list[self.i]) && consume(&self.token_list[self.i + 1], "(") {
+            match self.token_list[self.i].str.as_str() {
+                "va_start" => return self.va_start(),
+                "va_arg" => return self.va_arg(),
+                "va_end" => return self.va_end(),
+                _ => {}
+            }
         }
         if consume_ident(&self.token_list[self.i]) {
-            if let Some(lvar) = self.find_lvar(&self.token_list[self.i]) {
-                let offset = lvar.offset;
+            let token = &self.token_list[self.i];
+            if let Some(lvar) = self.find_lvar(token) {
+                let node = new_lvar_expr(lvar);
                 self.i += 1;
-                return new_node_lvar(offset);
-            } else {
-                self.locals = LVar {
-                    name: self.token_list[self.i].str.clone(),
-                    offset: self.locals.offset + 4,
-                };
-                self.lvar_list.push(self.locals.clone());
-                let offset = self.locals.offset;
+                return Ok(node);
+            }
+
+            // Functions are referred to by their label. Functions that have
+            // not been declared yet are assumed to return int, and their
+            // arguments are passed as if they were all named.
+            let func_ty = match self.find_function(&token.str) {
+                Some(func) => Some(func.func_ty()),
+                None if consume(&self.token_list[self.i + 1], "(") => {
+                    Some(Type::func(Type::int(), Vec::new(), false))
+                }
+                None => None,
+            };
+            if let Some(func_ty) = func_ty {
+                let node = Expr::new(ExprKind::Global(token.str.clone()), func_ty);
                 self.i += 1;
-                return new_node_lvar(offset);
+                return Ok(node);
+            }
+
+            let lvar = self.new_lvar(token.str.clone(), Type::int());
+            self.i += 1;
+            return Ok(new_lvar_expr(&lvar));
+        }
+        // String literals are anonymous arrays in static storage.
+        if self.token_list[self.i].kind == TokenKind::Str {
+            let token = &self.token_list[self.i];
+            let label = format!(".L.str.{}", self.globals.len());
+            let ty = token.ty.clone().unwrap();
+            let init = token
+                .contents
+                .iter()
+                .enumerate()
+                .map(|(i, &c)| GInit {
+                    offset: i as i64,
+                    size: 1,
+                    val: c as i8 as i64,
+                    label: None,
+                })
+                .collect();
+            self.globals.push(GVar {
+                label: label.clone(),
+                ty: ty.clone(),
+                init,
+                is_static: true,
+            });
+            self.i += 1;
+            return Ok(Expr::new(ExprKind::Global(label), ty));
+        }
+
+        let val = expect_number(&self.token_list[self.i])?;
+        let node = Expr::new(
+            ExprKind::Num(val),
+            self.token_list[self.i].ty.clone().unwrap(),
+        );
+        self.i += 1;
+        Ok(node)
+    }
+
+    // func-args = (assign ("," assign)*)? ")"
+    //
+    // Calls to a function by name jump to its label; anything else is a
+    // function pointer that is called through a register.
+    fn funcall(&mut self, callee: Expr) -> Result<Expr, Diagnostic> {
+        let ty = callee.ty.clone();
+        let func_ty = match ty.kind {
+            TypeKind::Func => ty,
+            TypeKind::Ptr if ty.base.as_ref().unwrap().kind == TypeKind::Func => *ty.base.unwrap(),
+            _ => bail!("Called object is not a function"),
+        };
+
+        let mut args = Vec::new();
+        while !consume(&self.token_list[self.i], ")") {
+            if !args.is_empty() {
+                expect(&self.token_list[self.i], ",")?;
+                self.i += 1;
+            }
+            let arg = self.assign()?;
+            if arg.ty.kind == TypeKind::Void {
+                bail!("Void value used as argument");
+            }
+            args.push(arg);
+        }
+        self.i += 1;
+
+        let name = match &callee.kind {
+            ExprKind::Global(label) => label.clone(),
+            _ => String::new(),
+        };
+        let is_direct = !name.is_empty() && callee.ty.kind == TypeKind::Func;
+        let is_implicit = is_direct && self.find_function(&name).is_none();
+        if !is_implicit {
+            if args.len() < func_ty.params.len()
+                || (!func_ty.is_variadic && args.len() > func_ty.params.len())
+            {
+                match name.as_str() {
+                    "" => bail!("Wrong number of arguments to function pointer"),
+                    name => bail!("Wrong number of arguments to {}", name),
+                }
+            }
+            for (param, arg) in func_ty.params.iter().zip(args.iter()) {
+                check_assignable(param, arg)?;
             }
         }
-        let num = expect_number(&self.token_list[self.i]);
+
+        let callee = if is_direct {
+            Callee::Direct(name)
+        } else {
+            Callee::Indirect(Box::new(callee))
+        };
+        let ty = *func_ty.return_ty.clone().unwrap();
+        Ok(Expr::new(
+            ExprKind::Call {
+                callee,
+                args,
+                func_ty,
+            },
+            ty,
+        ))
+    }
+
+    fn va_list_arg(&mut self) -> Result<LValue, Diagnostic> {
+        let ap = self.assign()?;
+        match ap.kind {
+            ExprKind::Local(offset) if ap.ty.kind == TypeKind::VaList => Ok(LValue::Local(offset)),
+            ExprKind::Global(label) if ap.ty.kind == TypeKind::VaList => Ok(LValue::Global(label)),
+            _ => bail!("It is not va_list variable"),
+        }
+    }
+
+    // va_start = "va_start" "(" ident "," ident ")"
+    fn va_start(&mut self) -> Result<Expr, Diagnostic> {
+        let func = self.functions.last().unwrap();
+        if !func.is_variadic {
+            bail!("va_start used in non-variadic function {}", func.name);
+        }
+        // Variadic arguments follow the named ones that were passed on the stack.
+        let offset = 8 + func.params.len().saturating_sub(4) as i64 * 4;
+        let last = func.params.last().map(|param| param.name.clone());
+        self.i += 2;
+
+        let ap = self.va_list_arg()?;
+        let node = Expr::new(
+            ExprKind::VaStart(ap, offset),
+            Type::new(TypeKind::Void, false),
+        );
+        expect(&self.token_list[self.i], ",")?;
+        self.i += 1;
+        let token = &self.token_list[self.i];
+        if !consume_ident(token) || Some(&token.str) != last.as_ref() {
+            bail!("Second argument to va_start is not the last named parameter");
+        }
+        self.i += 1;
+        expect(&self.token_list[self.i], ")")?;
+        self.i += 1;
+        Ok(node)
+    }
+
+    // va_arg = "va_arg" "(" ident "," type-name ")"
+    fn va_arg(&mut self) -> Result<Expr, Diagnostic> {
+        self.i += 2;
+        let ap = self.va_list_arg()?;
+        expect(&self.token_list[self.i], ",")?;
+        self.i += 1;
+        let ty = self.type_name()?;
+        if matches!(ty.kind, TypeKind::Void | TypeKind::Func) {
+            bail!("Invalid type for va_arg");
+        }
+        expect(&self.token_list[self.i], ")")?;
+        self.i += 1;
+        Ok(Expr::new(ExprKind::VaArg(ap), ty))
+    }
+
+    // va_end = "va_end" "(" ident ")"
+    fn va_end(&mut self) -> Result<Expr, Diagnostic> {
+        self.i += 2;
+        self.va_list_arg()?;
+        expect(&self.token_list[self.i], ")")?;
         self.i += 1;
-        new_node_num(num)
+        Ok(Expr::new(
+            ExprKind::Num(0),
+            Type::new(TypeKind::Void, false),
+        ))
     }
 }
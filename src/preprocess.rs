@@ -0,0 +1,1319 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::diag::{bail, Diagnostic, Location};
+use crate::token::read_char;
+
+#[derive(Debug, Clone, PartialEq)]
+enum PpKind {
+    Ident,
+    Number,
+    Str,
+    Char,
+    Punct,
+    Space,
+    Newline,
+    // Stands for an empty macro argument next to "##".
+    Placemarker,
+    // Marks where an included file ends.
+    EndOfFile,
+}
+
+#[derive(Debug, Clone)]
+struct PpToken {
+    kind: PpKind,
+    text: String,
+    // Index into Preprocessor::files.
+    file: usize,
+    line: usize,
+    // Macros that must not be expanded again in this token.
+    hideset: HashSet<String>,
+}
+
+impl PpToken {
+    fn is(&self, punct: &str) -> bool {
+        self.kind == PpKind::Punct && self.text == punct
+    }
+
+    fn is_space(&self) -> bool {
+        self.kind == PpKind::Space
+    }
+}
+
+const PUNCTUATORS: [&str; 23] = [
+    "<<=", ">>=", "...", "##", "->", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||",
+    "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=",
+];
+
+fn is_ident1(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_ident2(c: char) -> bool {
+    is_ident1(c) || c.is_ascii_digit()
+}
+
+// Splits source text into preprocessing tokens. Comments become a single
+// space and backslash-newline pairs are removed. A quote without its closing
+// one is a token of its own, which is only an error if it is not skipped.
+fn lex(src: &str, file: usize) -> Result<Vec<PpToken>, Diagnostic> {
+    let chars: Vec<char> = src.chars().collect();
+    let starts_with = |i: usize, s: &str| {
+        s.chars()
+            .enumerate()
+            .all(|(k, c)| chars.get(i + k) == Some(&c))
+    };
+
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    let mut line = 1;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        let start_line = line;
+
+        if c == '\\' && chars.get(i + 1) == Some(&'\n') {
+            i += 2;
+            line += 1;
+            continue;
+        }
+
+        let kind = if c == '\n' {
+            i += 1;
+            line += 1;
+            PpKind::Newline
+        } else if c.is_whitespace() {
+            while i < chars.len() && chars[i].is_whitespace() && chars[i] != '\n' {
+                i += 1;
+            }
+            PpKind::Space
+        } else if starts_with(i, "//") {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            PpKind::Space
+        } else if starts_with(i, "/*") {
+            i += 2;
+            while !starts_with(i, "*/") {
+                match chars.get(i) {
+                    Some('\n') => line += 1,
+                    Some(_) => {}
+                    None => bail!("Unterminated comment"),
+                }
+                i += 1;
+            }
+            i += 2;
+            PpKind::Space
+        } else if is_ident1(c) {
+            while i < chars.len() && is_ident2(chars[i]) {
+                i += 1;
+            }
+            PpKind::Ident
+        } else if c.is_ascii_digit()
+            || (c == '.' && chars.get(i + 1).is_some_and(char::is_ascii_digit))
+        {
+            i += 1;
+            while i < chars.len() && (is_ident2(chars[i]) || chars[i] == '.') {
+                i += 1;
+            }
+            PpKind::Number
+        } else if let Some(end) = matches!(c, '"' | '\'')
+            .then(|| literal_end(&chars, i))
+            .flatten()
+        {
+            i = end;
+            if c == '"' {
+                PpKind::Str
+            } else {
+                PpKind::Char
+            }
+        } else {
+            i += PUNCTUATORS
+                .iter()
+                .find(|punct| starts_with(i, punct))
+                .map_or(1, |punct| punct.len());
+            PpKind::Punct
+        };
+
+        let text = if kind == PpKind::Space && chars[start] == '/' {
+            " ".to_string()
+        } else {
+            chars[start..i].iter().collect()
+        };
+        tokens.push(PpToken {
+            kind,
+            text,
+            file,
+            line: start_line,
+            hideset: HashSet::new(),
+        });
+    }
+    Ok(tokens)
+}
+
+// Index of the first token at or after `i` that is not whitespace.
+// The end of the string or character literal starting at `start`, unless it
+// is not closed on the same line.
+fn literal_end(chars: &[char], start: usize) -> Option<usize> {
+    let mut i = start + 1;
+    while chars.get(i) != Some(&chars[start]) {
+        if chars.get(i) == Some(&'\\') {
+            i += 1;
+        }
+        if matches!(chars.get(i), None | Some('\n')) {
+            return None;
+        }
+        i += 1;
+    }
+    Some(i + 1)
+}
+
+fn skip_space(tokens: &[PpToken], mut i: usize) -> usize {
+    while i < tokens.len() && tokens[i].is_space() {
+        i += 1;
+    }
+    i
+}
+
+fn trim_space(tokens: &[PpToken]) -> Vec<PpToken> {
+    let start = skip_space(tokens, 0);
+    let end = tokens
+        .iter()
+        .rposition(|tok| !tok.is_space())
+        .map_or(start, |i| i + 1);
+    tokens[start..end.max(start)].to_vec()
+}
+
+// Turns a macro argument into a string literal for the "#" operator.
+fn stringize(arg: &[PpToken], hash: &PpToken) -> PpToken {
+    let mut text = String::from("\"");
+    for tok in arg {
+        match tok.kind {
+            PpKind::Space => text.push(' '),
+            PpKind::Str | PpKind::Char => {
+                for c in tok.text.chars() {
+                    if c == '"' || c == '\\' {
+                        text.push('\\');
+                    }
+                    text.push(c);
+                }
+            }
+            _ => text.push_str(&tok.text),
+        }
+    }
+    text.push('"');
+    PpToken {
+        kind: PpKind::Str,
+        text,
+        file: hash.file,
+        line: hash.line,
+        hideset: HashSet::new(),
+    }
+}
+
+// Joins two tokens for the "##" operator.
+fn paste(lhs: &PpToken, rhs: &PpToken) -> Result<PpToken, Diagnostic> {
+    if lhs.kind == PpKind::Placemarker {
+        return Ok(rhs.clone());
+    }
+    if rhs.kind == PpKind::Placemarker {
+        return Ok(lhs.clone());
+    }
+    let text = lhs.text.clone() + &rhs.text;
+    let mut tokens = lex(&text, lhs.file)?;
+    if tokens.len() != 1 || tokens[0].is_space() {
+        bail!(
+            "Pasting {} and {} does not give a valid token",
+            lhs.text,
+            rhs.text
+        );
+    }
+    let mut tok = tokens.remove(0);
+    tok.line = lhs.line;
+    Ok(tok)
+}
+
+// Whether two tokens written next to each other would read back as one.
+fn needs_space(prev: &str, next: &str) -> bool {
+    let (Some(last), Some(first)) = (prev.chars().last(), next.chars().next()) else {
+        return false;
+    };
+    if is_ident2(last) && is_ident2(first) {
+        return true;
+    }
+    let joined = prev.to_string() + next;
+    PUNCTUATORS.iter().any(|punct| {
+        punct.len() > prev.len() && joined.starts_with(punct) && punct.starts_with(prev)
+    })
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    // None for object-like macros.
+    params: Option<Vec<String>>,
+    is_variadic: bool,
+    body: Vec<PpToken>,
+}
+
+#[derive(Debug, PartialEq)]
+enum CondCtx {
+    Then,
+    Elif,
+    Else,
+}
+
+// An #if, #ifdef or #ifndef whose #endif has not been seen yet.
+#[derive(Debug)]
+struct CondIncl {
+    ctx: CondCtx,
+    // Whether one of its groups has been taken already.
+    included: bool,
+    // Length of the include stack when it started, so that conditionals
+    // cannot span files.
+    depth: usize,
+}
+
+// Finds the macro of a header wrapped in "#ifndef X / #define X ... #endif",
+// which can be skipped without reading it while X is defined.
+fn detect_include_guard(tokens: &[PpToken]) -> Option<String> {
+    let mut lines = tokens
+        .split(|tok| tok.kind == PpKind::Newline)
+        .map(trim_space)
+        .filter(|line| !line.is_empty());
+
+    let directive = |line: &[PpToken]| -> Option<(String, Vec<PpToken>)> {
+        if !line.first()?.is("#") {
+            return None;
+        }
+        let line = trim_space(&line[1..]);
+        Some((line.first()?.text.clone(), trim_space(&line[1..])))
+    };
+
+    let (name, rest) = directive(&lines.next()?)?;
+    let guard = match (name.as_str(), rest.as_slice()) {
+        ("ifndef", [guard]) if guard.kind == PpKind::Ident => guard.text.clone(),
+        _ => return None,
+    };
+    match directive(&lines.next()?)? {
+        (name, rest) if name == "define" && rest.first()?.text == guard => {}
+        _ => return None,
+    }
+
+    // The matching #endif must be the last line of the file.
+    let mut depth = 1;
+    for line in lines {
+        if depth == 0 {
+            return None;
+        }
+        match directive(&line).map(|(name, _)| name).as_deref() {
+            Some("if" | "ifdef" | "ifndef") => depth += 1,
+            Some("endif") => depth -= 1,
+            _ => {}
+        }
+    }
+    if depth == 0 {
+        Some(guard)
+    } else {
+        None
+    }
+}
+
+// A value of an #if expression. All arithmetic is done in 64 bits, as
+// unsigned if either operand is unsigned.
+#[derive(Debug, Clone, Copy)]
+struct PpValue {
+    val: i64,
+    is_unsigned: bool,
+}
+
+impl PpValue {
+    fn int(val: i64) -> PpValue {
+        PpValue {
+            val,
+            is_unsigned: false,
+        }
+    }
+
+    fn bool(val: bool) -> PpValue {
+        PpValue::int(val as i64)
+    }
+}
+
+// An operation with an undefined result. It is only an error if the value is
+// actually used.
+#[derive(Debug, Clone, Copy)]
+enum Undefined {
+    DivisionByZero,
+    Overflow,
+}
+
+type Value = Result<PpValue, Undefined>;
+
+// Numbers are unsigned with a "u" suffix or when they do not fit in a signed
+// value.
+fn parse_pp_number(text: &str) -> Result<PpValue, Diagnostic> {
+    let lower = text.to_ascii_lowercase();
+    let digits = lower.trim_end_matches(['u', 'l']);
+    let suffix = &lower[digits.len()..];
+    let (digits, radix) = if let Some(hex) = digits.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        (bin, 2)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        (&digits[1..], 8)
+    } else {
+        (digits, 10)
+    };
+    match u64::from_str_radix(digits, radix) {
+        Ok(val) => Ok(PpValue {
+            val: val as i64,
+            is_unsigned: suffix.contains('u') || val > i64::MAX as u64,
+        }),
+        Err(_) => bail!("Invalid integer {} in preprocessor expression", text),
+    }
+}
+
+fn binary_op(op: &str, lhs: PpValue, rhs: PpValue) -> Value {
+    // A shift has the type of its left operand.
+    let is_unsigned = match op {
+        "<<" | ">>" => lhs.is_unsigned,
+        _ => lhs.is_unsigned || rhs.is_unsigned,
+    };
+    let (l, r) = (lhs.val, rhs.val);
+    let ord = if is_unsigned {
+        (l as u64).cmp(&(r as u64))
+    } else {
+        l.cmp(&r)
+    };
+    let val = match op {
+        "|" => l | r,
+        "^" => l ^ r,
+        "&" => l & r,
+        "==" => return Ok(PpValue::bool(l == r)),
+        "!=" => return Ok(PpValue::bool(l != r)),
+        "<" => return Ok(PpValue::bool(ord.is_lt())),
+        "<=" => return Ok(PpValue::bool(ord.is_le())),
+        ">" => return Ok(PpValue::bool(ord.is_gt())),
+        ">=" => return Ok(PpValue::bool(ord.is_ge())),
+        "<<" => l.wrapping_shl(r as u32),
+        ">>" if is_unsigned => (l as u64).wrapping_shr(r as u32) as i64,
+        ">>" => l.wrapping_shr(r as u32),
+        "+" => l.wrapping_add(r),
+        "-" => l.wrapping_sub(r),
+        "*" => l.wrapping_mul(r),
+        _ if r == 0 => return Err(Undefined::DivisionByZero),
+        "/" if is_unsigned => (l as u64 / r as u64) as i64,
+        _ if is_unsigned => (l as u64 % r as u64) as i64,
+        _ if l == i64::MIN && r == -1 => return Err(Undefined::Overflow),
+        "/" => l / r,
+        _ => l % r,
+    };
+    Ok(PpValue { val, is_unsigned })
+}
+
+// Evaluates the constant expression of #if and #elif after macros have been
+// expanded.
+struct CondExpr {
+    tokens: Vec<PpToken>,
+    i: usize,
+}
+
+impl CondExpr {
+    fn peek(&self) -> Option<&PpToken> {
+        self.tokens.get(self.i)
+    }
+
+    fn consume(&mut self, punct: &str) -> bool {
+        if self.peek().is_some_and(|tok| tok.is(punct)) {
+            self.i += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), Diagnostic> {
+        if !self.consume(punct) {
+            bail!("Expected {} in preprocessor expression", punct);
+        }
+        Ok(())
+    }
+
+    // conditional = logor ("?" expr ":" conditional)?
+    //
+    // The result is unsigned if either branch is, whichever one is taken.
+    fn conditional(&mut self) -> Result<Value, Diagnostic> {
+        let cond = self.logor()?;
+        if !self.consume("?") {
+            return Ok(cond);
+        }
+        let then = self.conditional()?;
+        self.expect(":")?;
+        let els = self.conditional()?;
+        let is_unsigned = [then, els]
+            .iter()
+            .any(|val| val.is_ok_and(|val| val.is_unsigned));
+        let val = match cond {
+            Ok(cond) if cond.val != 0 => then,
+            Ok(_) => els,
+            Err(undefined) => Err(undefined),
+        };
+        Ok(val.map(|val| PpValue { is_unsigned, ..val }))
+    }
+
+    fn logor(&mut self) -> Result<Value, Diagnostic> {
+        let mut val = self.logand()?;
+        while self.consume("||") {
+            let rhs = self.logand()?;
+            val = match val {
+                Ok(lhs) if lhs.val != 0 => Ok(PpValue::bool(true)),
+                Ok(_) => rhs.map(|rhs| PpValue::bool(rhs.val != 0)),
+                Err(undefined) => Err(undefined),
+            };
+        }
+        Ok(val)
+    }
+
+    fn logand(&mut self) -> Result<Value, Diagnostic> {
+        let mut val = self.binary(0)?;
+        while self.consume("&&") {
+            let rhs = self.binary(0)?;
+            val = match val {
+                Ok(lhs) if lhs.val == 0 => Ok(PpValue::bool(false)),
+                Ok(_) => rhs.map(|rhs| PpValue::bool(rhs.val != 0)),
+                Err(undefined) => Err(undefined),
+            };
+        }
+        Ok(val)
+    }
+
+    // Binary operators from "|" down to "*", by precedence level.
+    fn binary(&mut self, level: usize) -> Result<Value, Diagnostic> {
+        const LEVELS: [&[&str]; 8] = [
+            &["|"],
+            &["^"],
+            &["&"],
+            &["==", "!="],
+            &["<", "<=", ">", ">="],
+            &["<<", ">>"],
+            &["+", "-"],
+            &["*", "/", "%"],
+        ];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut val = self.binary(level + 1)?;
+        loop {
+            let Some(op) = LEVELS[level]
+                .iter()
+                .find(|op| self.peek().is_some_and(|tok| tok.is(op)))
+            else {
+                return Ok(val);
+            };
+            self.i += 1;
+            let rhs = self.binary(level + 1)?;
+            val = match (val, rhs) {
+                (Ok(lhs), Ok(rhs)) => binary_op(op, lhs, rhs),
+                (Err(undefined), _) | (_, Err(undefined)) => Err(undefined),
+            };
+        }
+    }
+
+    // unary = ("+" | "-" | "!" | "~") unary | "(" conditional ")" | number | char
+    fn unary(&mut self) -> Result<Value, Diagnostic> {
+        if self.consume("+") {
+            return self.unary();
+        }
+        if self.consume("-") {
+            return Ok(self.unary()?.map(|val| PpValue {
+                val: val.val.wrapping_neg(),
+                ..val
+            }));
+        }
+        if self.consume("!") {
+            return Ok(self.unary()?.map(|val| PpValue::bool(val.val == 0)));
+        }
+        if self.consume("~") {
+            return Ok(self.unary()?.map(|val| PpValue {
+                val: !val.val,
+                ..val
+            }));
+        }
+        if self.consume("(") {
+            let val = self.conditional()?;
+            self.expect(")")?;
+            return Ok(val);
+        }
+        let Some(tok) = self.peek() else {
+            bail!("Missing operand in preprocessor expression");
+        };
+        let val = match tok.kind {
+            PpKind::Number => parse_pp_number(&tok.text)?,
+            PpKind::Char => {
+                let bytes = tok.text.as_bytes();
+                let mut i = 1;
+                PpValue::int(read_char(bytes, &mut i)? as i8 as i64)
+            }
+            _ => bail!("Invalid token {} in preprocessor expression", tok.text),
+        };
+        self.i += 1;
+        Ok(Ok(val))
+    }
+}
+
+// Macros whose expansion depends on where they are used. They are always
+// defined, and expanded by expand_macro itself.
+const DYNAMIC_MACROS: [&str; 2] = ["__FILE__", "__LINE__"];
+
+#[derive(Debug, Default)]
+pub struct Preprocessor {
+    include_paths: Vec<PathBuf>,
+    macros: HashMap<String, Macro>,
+    files: Vec<PathBuf>,
+    // Files currently being read, innermost last.
+    include_stack: Vec<PathBuf>,
+    conds: Vec<CondIncl>,
+    include_guards: HashMap<PathBuf, String>,
+    // The file and line of the last token read, where errors are reported.
+    pos: Option<(usize, usize)>,
+}
+
+impl Preprocessor {
+    pub fn new(include_paths: Vec<PathBuf>) -> Self {
+        let mut pp = Self {
+            include_paths,
+            ..Default::default()
+        };
+        pp.define("__KTC32__").unwrap();
+        pp
+    }
+
+    // Defines a macro from a command-line "NAME" or "NAME=VALUE"; without a
+    // value it is defined as 1.
+    pub fn define(&mut self, def: &str) -> Result<(), Diagnostic> {
+        let def = match def.split_once('=') {
+            Some((name, value)) => format!("{} {}", name, value),
+            None => format!("{} 1", def),
+        };
+        let tokens = lex(&def, 0)?;
+        self.define_macro(&tokens)
+    }
+
+    pub fn undefine(&mut self, name: &str) {
+        self.macros.remove(name);
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.macros.contains_key(name) || DYNAMIC_MACROS.contains(&name)
+    }
+
+    // Reads a file and returns its text with directives executed and macros expanded.
+    pub fn preprocess_file(&mut self, path: &Path) -> Result<String, Diagnostic> {
+        let input = self.read_file(path)?;
+        self.expand(input)
+    }
+
+    // Like preprocess_file, for source that does not come from a file, such
+    // as stdin. `name` is used for __FILE__ and to find relative includes.
+    pub fn preprocess_source(&mut self, name: &Path, src: &str) -> Result<String, Diagnostic> {
+        let input = self.lex_file(name, name.to_path_buf(), src)?;
+        self.expand(input)
+    }
+
+    fn location(&self, file: usize, line: usize) -> Location {
+        Location {
+            file: self.files[file].display().to_string(),
+            line,
+        }
+    }
+
+    // Joins the expanded tokens into text. Tokens that do not come from the
+    // line the text has reached are preceded by a `# LINE "FILE"` marker, or
+    // by a few newlines if they come from a bit further down the same file,
+    // so that the tokenizer knows where each token was written.
+    fn expand(&mut self, mut input: Vec<PpToken>) -> Result<String, Diagnostic> {
+        let mut output = Vec::new();
+        if let Err(err) = self.run(&mut input, &mut output, true) {
+            return Err(match self.pos {
+                Some((file, line)) => err.at(&self.location(file, line)),
+                None => err,
+            });
+        }
+
+        let mut text = String::new();
+        let mut prev = String::new();
+        let mut at: Option<(usize, usize)> = None;
+        for tok in output {
+            match tok.kind {
+                PpKind::Newline => {
+                    if let Some((_, line)) = &mut at {
+                        *line += 1;
+                    }
+                }
+                PpKind::Space => {}
+                _ => {
+                    match at {
+                        Some((file, line)) if file == tok.file && line == tok.line => {}
+                        Some((file, line))
+                            if file == tok.file && (line..line + 8).contains(&tok.line) =>
+                        {
+                            text.push_str(&"\n".repeat(tok.line - line));
+                            prev.clear();
+                        }
+                        _ => {
+                            if !text.is_empty() && !text.ends_with('\n') {
+                                text.push('\n');
+                            }
+                            let file = self.files[tok.file].display().to_string();
+                            text.push_str(&format!("# {} {:?}\n", tok.line, file));
+                            prev.clear();
+                        }
+                    }
+                    at = Some((tok.file, tok.line));
+                }
+            }
+            if needs_space(&prev, &tok.text) {
+                text.push(' ');
+            }
+            text.push_str(&tok.text);
+            prev = tok.text;
+        }
+        Ok(text)
+    }
+
+    // Returns the tokens of a file in reverse order, ready to be pushed onto
+    // the input stack.
+    fn read_file(&mut self, path: &Path) -> Result<Vec<PpToken>, Diagnostic> {
+        let canonical = match fs::canonicalize(path) {
+            Ok(canonical) => canonical,
+            Err(_) => bail!("Cannot open {}", path.display()),
+        };
+        if let Some(guard) = self.include_guards.get(&canonical) {
+            if self.macros.contains_key(guard) {
+                return Ok(Vec::new());
+            }
+        }
+        if self.include_stack.contains(&canonical) {
+            let chain: Vec<String> = self
+                .include_stack
+                .iter()
+                .skip_while(|file| **file != canonical)
+                .chain([&canonical])
+                .map(|file| file.display().to_string())
+                .collect();
+            bail!("Include cycle: {}", chain.join(" -> "));
+        }
+        let src = match fs::read_to_string(&canonical) {
+            Ok(src) => src,
+            Err(_) => bail!("Cannot read {}", path.display()),
+        };
+        self.lex_file(path, canonical, &src)
+    }
+
+    fn lex_file(
+        &mut self,
+        path: &Path,
+        canonical: PathBuf,
+        src: &str,
+    ) -> Result<Vec<PpToken>, Diagnostic> {
+        let file = self.files.len();
+        self.files.push(path.to_path_buf());
+        self.include_stack.push(canonical);
+
+        // An unterminated comment is the only error, and it runs to the end.
+        let end = Location {
+            file: path.display().to_string(),
+            line: src.lines().count().max(1),
+        };
+        let mut tokens = lex(src, file).map_err(|err| err.at(&end))?;
+        if let Some(guard) = detect_include_guard(&tokens) {
+            self.include_guards
+                .insert(self.include_stack.last().unwrap().clone(), guard);
+        }
+        let line = tokens.last().map_or(1, |tok| tok.line);
+        // Make sure that a directive on the last line is terminated.
+        for (kind, text) in [(PpKind::Newline, "\n"), (PpKind::EndOfFile, "")] {
+            tokens.push(PpToken {
+                kind,
+                text: text.to_string(),
+                file,
+                line,
+                hideset: HashSet::new(),
+            });
+        }
+        tokens.reverse();
+        Ok(tokens)
+    }
+
+    // Consumes tokens from the reversed `input` stack. Expanded macros are
+    // pushed back onto it so that they are rescanned.
+    fn run(
+        &mut self,
+        input: &mut Vec<PpToken>,
+        output: &mut Vec<PpToken>,
+        directives: bool,
+    ) -> Result<(), Diagnostic> {
+        let mut bol = true;
+        while let Some(tok) = input.pop() {
+            self.pos = Some((tok.file, tok.line));
+            match tok.kind {
+                PpKind::EndOfFile => {
+                    if self
+                        .conds
+                        .last()
+                        .is_some_and(|cond| cond.depth == self.include_stack.len())
+                    {
+                        bail!("Unterminated conditional directive");
+                    }
+                    self.include_stack.pop();
+                    bol = true;
+                    continue;
+                }
+                PpKind::Newline => {
+                    bol = true;
+                    output.push(tok);
+                    continue;
+                }
+                PpKind::Space => {
+                    output.push(tok);
+                    continue;
+                }
+                _ => {}
+            }
+
+            if directives && bol && tok.is("#") {
+                let mut line = Vec::new();
+                while input
+                    .last()
+                    .is_some_and(|tok| !matches!(tok.kind, PpKind::Newline | PpKind::EndOfFile))
+                {
+                    line.push(input.pop().unwrap());
+                }
+                // The newline is kept so that line numbers stay the same.
+                if input.last().is_some_and(|tok| tok.kind == PpKind::Newline) {
+                    output.push(input.pop().unwrap());
+                }
+                self.directive(&tok, &line, input, output)?;
+                continue;
+            }
+            bol = false;
+
+            if tok.kind == PpKind::Ident && self.expand_macro(&tok, input)? {
+                continue;
+            }
+            if tok.is("\"") || tok.is("'") {
+                bail!("Unterminated literal");
+            }
+            output.push(tok);
+        }
+        Ok(())
+    }
+
+    fn directive(
+        &mut self,
+        hash: &PpToken,
+        line: &[PpToken],
+        input: &mut Vec<PpToken>,
+        output: &mut Vec<PpToken>,
+    ) -> Result<(), Diagnostic> {
+        let line = trim_space(line);
+        let Some(name) = line.first() else {
+            // A "#" on its own line does nothing.
+            return Ok(());
+        };
+        let rest = trim_space(&line[1..]);
+        match name.text.as_str() {
+            "include" => self.include(hash, rest, input)?,
+            "define" => self.define_macro(&rest)?,
+            "if" => {
+                let val = self.eval_cond(&rest)?;
+                self.push_cond(val, input, output)?;
+            }
+            "ifdef" | "ifndef" => {
+                let defined = match rest.as_slice() {
+                    [name] if name.kind == PpKind::Ident => self.is_defined(&name.text),
+                    _ => bail!("Invalid #{}", name.text),
+                };
+                self.push_cond(defined == (name.text == "ifdef"), input, output)?;
+            }
+            "elif" => {
+                let cond = match self.conds.last() {
+                    Some(cond) if cond.ctx != CondCtx::Else => cond,
+                    Some(_) => bail!("#elif after #else"),
+                    None => bail!("Stray #elif"),
+                };
+                // Later conditions are not evaluated once a group was taken.
+                let take = !cond.included && self.eval_cond(&rest)?;
+                let cond = self.conds.last_mut().unwrap();
+                cond.ctx = CondCtx::Elif;
+                cond.included |= take;
+                if !take {
+                    self.skip_cond_incl(input, output)?;
+                }
+            }
+            "else" => {
+                let cond = match self.conds.last_mut() {
+                    Some(cond) if cond.ctx != CondCtx::Else => cond,
+                    Some(_) => bail!("Duplicate #else"),
+                    None => bail!("Stray #else"),
+                };
+                cond.ctx = CondCtx::Else;
+                if cond.included {
+                    self.skip_cond_incl(input, output)?;
+                }
+            }
+            "endif" => {
+                if self.conds.pop().is_none() {
+                    bail!("Stray #endif");
+                }
+            }
+            "undef" => {
+                match rest.as_slice() {
+                    [name] if name.kind == PpKind::Ident => self.macros.remove(&name.text),
+                    _ => bail!("Invalid #undef"),
+                };
+            }
+            "error" => {
+                let message: String = rest.iter().map(|tok| tok.text.as_str()).collect();
+                bail!("#error {}", message);
+            }
+            // No pragmas are supported yet.
+            "pragma" => {}
+            _ => bail!("Invalid preprocessor directive #{}", name.text),
+        }
+        Ok(())
+    }
+
+    // include = "#include" ("\"" path "\"" | "<" path ">" | macro)
+    //
+    // Quoted names are searched for next to the including file first, then
+    // in the -I directories; bracketed names only in the -I directories.
+    fn include(
+        &mut self,
+        hash: &PpToken,
+        mut rest: Vec<PpToken>,
+        input: &mut Vec<PpToken>,
+    ) -> Result<(), Diagnostic> {
+        if !rest
+            .first()
+            .is_some_and(|tok| tok.kind == PpKind::Str || tok.is("<"))
+        {
+            rest.reverse();
+            let mut expanded = Vec::new();
+            self.run(&mut rest, &mut expanded, false)?;
+            rest = trim_space(&expanded);
+        }
+
+        let (name, quoted) = match rest.first() {
+            Some(tok) if tok.kind == PpKind::Str && rest.len() == 1 => {
+                (tok.text[1..tok.text.len() - 1].to_string(), true)
+            }
+            Some(tok) if tok.is("<") => match rest.iter().position(|tok| tok.is(">")) {
+                Some(end) if end == rest.len() - 1 => (
+                    rest[1..end].iter().map(|tok| tok.text.as_str()).collect(),
+                    false,
+                ),
+                _ => bail!("Expected > after #include <"),
+            },
+            _ => bail!("Expected a file name after #include"),
+        };
+
+        let mut dirs = Vec::new();
+        if quoted {
+            let current = &self.files[hash.file];
+            dirs.push(current.parent().unwrap_or(Path::new("")).to_path_buf());
+        }
+        dirs.extend(self.include_paths.iter().cloned());
+        let path = match dirs
+            .iter()
+            .map(|dir| dir.join(&name))
+            .find(|path| path.is_file())
+        {
+            Some(path) => path,
+            None => bail!("Cannot find include file {}", name),
+        };
+        let tokens = self.read_file(&path)?;
+        input.extend(tokens);
+        Ok(())
+    }
+
+    fn push_cond(
+        &mut self,
+        take: bool,
+        input: &mut Vec<PpToken>,
+        output: &mut Vec<PpToken>,
+    ) -> Result<(), Diagnostic> {
+        self.conds.push(CondIncl {
+            ctx: CondCtx::Then,
+            included: take,
+            depth: self.include_stack.len(),
+        });
+        if !take {
+            self.skip_cond_incl(input, output)?;
+        }
+        Ok(())
+    }
+
+    // Skips to the #elif, #else or #endif that ends the current group,
+    // leaving it to be read next. Nested conditionals are skipped whole.
+    // Only the newlines are kept.
+    fn skip_cond_incl(
+        &mut self,
+        input: &mut Vec<PpToken>,
+        output: &mut Vec<PpToken>,
+    ) -> Result<(), Diagnostic> {
+        let mut depth = 0;
+        let mut bol = true;
+        while let Some(tok) = input.pop() {
+            self.pos = Some((tok.file, tok.line));
+            match tok.kind {
+                PpKind::EndOfFile => bail!("Unterminated conditional directive"),
+                PpKind::Newline => {
+                    bol = true;
+                    output.push(tok);
+                    continue;
+                }
+                PpKind::Space => continue,
+                _ => {}
+            }
+            if bol && tok.is("#") {
+                let name = input.iter().rev().find(|tok| !tok.is_space());
+                match name.map(|name| name.text.as_str()) {
+                    Some("if" | "ifdef" | "ifndef") => depth += 1,
+                    Some("elif" | "else" | "endif") if depth == 0 => {
+                        input.push(tok);
+                        return Ok(());
+                    }
+                    Some("endif") => depth -= 1,
+                    _ => {}
+                }
+            }
+            bol = false;
+        }
+        Ok(())
+    }
+
+    // Evaluates the expression of #if or #elif. "defined" is resolved before
+    // macros are expanded, and identifiers left after expansion are 0.
+    fn eval_cond(&mut self, line: &[PpToken]) -> Result<bool, Diagnostic> {
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < line.len() {
+            let tok = &line[i];
+            if tok.kind != PpKind::Ident || tok.text != "defined" {
+                tokens.push(tok.clone());
+                i += 1;
+                continue;
+            }
+            i = skip_space(line, i + 1);
+            let parens = line.get(i).is_some_and(|tok| tok.is("("));
+            if parens {
+                i = skip_space(line, i + 1);
+            }
+            let Some(name) = line.get(i).filter(|tok| tok.kind == PpKind::Ident) else {
+                bail!("Macro name must be an identifier");
+            };
+            i += 1;
+            if parens {
+                i = skip_space(line, i);
+                if !line.get(i).is_some_and(|tok| tok.is(")")) {
+                    bail!("Expected ) after defined(");
+                }
+                i += 1;
+            }
+            tokens.push(PpToken {
+                kind: PpKind::Number,
+                text: if self.is_defined(&name.text) {
+                    "1"
+                } else {
+                    "0"
+                }
+                .to_string(),
+                ..name.clone()
+            });
+        }
+
+        tokens.reverse();
+        let mut expanded = Vec::new();
+        self.run(&mut tokens, &mut expanded, false)?;
+        let tokens = expanded
+            .into_iter()
+            .filter(|tok| !matches!(tok.kind, PpKind::Space | PpKind::Newline))
+            .map(|tok| match tok.kind {
+                PpKind::Ident => PpToken {
+                    kind: PpKind::Number,
+                    text: "0".to_string(),
+                    ..tok
+                },
+                _ => tok,
+            })
+            .collect();
+
+        let mut expr = CondExpr { tokens, i: 0 };
+        let val = expr.conditional()?;
+        if expr.i != expr.tokens.len() {
+            bail!(
+                "Extra token {} in preprocessor expression",
+                expr.tokens[expr.i].text
+            );
+        }
+        match val {
+            Ok(val) => Ok(val.val != 0),
+            Err(Undefined::DivisionByZero) => bail!("Division by zero in preprocessor expression"),
+            Err(Undefined::Overflow) => bail!("Integer overflow in preprocessor expression"),
+        }
+    }
+
+    // define = "#define" ident ("(" params? ")")? body
+    // params = ident ("," ident)* ("," "...")? | "..."
+    //
+    // A macro is function-like only if "(" follows the name without a space.
+    fn define_macro(&mut self, rest: &[PpToken]) -> Result<(), Diagnostic> {
+        let Some(name) = rest.first().filter(|tok| tok.kind == PpKind::Ident) else {
+            bail!("Macro name must be an identifier");
+        };
+
+        let mut i = 1;
+        let mut params = None;
+        let mut is_variadic = false;
+        if rest.get(i).is_some_and(|tok| tok.is("(")) {
+            let mut names = Vec::new();
+            i = skip_space(rest, i + 1);
+            if rest.get(i).is_some_and(|tok| tok.is(")")) {
+                i += 1;
+            } else {
+                loop {
+                    match rest.get(i) {
+                        Some(tok) if tok.is("...") => {
+                            is_variadic = true;
+                            names.push("__VA_ARGS__".to_string());
+                        }
+                        Some(tok) if tok.kind == PpKind::Ident => {
+                            if names.contains(&tok.text) {
+                                bail!("Duplicate macro parameter {}", tok.text);
+                            }
+                            names.push(tok.text.clone());
+                        }
+                        _ => bail!("Invalid parameter list of macro {}", name.text),
+                    }
+                    i = skip_space(rest, i + 1);
+                    match rest.get(i) {
+                        Some(tok) if tok.is(")") => break,
+                        Some(tok) if tok.is(",") && !is_variadic => i = skip_space(rest, i + 1),
+                        _ => bail!("Invalid parameter list of macro {}", name.text),
+                    }
+                }
+                i += 1;
+            }
+            params = Some(names);
+        }
+
+        let body = trim_space(&rest[i.min(rest.len())..]);
+        if body.first().is_some_and(|tok| tok.is("##"))
+            || body.last().is_some_and(|tok| tok.is("##"))
+        {
+            bail!("'##' cannot appear at either end of macro {}", name.text);
+        }
+        if let Some(params) = &params {
+            for (j, tok) in body.iter().enumerate() {
+                if tok.is("#") {
+                    let next = body.get(skip_space(&body, j + 1));
+                    if !next.is_some_and(|next| params.contains(&next.text)) {
+                        bail!("'#' is not followed by a parameter of macro {}", name.text);
+                    }
+                }
+            }
+        }
+
+        self.macros.insert(
+            name.text.clone(),
+            Macro {
+                params,
+                is_variadic,
+                body,
+            },
+        );
+        Ok(())
+    }
+
+    // Replaces a macro invocation at the top of `input` with its expansion.
+    // Returns false if `tok` does not start one.
+    fn expand_macro(
+        &mut self,
+        tok: &PpToken,
+        input: &mut Vec<PpToken>,
+    ) -> Result<bool, Diagnostic> {
+        if tok.hideset.contains(&tok.text) {
+            return Ok(false);
+        }
+        let Some(mac) = self.macros.get(&tok.text).cloned() else {
+            // Dynamic macros refer to the line the expansion started on.
+            let (kind, text) = match tok.text.as_str() {
+                "__LINE__" => (PpKind::Number, tok.line.to_string()),
+                "__FILE__" => {
+                    let file = self.files[tok.file].display().to_string();
+                    (PpKind::Str, format!("{:?}", file))
+                }
+                _ => return Ok(false),
+            };
+            input.push(PpToken {
+                kind,
+                text,
+                ..tok.clone()
+            });
+            return Ok(true);
+        };
+
+        let mut hideset = tok.hideset.clone();
+        let body = match &mac.params {
+            None => mac.body.clone(),
+            Some(params) => {
+                // A function-like macro name is left alone unless it is called.
+                let Some(lparen) = input
+                    .iter()
+                    .rposition(|tok| !matches!(tok.kind, PpKind::Space | PpKind::Newline))
+                else {
+                    return Ok(false);
+                };
+                if !input[lparen].is("(") {
+                    return Ok(false);
+                }
+                input.truncate(lparen);
+
+                let (args, rparen) = self.read_args(tok, params, mac.is_variadic, input)?;
+                hideset.retain(|name| rparen.hideset.contains(name));
+                self.subst(&mac, params, &args)?
+            }
+        };
+        hideset.insert(tok.text.clone());
+
+        for mut body_tok in body.into_iter().rev() {
+            body_tok.hideset.extend(hideset.iter().cloned());
+            body_tok.file = tok.file;
+            body_tok.line = tok.line;
+            input.push(body_tok);
+        }
+        Ok(true)
+    }
+
+    // Reads the arguments of a macro call up to the closing parenthesis,
+    // which is returned as well.
+    fn read_args(
+        &mut self,
+        tok: &PpToken,
+        params: &[String],
+        is_variadic: bool,
+        input: &mut Vec<PpToken>,
+    ) -> Result<(Vec<Vec<PpToken>>, PpToken), Diagnostic> {
+        let mut args = vec![Vec::new()];
+        let mut depth = 0;
+        let rparen = loop {
+            let Some(mut arg_tok) = input.pop().filter(|tok| tok.kind != PpKind::EndOfFile) else {
+                bail!("Unterminated argument list invoking macro {}", tok.text);
+            };
+            self.pos = Some((arg_tok.file, arg_tok.line));
+            if arg_tok.is("(") {
+                depth += 1;
+            } else if arg_tok.is(")") {
+                if depth == 0 {
+                    break arg_tok;
+                }
+                depth -= 1;
+            } else if arg_tok.is(",") && depth == 0 && !(is_variadic && args.len() == params.len())
+            {
+                args.push(Vec::new());
+                continue;
+            } else if arg_tok.kind == PpKind::Newline {
+                arg_tok.kind = PpKind::Space;
+                arg_tok.text = " ".to_string();
+            }
+            args.last_mut().unwrap().push(arg_tok);
+        };
+
+        let mut args: Vec<Vec<PpToken>> = args.iter().map(|arg| trim_space(arg)).collect();
+        if params.is_empty() && args.len() == 1 && args[0].is_empty() {
+            args.clear();
+        }
+        if is_variadic && args.len() == params.len() - 1 {
+            args.push(Vec::new());
+        }
+        if args.len() != params.len() {
+            bail!("Wrong number of arguments to macro {}", tok.text);
+        }
+        Ok((args, rparen))
+    }
+
+    // Substitutes arguments into the body of a function-like macro. Operands
+    // of "#" and "##" are used as written; other arguments are fully expanded
+    // first.
+    fn subst(
+        &mut self,
+        mac: &Macro,
+        params: &[String],
+        args: &[Vec<PpToken>],
+    ) -> Result<Vec<PpToken>, Diagnostic> {
+        let param = |tok: &PpToken| {
+            if tok.kind == PpKind::Ident {
+                params.iter().position(|param| *param == tok.text)
+            } else {
+                None
+            }
+        };
+        let placemarker = |tok: &PpToken| PpToken {
+            kind: PpKind::Placemarker,
+            text: String::new(),
+            ..tok.clone()
+        };
+
+        let body = &mac.body;
+        let mut result: Vec<PpToken> = Vec::new();
+        let mut i = 0;
+        while i < body.len() {
+            let tok = &body[i];
+            if tok.is("#") {
+                let next = skip_space(body, i + 1);
+                result.push(stringize(&args[param(&body[next]).unwrap()], tok));
+                i = next + 1;
+                continue;
+            }
+
+            if tok.is("##") {
+                while result.last().is_some_and(PpToken::is_space) {
+                    result.pop();
+                }
+                let next = skip_space(body, i + 1);
+                let rhs = match param(&body[next]) {
+                    Some(idx) if args[idx].is_empty() => vec![placemarker(&body[next])],
+                    Some(idx) => args[idx].clone(),
+                    None => vec![body[next].clone()],
+                };
+                let lhs = result.pop().unwrap();
+                result.push(paste(&lhs, &rhs[0])?);
+                result.extend(rhs[1..].iter().cloned());
+                i = next + 1;
+                continue;
+            }
+
+            if let Some(idx) = param(tok) {
+                let next = skip_space(body, i + 1);
+                if body.get(next).is_some_and(|tok| tok.is("##")) {
+                    if args[idx].is_empty() {
+                        result.push(placemarker(tok));
+                    } else {
+                        result.extend(args[idx].iter().cloned());
+                    }
+                } else {
+                    let mut arg: Vec<PpToken> = args[idx].iter().rev().cloned().collect();
+                    self.run(&mut arg, &mut result, false)?;
+                }
+                i += 1;
+                continue;
+            }
+
+            result.push(tok.clone());
+            i += 1;
+        }
+        result.retain(|tok| tok.kind != PpKind::Placemarker);
+        Ok(result)
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::HashSet;
+
+use crate::ast::BinOp;
+use crate::ir::{Function, Inst, Reg, Terminator};
+
+// Linear-scan register allocation for the IR of a function. Every virtual
+// register keeps one location for its whole lifetime: a machine register,
+// or a word of the frame when there are too few of them. Instructions and
+// terminators are numbered in layout order, and a lifetime runs from the
+// first definition to the last use, widened over the blocks it is live
+// across.
+//
+// a0-a3 and s0-s3 are handed out, and t0-t2 are left to the code generator
+// for reloading spilled values. Calls may change a0-a3, so values that live
+// across one go to s0-s3, which the function saves for its caller.
+
+const REGS: [&str; 8] = ["a0", "a1", "a2", "a3", "s0", "s1", "s2", "s3"];
+
+// The first callee-saved register in REGS.
+const SAVED: usize = 4;
+
+// Sets of REGS, one bit per register.
+const CALL_CLOBBERS: u8 = 0x0f;
+// __mul and __udivmod use a0-a2.
+const RUNTIME_CLOBBERS: u8 = 0x07;
+
+const ARGREGS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Loc {
+    Reg(&'static str),
+    // A word of the frame, numbered from 0.
+    Spill(usize),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Allocation {
+    // By virtual register. Registers that were only used in unreachable
+    // code are never read or written, and are left in zero.
+    pub locs: Vec<Loc>,
+    pub spills: usize,
+    // The callee-saved registers that are used, in order.
+    pub saved: Vec<&'static str>,
+}
+
+// A register a value would rather be in, so that a move can be left out.
+#[derive(Debug, Clone, Copy)]
+enum Hint {
+    // It is passed in or returned from this register of REGS.
+    Fixed(usize),
+    // It is computed from this value in place.
+    Same(Reg),
+}
+
+// The registers of REGS an instruction may change besides its result.
+fn clobbers(inst: &Inst) -> u8 {
+    match inst {
+        Inst::Call { .. } => CALL_CLOBBERS,
+        Inst::Binary {
+            op: BinOp::Mul | BinOp::Div | BinOp::Mod,
+            ..
+        } => RUNTIME_CLOBBERS,
+        _ => 0,
+    }
+}
+
+// The first hint found for a value is kept, so a definition that can be
+// done in place wins over the register it is moved to later.
+fn hints(func: &Function) -> Vec<Option<Hint>> {
+    let mut hints = vec![None; func.nregs];
+    let mut hint = |reg: Reg, hint: Hint| {
+        hints[reg.0].get_or_insert(hint);
+    };
+    for (i, &param) in func.params.iter().take(ARGREGS).enumerate() {
+        hint(param, Hint::Fixed(i));
+    }
+    for block in &func.blocks {
+        for inst in &block.insts {
+            match inst {
+                Inst::Binary {
+                    op: op @ (BinOp::Mul | BinOp::Div | BinOp::Mod),
+                    dst,
+                    lhs,
+                    rhs,
+                    ..
+                } => {
+                    let result = if *op == BinOp::Mod { 1 } else { 0 };
+                    hint(*dst, Hint::Fixed(result));
+                    hint(*lhs, Hint::Fixed(0));
+                    hint(*rhs, Hint::Fixed(1));
+                }
+                Inst::Binary { dst, lhs, .. } => hint(*dst, Hint::Same(*lhs)),
+                Inst::Move { dst, src } | Inst::Ext { dst, src, .. } => {
+                    hint(*dst, Hint::Same(*src))
+                }
+                Inst::Call {
+                    dst, args, nregs, ..
+                } => {
+                    for (i, &arg) in args[..*nregs].iter().enumerate() {
+                        hint(arg, Hint::Fixed(i));
+                    }
+                    hint(*dst, Hint::Fixed(0));
+                }
+                _ => {}
+            }
+        }
+        if let Terminator::Return(Some(val)) = block.term {
+            hint(val, Hint::Fixed(0));
+        }
+    }
+    hints
+}
+
+// The values live on entry to and on exit from each block.
+fn liveness(func: &Function) -> (Vec<HashSet<Reg>>, Vec<HashSet<Reg>>) {
+    let n = func.blocks.len();
+    let mut gen = vec![HashSet::new(); n];
+    let mut kill = vec![HashSet::new(); n];
+    for (b, block) in func.blocks.iter().enumerate() {
+        for inst in &block.insts {
+            for reg in inst.uses() {
+                if !kill[b].contains(&reg) {
+                    gen[b].insert(reg);
+                }
+            }
+            if let Some(dst) = inst.dst() {
+                kill[b].insert(dst);
+            }
+        }
+        for reg in block.term.uses() {
+            if !kill[b].contains(&reg) {
+                gen[b].insert(reg);
+            }
+        }
+    }
+
+    let mut live_in: Vec<HashSet<Reg>> = vec![HashSet::new(); n];
+    let mut live_out: Vec<HashSet<Reg>> = vec![HashSet::new(); n];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for b in (0..n).rev() {
+            let out: HashSet<Reg> = func.blocks[b]
+                .term
+                .successors()
+                .iter()
+                .flat_map(|succ| live_in[succ.0].iter().copied())
+                .collect();
+            let mut inn = gen[b].clone();
+            inn.extend(out.difference(&kill[b]));
+            if inn != live_in[b] || out != live_out[b] {
+                live_in[b] = inn;
+                live_out[b] = out;
+                changed = true;
+            }
+        }
+    }
+    (live_in, live_out)
+}
+
+pub fn allocate(func: &Function) -> Allocation {
+    let nregs = func.nregs;
+    let (live_in, live_out) = liveness(func);
+
+    // Parameters are defined on entry, at position 0, before the first
+    // instruction reads them.
+    let mut start = vec![usize::MAX; nregs];
+    let mut end = vec![0; nregs];
+    let mut mark = |reg: Reg, pos: usize| {
+        start[reg.0] = start[reg.0].min(pos);
+        end[reg.0] = end[reg.0].max(pos);
+    };
+    for &param in &func.params {
+        mark(param, 0);
+    }
+    let mut calls = Vec::new();
+    let mut pos = 1;
+    for (b, block) in func.blocks.iter().enumerate() {
+        let term = pos + block.insts.len();
+        for &reg in &live_in[b] {
+            mark(reg, pos);
+        }
+        for &reg in &live_out[b] {
+            mark(reg, term);
+        }
+        for inst in &block.insts {
+            for reg in inst.uses() {
+                mark(reg, pos);
+            }
+            if let Some(dst) = inst.dst() {
+                mark(dst, pos);
+            }
+            if clobbers(inst) != 0 {
+                calls.push((pos, clobbers(inst)));
+            }
+            pos += 1;
+        }
+        for reg in block.term.uses() {
+            mark(reg, term);
+        }
+        pos += 1;
+    }
+
+    // A value cannot be kept in a register that an instruction in the middle
+    // of its lifetime changes. Operands are read before the result is
+    // written, so a value that ends where another starts does not overlap it.
+    let forbidden: Vec<u8> = (0..nregs)
+        .map(|reg| {
+            calls
+                .iter()
+                .filter(|&&(pos, _)| start[reg] < pos && pos < end[reg])
+                .fold(0, |mask, &(_, clobbers)| mask | clobbers)
+        })
+        .collect();
+
+    let hints = hints(func);
+    let mut order: Vec<usize> = (0..nregs).filter(|&reg| start[reg] != usize::MAX).collect();
+    order.sort_by_key(|&reg| (start[reg], reg));
+
+    let mut assigned: Vec<Option<usize>> = vec![None; nregs];
+    let mut spilled: Vec<Option<usize>> = vec![None; nregs];
+    let mut spills = 0;
+    let mut active: Vec<usize> = Vec::new();
+    for reg in order {
+        active.retain(|&other| end[other] > start[reg]);
+        let busy = active
+            .iter()
+            .filter_map(|&other| assigned[other])
+            .fold(0u8, |mask, i| mask | 1 << i);
+        let free = !busy & !forbidden[reg];
+        let hint = match hints[reg] {
+            Some(Hint::Fixed(i)) => Some(i),
+            Some(Hint::Same(other)) => assigned[other.0],
+            None => None,
+        };
+        let choice = hint
+            .filter(|&i| free & 1 << i != 0)
+            .or_else(|| (0..REGS.len()).find(|&i| free & 1 << i != 0));
+        if let Some(i) = choice {
+            assigned[reg] = Some(i);
+            active.push(reg);
+            continue;
+        }
+
+        // Out of registers: whichever value that could give up its register
+        // lives the longest goes to the frame.
+        let victim = active
+            .iter()
+            .copied()
+            .filter(|&other| forbidden[reg] & 1 << assigned[other].unwrap() == 0)
+            .max_by_key(|&other| end[other]);
+        let spill = match victim {
+            Some(victim) if end[victim] > end[reg] => {
+                assigned[reg] = assigned[victim].take();
+                active.retain(|&other| other != victim);
+                active.push(reg);
+                victim
+            }
+            _ => reg,
+        };
+        spilled[spill] = Some(spills);
+        spills += 1;
+    }
+
+    let locs: Vec<Loc> = (0..nregs)
+        .map(|reg| match (assigned[reg], spilled[reg]) {
+            (Some(i), _) => Loc::Reg(REGS[i]),
+            (None, Some(slot)) => Loc::Spill(slot),
+            (None, None) => Loc::Reg("zero"),
+        })
+        .collect();
+    let used: HashSet<usize> = assigned.iter().flatten().copied().collect();
+    let saved = (SAVED..REGS.len())
+        .filter(|i| used.contains(i))
+        .map(|i| REGS[i])
+        .collect();
+    Allocation {
+        locs,
+        spills,
+        saved,
+    }
+}
@@ -0,0 +1,241 @@
+use std::fmt;
+
+use crate::asm::mnemonic;
+
+// Instruction-set simulator for KTC32, using the encoding of asm.rs.
+
+// Stops programs that never return.
+pub(crate) const MAX_STEPS: u64 = 10_000_000;
+
+const SP: usize = 2;
+const FLAG: usize = 4;
+const A0: usize = 8;
+
+/// Why the simulator stopped a program before main returned.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    InvalidInstruction {
+        pc: u32,
+        opcode: u32,
+    },
+    InvalidAddress {
+        pc: u32,
+        addr: u32,
+    },
+    /// The image runs past the end of memory when loaded at `base`.
+    ImageTooLarge {
+        base: u32,
+    },
+    /// The stack grew down into the image.
+    StackOverflow {
+        pc: u32,
+        sp: u32,
+    },
+    StepLimit,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::InvalidInstruction { pc, opcode } => {
+                write!(f, "invalid instruction {:#x} at {:#x}", opcode, pc)
+            }
+            Fault::InvalidAddress { pc, addr } => {
+                write!(f, "invalid memory access to {:#x} at {:#x}", addr, pc)
+            }
+            Fault::ImageTooLarge { base } => {
+                write!(f, "image does not fit in memory at base {:#x}", base)
+            }
+            Fault::StackOverflow { pc, sp } => {
+                write!(f, "stack overflow to {:#x} at {:#x}", sp, pc)
+            }
+            Fault::StepLimit => write!(f, "no return after {} steps", MAX_STEPS),
+        }
+    }
+}
+
+pub(crate) struct Cpu {
+    pub(crate) regs: [u32; 16],
+    pub(crate) pc: u32,
+    pub(crate) mem: Vec<u8>,
+    pub(crate) steps: u64,
+    // Where the program stops, if it can.
+    halt: Option<u32>,
+    // The end of the image, which the stack must stay above.
+    image_end: u32,
+}
+
+impl Cpu {
+    // Loads an image at `base` into `memory` bytes and starts executing it
+    // there, until it reaches `halt`.
+    pub(crate) fn new(
+        image: &[u8],
+        base: u32,
+        memory: u32,
+        halt: Option<u32>,
+    ) -> Result<Cpu, Fault> {
+        let mut mem = vec![0; memory as usize];
+        let start = base as usize;
+        if start + image.len() > mem.len() {
+            return Err(Fault::ImageTooLarge { base });
+        }
+        mem[start..start + image.len()].copy_from_slice(image);
+        Ok(Cpu {
+            regs: [0; 16],
+            pc: base,
+            mem,
+            steps: 0,
+            halt,
+            image_end: base + image.len() as u32,
+        })
+    }
+
+    fn load(&self, addr: u32, size: usize) -> Result<u32, Fault> {
+        let start = addr as usize;
+        match self.mem.get(start..start + size) {
+            Some(bytes) => {
+                let mut word = [0; 4];
+                word[..size].copy_from_slice(bytes);
+                Ok(u32::from_le_bytes(word))
+            }
+            None => Err(Fault::InvalidAddress { pc: self.pc, addr }),
+        }
+    }
+
+    fn store(&mut self, addr: u32, size: usize, val: u32) -> Result<(), Fault> {
+        let start = addr as usize;
+        match self.mem.get_mut(start..start + size) {
+            Some(bytes) => {
+                bytes.copy_from_slice(&val.to_le_bytes()[..size]);
+                Ok(())
+            }
+            None => Err(Fault::InvalidAddress { pc: self.pc, addr }),
+        }
+    }
+
+    fn set(&mut self, reg: usize, val: u32) {
+        // zero is hardwired.
+        if reg != 0 {
+            self.regs[reg] = val;
+        }
+    }
+
+    // Executes one instruction. Moving sp below the end of the image means
+    // the stack has run into it.
+    pub(crate) fn step(&mut self) -> Result<(), Fault> {
+        let pc = self.pc;
+        let sp = self.regs[SP];
+        self.execute()?;
+        if self.regs[SP] != sp && self.regs[SP] < self.image_end {
+            let sp = self.regs[SP];
+            return Err(Fault::StackOverflow { pc, sp });
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self) -> Result<(), Fault> {
+        let inst = self.load(self.pc, 2)?;
+        let opcode = inst & 0x3f;
+        let rd = (inst >> 6 & 0x1f) as usize;
+        let rs = (inst >> 11 & 0x1f) as usize;
+        let invalid = Fault::InvalidInstruction {
+            pc: self.pc,
+            opcode,
+        };
+        let Some(op) = mnemonic(opcode) else {
+            return Err(invalid);
+        };
+        if rd >= 16 || (rs >= 16 && op != "jal") {
+            return Err(invalid);
+        }
+        let a = self.regs[rd];
+        let b = self.regs[rs & 15];
+
+        if opcode < 0x20 {
+            match op {
+                "mov" => self.set(rd, b),
+                "add" => self.set(rd, a.wrapping_add(b)),
+                "sub" => self.set(rd, a.wrapping_sub(b)),
+                "and" => self.set(rd, a & b),
+                "or" => self.set(rd, a | b),
+                "xor" => self.set(rd, a ^ b),
+                "sll" => self.set(rd, a << (b & 31)),
+                "srl" => self.set(rd, a >> (b & 31)),
+                "sra" => self.set(rd, ((a as i32) >> (b & 31)) as u32),
+                "slt" => self.set(FLAG, ((a as i32) < (b as i32)) as u32),
+                "sltu" => self.set(FLAG, (a < b) as u32),
+                _ => unreachable!(),
+            }
+            self.pc = self.pc.wrapping_add(2);
+            return Ok(());
+        }
+
+        let imm = self.load(self.pc, 4)? >> 16;
+        let simm = imm as u16 as i16 as i32 as u32;
+        let addr = b.wrapping_add(simm);
+        let next = self.pc.wrapping_add(4);
+        let mut pc = next;
+        match op {
+            "addi" => self.set(rd, addr),
+            "andi" => self.set(rd, b & imm),
+            "ori" => self.set(rd, b | imm),
+            "xori" => self.set(rd, b ^ imm),
+            "slli" => self.set(rd, b << (imm & 31)),
+            "srli" => self.set(rd, b >> (imm & 31)),
+            "srai" => self.set(rd, ((b as i32) >> (imm & 31)) as u32),
+            "lui" => self.set(rd, imm << 16),
+            "lw" => self.set(rd, self.load(addr, 4)?),
+            "lh" => self.set(rd, self.load(addr, 2)? as u16 as i16 as i32 as u32),
+            "lhu" => self.set(rd, self.load(addr, 2)?),
+            "lb" => self.set(rd, self.load(addr, 1)? as u8 as i8 as i32 as u32),
+            "lbu" => self.set(rd, self.load(addr, 1)?),
+            "sw" => self.store(addr, 4, a)?,
+            "sh" => self.store(addr, 2, a)?,
+            "sb" => self.store(addr, 1, a)?,
+            "beq" | "bnq" | "blt" => {
+                let taken = match op {
+                    "beq" => a == b,
+                    "bnq" => a != b,
+                    _ => (a as i32) < (b as i32),
+                };
+                if taken {
+                    pc = next.wrapping_add(simm);
+                }
+            }
+            "jal" => {
+                let offset = ((rs as u32) << 16 | imm) << 11;
+                pc = next.wrapping_add(((offset as i32) >> 11) as u32);
+                self.set(rd, next);
+            }
+            "jalr" => {
+                pc = addr;
+                self.set(rd, next);
+            }
+            _ => unreachable!(),
+        }
+
+        self.pc = pc;
+        Ok(())
+    }
+
+    // Runs until the program reaches its halt address and returns the value
+    // left in a0, which is what main returned when started by the linker's
+    // _start.
+    pub(crate) fn run(&mut self) -> Result<i32, Fault> {
+        while Some(self.pc) != self.halt {
+            self.step()?;
+            self.steps += 1;
+            if self.steps >= MAX_STEPS {
+                return Err(Fault::StepLimit);
+            }
+        }
+        Ok(self.regs[A0] as i32)
+    }
+}
+
+/// Runs an image assembled for address `base` until it reaches `halt`, the
+/// address of the linker's `__halt` label. Without one, it only stops at a
+/// fault.
+pub fn run(image: &[u8], base: u32, memory: u32, halt: Option<u32>) -> Result<i32, Fault> {
+    Cpu::new(image, base, memory, halt)?.run()
+}
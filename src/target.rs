@@ -0,0 +1,85 @@
+use std::fs;
+use std::path::Path;
+
+use crate::diag::{bail, Diagnostic};
+use crate::image::Format;
+
+/// The board a program is built for. A --target-config file sets these with
+/// `key = value` lines, and options on the command line override them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    /// Bytes of memory from address 0. The stack starts at the top.
+    pub memory: u32,
+    /// Address the image is loaded at.
+    pub base: u32,
+    /// How an assembled image is written.
+    pub format: Format,
+    /// Bits per line of readmemh output.
+    pub width: u32,
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Target {
+            memory: 0x10000,
+            base: 0,
+            format: Format::Bin,
+            width: 32,
+        }
+    }
+}
+
+/// Reads a decimal or `0x` hexadecimal number, as the settings use.
+pub fn parse_number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+impl Target {
+    /// Reads a target configuration. Settings that are not given keep their
+    /// default. `#` starts a comment.
+    pub fn parse(name: &str, text: &str) -> Result<Target, Diagnostic> {
+        let mut target = Target::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                bail!("Expected key = value in {} at line {}", name, i + 1);
+            };
+            let (key, value) = (key.trim(), value.trim());
+            if key == "format" {
+                match Format::from_name(value) {
+                    Some(format) => target.format = format,
+                    None => bail!("Unknown format {} in {} at line {}", value, name, i + 1),
+                }
+                continue;
+            }
+            let field = match key {
+                "memory" => &mut target.memory,
+                "base" => &mut target.base,
+                "width" => &mut target.width,
+                _ => bail!("Unknown setting {} in {} at line {}", key, name, i + 1),
+            };
+            match parse_number(value) {
+                Some(n) => *field = n,
+                None => bail!("Invalid number {} in {} at line {}", value, name, i + 1),
+            }
+        }
+        if target.memory == 0 || !target.memory.is_multiple_of(4) {
+            bail!("Memory size {:#x} is not a multiple of 4", target.memory);
+        }
+        Ok(target)
+    }
+
+    /// Reads the target configuration in a file.
+    pub fn load(path: &Path) -> Result<Target, Diagnostic> {
+        match fs::read_to_string(path) {
+            Ok(text) => Target::parse(&path.display().to_string(), &text),
+            Err(_) => bail!("Cannot read {}", path.display()),
+        }
+    }
+}
@@ -1,29 +1,152 @@
-fn strtol(s: String) -> (String, Option<i64>) {
-    let mut num = 0;
+use crate::diag::{bail, Diagnostic, Location};
+use crate::types::{Type, TypeKind};
+use std::fmt;
+
+fn strtol(s: String, base: u32) -> Result<(String, Option<i64>), Diagnostic> {
+    let mut num: i64 = 0;
     let chars = s.chars();
     let mut has_digits = false;
     let mut index = s.len();
 
     for (i, c) in chars.enumerate() {
-        if c.is_ascii_digit() {
+        if let Some(d) = c.to_digit(base) {
             has_digits = true;
-            num = num * 10 + (c as i64 - '0' as i64);
+            num = num * base as i64 + d as i64;
+            if num > u32::MAX as i64 {
+                bail!("Integer literal is too large");
+            }
         } else {
             index = i;
             break;
         }
     }
     if !has_digits {
-        return (s, None);
+        return Ok((s, None));
     }
     let (_, remainder) = s.split_at(index);
-    (remainder.to_string(), Some(num))
+    Ok((remainder.to_string(), Some(num)))
+}
+
+// Reads an integer literal with an optional 0x, 0b or 0 prefix and u/l suffixes,
+// and gives it the first type that can represent its value.
+fn read_int_literal(s: String) -> Result<(String, i64, Type), Diagnostic> {
+    let lower = s.to_ascii_lowercase();
+    let (r, base) = if lower.starts_with("0x") {
+        (s[2..].to_string(), 16)
+    } else if lower.starts_with("0b") {
+        (s[2..].to_string(), 2)
+    } else if lower.starts_with('0') {
+        (s.clone(), 8)
+    } else {
+        (s.clone(), 10)
+    };
+
+    let (mut r, num) = strtol(r, base)?;
+    let val = match num {
+        Some(val) => val,
+        None => bail!("Invalid integer literal {}", s),
+    };
+
+    let mut is_unsigned = false;
+    let mut is_long = false;
+    while let Some(c) = r.chars().next() {
+        if !is_unsigned && matches!(c, 'u' | 'U') {
+            is_unsigned = true;
+        } else if !is_long && matches!(c, 'l' | 'L') {
+            is_long = true;
+        } else {
+            break;
+        }
+        r = r.split_off(c.len_utf8());
+    }
+    if let Some(c) = r.chars().next() {
+        if c.is_ascii_digit() {
+            bail!("Invalid digit {} in integer literal", c);
+        }
+        if is_ident2(c) {
+            bail!("Invalid suffix {} on integer literal", c);
+        }
+    }
+
+    let fits_int = val <= i32::MAX as i64;
+    // Unsuffixed decimal literals that do not fit in int become unsigned long,
+    // since long has no more range than int on KTC32.
+    let ty = if is_long {
+        Type::new(TypeKind::Long, is_unsigned || !fits_int)
+    } else if is_unsigned || !fits_int {
+        Type::new(
+            if base == 10 && !is_unsigned {
+                TypeKind::Long
+            } else {
+                TypeKind::Int
+            },
+            true,
+        )
+    } else {
+        Type::int()
+    };
+    Ok((r, val, ty))
+}
+
+const KEYWORDS: [&str; 44] = [
+    "auto",
+    "break",
+    "case",
+    "char",
+    "const",
+    "continue",
+    "default",
+    "do",
+    "double",
+    "else",
+    "enum",
+    "extern",
+    "float",
+    "for",
+    "goto",
+    "if",
+    "inline",
+    "int",
+    "long",
+    "register",
+    "restrict",
+    "return",
+    "short",
+    "signed",
+    "sizeof",
+    "static",
+    "struct",
+    "switch",
+    "typedef",
+    "union",
+    "unsigned",
+    "void",
+    "volatile",
+    "while",
+    "_Alignas",
+    "_Alignof",
+    "_Atomic",
+    "_Bool",
+    "_Complex",
+    "_Generic",
+    "_Imaginary",
+    "_Noreturn",
+    "_Static_assert",
+    "_Thread_local",
+];
+
+fn is_ident1(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_ident2(c: char) -> bool {
+    is_ident1(c) || c.is_ascii_digit()
 }
 
 fn extract_name(s: String) -> (String, String) {
     let mut index = 0;
     for (i, c) in s.chars().enumerate() {
-        if c.is_ascii_alphabetic() {
+        if (i == 0 && is_ident1(c)) || (i > 0 && is_ident2(c)) {
             index = i + 1;
         } else {
             break;
@@ -34,119 +157,358 @@ fn extract_name(s: String) -> (String, String) {
     (remainder, name)
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum TokenKind {
     Reserved,
+    Keyword,
     Ident,
     Num,
+    Str,
     Eof,
 }
 
-#[derive(Debug)]
+/// A token of preprocessed text. Numbers carry their value and type, and
+/// string literals their contents.
+#[derive(Debug, Clone)]
 pub struct Token {
     pub kind: TokenKind,
     pub val: i64,
     pub str: String,
+    pub ty: Option<Type>,
+    /// String literal contents with escapes resolved, excluding the terminating NUL.
+    pub contents: Vec<u8>,
+    /// Where the token was written, before preprocessing.
+    pub location: Location,
 }
 
-pub fn tokenize(mut p: String) -> Vec<Token> {
+// The kind and source text, as printed by --emit tokens.
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.str.is_empty() {
+            write!(f, "{:?}", self.kind)
+        } else {
+            write!(f, "{:?} {}", self.kind, self.str)
+        }
+    }
+}
+
+// Reads one possibly escaped character of a string or character literal.
+pub(crate) fn read_char(p: &[u8], i: &mut usize) -> Result<u8, Diagnostic> {
+    if p[*i] != b'\\' {
+        *i += 1;
+        return Ok(p[*i - 1]);
+    }
+    *i += 1;
+    let Some(&c) = p.get(*i) else {
+        bail!("Unterminated literal");
+    };
+    *i += 1;
+    let c = match c {
+        b'0'..=b'7' => {
+            let mut val = (c - b'0') as u32;
+            for _ in 0..2 {
+                match p.get(*i) {
+                    Some(&d @ b'0'..=b'7') => {
+                        val = val * 8 + (d - b'0') as u32;
+                        *i += 1;
+                    }
+                    _ => break,
+                }
+            }
+            if val > 0xff {
+                bail!("Octal escape sequence out of range");
+            }
+            val as u8
+        }
+        b'x' => {
+            let start = *i;
+            let mut val = 0u32;
+            while let Some(d) = p.get(*i).and_then(|&d| (d as char).to_digit(16)) {
+                val = val * 16 + d;
+                if val > 0xff {
+                    bail!("Hex escape sequence out of range");
+                }
+                *i += 1;
+            }
+            if *i == start {
+                bail!("Invalid hex escape sequence");
+            }
+            val as u8
+        }
+        b'a' => 7,
+        b'b' => 8,
+        b't' => b'\t',
+        b'n' => b'\n',
+        b'v' => 11,
+        b'f' => 12,
+        b'r' => b'\r',
+        b'e' => 27,
+        c => c,
+    };
+    Ok(c)
+}
+
+// Reads a `# LINE "FILE"` line marker, which says where the next line comes
+// from.
+fn read_line_marker(marker: &str) -> Result<Location, Diagnostic> {
+    let marker = marker[1..].trim();
+    let Some((line, file)) = marker.split_once(' ') else {
+        bail!("Invalid line marker");
+    };
+    let (Ok(line), Some(file)) = (
+        line.parse(),
+        file.strip_prefix('"')
+            .and_then(|file| file.strip_suffix('"')),
+    ) else {
+        bail!("Invalid line marker");
+    };
+    let bytes = file.as_bytes();
+    let mut name = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        name.push(read_char(bytes, &mut i)?);
+    }
+    Ok(Location {
+        file: String::from_utf8_lossy(&name).into_owned(),
+        line,
+    })
+}
+
+/// Splits preprocessed text into tokens, ending with an `Eof` token. Lines
+/// are counted from the `# LINE "FILE"` markers the preprocessor writes, and
+/// from line 1 of `<input>` before the first one.
+pub fn tokenize(p: String) -> Result<Vec<Token>, Diagnostic> {
+    let mut location = Location {
+        file: "<input>".to_string(),
+        line: 1,
+    };
+    read_tokens(p, &mut location).map_err(|err| err.at(&location))
+}
+
+fn read_tokens(mut p: String, loc: &mut Location) -> Result<Vec<Token>, Diagnostic> {
     let mut token_list: Vec<Token> = Vec::new();
+    let mut bol = true;
     while let Some(c) = p.chars().next() {
         if c.is_whitespace() {
-            p = p.split_off(1);
+            if c == '\n' {
+                loc.line += 1;
+                bol = true;
+            }
+            p = p.split_off(c.len_utf8());
             continue;
         }
 
-        if c == '+' || c == '-' || c == '*' || c == '/' || c == '(' || c == ')' || c == ';' {
-            p = p.split_off(1);
+        if c == '#' && bol {
+            let end = p.find('\n').map_or(p.len(), |end| end + 1);
+            *loc = read_line_marker(&p[..end])?;
+            p = p.split_off(end);
+            continue;
+        }
+        bol = false;
+
+        if p.starts_with("...") {
+            p = p.split_off(3);
+            token_list.push(Token {
+                kind: TokenKind::Reserved,
+                val: 0,
+                str: "...".to_string(),
+                ty: None,
+                contents: Vec::new(),
+                location: loc.clone(),
+            });
+            continue;
+        }
+
+        if c == '"' {
+            let bytes = p.as_bytes();
+            let mut i = 1;
+            let mut contents = Vec::new();
+            loop {
+                match bytes.get(i) {
+                    None | Some(b'\n') => bail!("Unterminated string literal"),
+                    Some(b'"') => break,
+                    _ => contents.push(read_char(bytes, &mut i)?),
+                }
+            }
+            let str = p[..i + 1].to_string();
+            p = p.split_off(i + 1);
+            token_list.push(Token {
+                kind: TokenKind::Str,
+                val: 0,
+                str,
+                ty: Some(Type::array_of(
+                    Type::new(TypeKind::Char, false),
+                    contents.len() as i64 + 1,
+                )),
+                contents,
+                location: loc.clone(),
+            });
+            continue;
+        }
+
+        // Character literals are ints holding the value of a sign-extended char.
+        if c == '\'' {
+            let bytes = p.as_bytes();
+            let mut i = 1;
+            if matches!(bytes.get(i), None | Some(b'\'') | Some(b'\n')) {
+                bail!("Empty character literal");
+            }
+            let val = read_char(bytes, &mut i)? as i8 as i64;
+            if bytes.get(i) != Some(&b'\'') {
+                bail!("Unterminated character literal");
+            }
+            let str = p[..i + 1].to_string();
+            p = p.split_off(i + 1);
+            token_list.push(Token {
+                kind: TokenKind::Num,
+                val,
+                str,
+                ty: Some(Type::int()),
+                contents: Vec::new(),
+                location: loc.clone(),
+            });
+            continue;
+        }
+
+        if p.starts_with("->") {
+            p = p.split_off(2);
+            token_list.push(Token {
+                kind: TokenKind::Reserved,
+                val: 0,
+                str: "->".to_string(),
+                ty: None,
+                contents: Vec::new(),
+                location: loc.clone(),
+            });
+            continue;
+        }
+
+        if c == '+'
+            || c == '-'
+            || c == '*'
+            || c == '/'
+            || c == '%'
+            || c == '('
+            || c == ')'
+            || c == '{'
+            || c == '}'
+            || c == ';'
+            || c == ','
+            || c == '['
+            || c == ']'
+            || c == '.'
+            || c == '&'
+        {
+            p = p.split_off(c.len_utf8());
             token_list.push(Token {
                 kind: TokenKind::Reserved,
                 val: 0,
                 str: c.to_string(),
+                ty: None,
+                contents: Vec::new(),
+                location: loc.clone(),
             });
             continue;
         }
 
         if c == '=' || c == '!' {
-            p = p.split_off(1);
-            let h = p.chars().next().unwrap();
+            p = p.split_off(c.len_utf8());
+            let h = p.chars().next().unwrap_or_default();
             if h == '=' {
                 token_list.push(Token {
                     kind: TokenKind::Reserved,
                     val: 0,
                     str: c.to_string() + &h.to_string(),
+                    ty: None,
+                    contents: Vec::new(),
+                    location: loc.clone(),
                 });
-                p = p.split_off(1);
+                p = p.split_off(h.len_utf8());
                 continue;
             } else {
                 token_list.push(Token {
                     kind: TokenKind::Reserved,
                     val: 0,
                     str: c.to_string(),
+                    ty: None,
+                    contents: Vec::new(),
+                    location: loc.clone(),
                 });
                 continue;
             }
         }
 
         if c == '>' || c == '<' {
-            p = p.split_off(1);
-            let h = p.chars().peekable().peek().cloned().unwrap();
-            if h == '=' {
+            p = p.split_off(c.len_utf8());
+            let h = p.chars().next().unwrap_or_default();
+            if h == '=' || h == c {
                 token_list.push(Token {
                     kind: TokenKind::Reserved,
                     val: 0,
                     str: c.to_string() + &h.to_string(),
+                    ty: None,
+                    contents: Vec::new(),
+                    location: loc.clone(),
                 });
-                p = p.split_off(1);
+                p = p.split_off(h.len_utf8());
                 continue;
             } else {
                 token_list.push(Token {
                     kind: TokenKind::Reserved,
                     val: 0,
                     str: c.to_string(),
+                    ty: None,
+                    contents: Vec::new(),
+                    location: loc.clone(),
                 });
                 continue;
             }
         }
 
         if c.is_ascii_digit() {
-            let (r, num) = strtol(p);
+            let len = p.len();
+            let (r, val, ty) = read_int_literal(p.clone())?;
+            let str = p[..len - r.len()].to_string();
             p = r;
             token_list.push(Token {
                 kind: TokenKind::Num,
-                val: num.unwrap(),
-                str: c.to_string(),
+                val,
+                str,
+                ty: Some(ty),
+                contents: Vec::new(),
+                location: loc.clone(),
             });
             continue;
         }
 
-        if c.is_ascii_alphabetic() {
+        if is_ident1(c) {
             let (r, name) = extract_name(p);
             p = r;
-            match name.as_str() {
-                "return" | "if" | "else" | "while" | "for" => token_list.push(Token {
-                    kind: TokenKind::Reserved,
-                    val: 0,
-                    str: name,
-                }),
-
-                _ => {
-                    token_list.push(Token {
-                        kind: TokenKind::Ident,
-                        val: 0,
-                        str: name,
-                    });
-                }
-            }
+            let kind = if KEYWORDS.contains(&name.as_str()) {
+                TokenKind::Keyword
+            } else {
+                TokenKind::Ident
+            };
+            token_list.push(Token {
+                kind,
+                val: 0,
+                str: name,
+                ty: None,
+                contents: Vec::new(),
+                location: loc.clone(),
+            });
             continue;
         }
-        panic!("could not tokenize {}", c);
+        bail!("Could not tokenize {}", c);
     }
 
     token_list.push(Token {
         kind: TokenKind::Eof,
         val: 0,
         str: "".to_string(),
+        ty: None,
+        contents: Vec::new(),
+        location: loc.clone(),
     });
-    token_list
+    Ok(token_list)
 }
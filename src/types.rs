@@ -0,0 +1,213 @@
+#[derive(Debug, PartialEq, Clone)]
+pub enum TypeKind {
+    Void,
+    Char,
+    Short,
+    Int,
+    Long,
+    /// The address of the next variadic argument.
+    VaList,
+    Ptr,
+    Array,
+    Struct,
+    Func,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Member {
+    pub name: String,
+    pub ty: Type,
+    pub offset: i64,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Type {
+    pub kind: TypeKind,
+    pub size: i64,
+    pub align: i64,
+    pub is_unsigned: bool,
+    pub is_const: bool,
+    pub is_volatile: bool,
+    /// Pointer or array
+    pub base: Option<Box<Type>>,
+    pub array_len: i64,
+    /// Struct
+    pub tag: String,
+    pub members: Vec<Member>,
+    /// Function type
+    pub return_ty: Option<Box<Type>>,
+    pub params: Vec<Type>,
+    pub param_names: Vec<String>,
+    pub is_variadic: bool,
+}
+
+impl Type {
+    pub fn new(kind: TypeKind, is_unsigned: bool) -> Self {
+        let size = match kind {
+            TypeKind::Void | TypeKind::Char | TypeKind::Func => 1,
+            TypeKind::Short => 2,
+            TypeKind::Int | TypeKind::Long | TypeKind::VaList | TypeKind::Ptr => 4,
+            TypeKind::Array | TypeKind::Struct => 0,
+        };
+        Self {
+            kind,
+            size,
+            align: size.max(1),
+            is_unsigned,
+            is_const: false,
+            is_volatile: false,
+            base: None,
+            array_len: 0,
+            tag: String::new(),
+            members: Vec::new(),
+            return_ty: None,
+            params: Vec::new(),
+            param_names: Vec::new(),
+            is_variadic: false,
+        }
+    }
+
+    pub fn int() -> Self {
+        Type::new(TypeKind::Int, false)
+    }
+
+    pub fn pointer_to(base: Type) -> Self {
+        let mut ty = Type::new(TypeKind::Ptr, true);
+        ty.base = Some(Box::new(base));
+        ty
+    }
+
+    pub fn array_of(base: Type, len: i64) -> Self {
+        let mut ty = Type::new(TypeKind::Array, false);
+        ty.size = base.size * len;
+        ty.align = base.align;
+        // An array is qualified like its elements.
+        ty.is_const = base.is_const;
+        ty.is_volatile = base.is_volatile;
+        ty.base = Some(Box::new(base));
+        ty.array_len = len;
+        ty
+    }
+
+    pub fn func(return_ty: Type, params: Vec<Type>, is_variadic: bool) -> Self {
+        let mut ty = Type::new(TypeKind::Func, false);
+        ty.return_ty = Some(Box::new(return_ty));
+        ty.params = params;
+        ty.is_variadic = is_variadic;
+        ty
+    }
+
+    pub fn is_integer(&self) -> bool {
+        matches!(
+            self.kind,
+            TypeKind::Char | TypeKind::Short | TypeKind::Int | TypeKind::Long | TypeKind::VaList
+        )
+    }
+
+    /// Pointers, and arrays and functions which decay to pointers when used as values.
+    pub fn is_pointer(&self) -> bool {
+        matches!(self.kind, TypeKind::Ptr | TypeKind::Array | TypeKind::Func)
+    }
+
+    /// Values of these types are addresses, so they are never loaded from memory.
+    pub fn is_aggregate(&self) -> bool {
+        matches!(
+            self.kind,
+            TypeKind::Array | TypeKind::Struct | TypeKind::Func
+        )
+    }
+
+    pub fn unqualified(&self) -> Self {
+        let mut ty = self.clone();
+        ty.is_const = false;
+        ty.is_volatile = false;
+        ty
+    }
+
+    /// The type a pointer-like value points to, with functions pointing to themselves.
+    pub fn pointee(&self) -> Option<Type> {
+        match self.kind {
+            TypeKind::Ptr | TypeKind::Array => Some(*self.base.clone().unwrap()),
+            TypeKind::Func => Some(self.clone()),
+            _ => None,
+        }
+    }
+
+    fn rank(&self) -> i64 {
+        match self.kind {
+            TypeKind::Void | TypeKind::Func | TypeKind::Array | TypeKind::Struct => 0,
+            TypeKind::Char => 1,
+            TypeKind::Short => 2,
+            TypeKind::Int => 3,
+            TypeKind::Long | TypeKind::VaList | TypeKind::Ptr => 4,
+        }
+    }
+}
+
+// Whether two types name the same type, ignoring top-level qualifiers.
+pub(crate) fn is_compatible(lhs: &Type, rhs: &Type) -> bool {
+    if lhs.kind != rhs.kind {
+        return false;
+    }
+    match lhs.kind {
+        TypeKind::Ptr => {
+            let lhs = lhs.base.as_ref().unwrap();
+            let rhs = rhs.base.as_ref().unwrap();
+            is_compatible(lhs, rhs) && lhs.is_const == rhs.is_const
+        }
+        TypeKind::Array => {
+            is_compatible(lhs.base.as_ref().unwrap(), rhs.base.as_ref().unwrap())
+                && (lhs.array_len == rhs.array_len || lhs.array_len == 0 || rhs.array_len == 0)
+        }
+        TypeKind::Struct => lhs.tag == rhs.tag,
+        TypeKind::Func => {
+            is_compatible(
+                lhs.return_ty.as_ref().unwrap(),
+                rhs.return_ty.as_ref().unwrap(),
+            ) && lhs.is_variadic == rhs.is_variadic
+                && lhs.params.len() == rhs.params.len()
+                && lhs
+                    .params
+                    .iter()
+                    .zip(rhs.params.iter())
+                    .all(|(l, r)| is_compatible(l, r))
+        }
+        _ => lhs.is_unsigned == rhs.is_unsigned,
+    }
+}
+
+// Sign- or zero-extends the low bytes of a value, like a load of `ty`.
+pub(crate) fn truncate(val: u32, ty: &Type) -> u32 {
+    match (ty.size, ty.is_unsigned) {
+        (1, false) => val as i8 as u32,
+        (1, true) => val as u8 as u32,
+        (2, false) => val as i16 as u32,
+        (2, true) => val as u16 as u32,
+        _ => val,
+    }
+}
+
+// char and short are promoted to int, which can represent all of their values.
+pub(crate) fn promote(ty: &Type) -> Type {
+    if ty.is_pointer() {
+        return Type::new(TypeKind::Long, true);
+    }
+    if ty.size < 4 {
+        return Type::int();
+    }
+    Type::new(ty.kind.clone(), ty.is_unsigned)
+}
+
+// Usual arithmetic conversions. long and int have the same size on KTC32,
+// so mixing a signed long with an unsigned int yields an unsigned long.
+// Addresses compare as unsigned.
+pub(crate) fn common_type(lhs: &Type, rhs: &Type) -> Type {
+    let lhs = promote(lhs);
+    let rhs = promote(rhs);
+    let kind = if lhs.rank() >= rhs.rank() {
+        lhs.kind
+    } else {
+        rhs.kind
+    };
+    Type::new(kind, lhs.is_unsigned || rhs.is_unsigned)
+}
@@ -0,0 +1,151 @@
+// Uses the compiler as a library, the way other tools embed it.
+
+use ktc32_com::asm::{assemble, label_address};
+use ktc32_com::ast::{
+    fold_expr_children, functions, globals, walk_expr, Decl, Expr, ExprKind, Folder, Visitor,
+};
+use ktc32_com::interp::interpret;
+use ktc32_com::link::{link, Object, HALT};
+use ktc32_com::sim::Fault;
+use ktc32_com::target::Target;
+use ktc32_com::token::TokenKind;
+use ktc32_com::{compile, generate, sim, Options};
+
+#[test]
+fn every_stage_is_returned() {
+    let options = Options {
+        macros: vec![(true, "N=3".to_string())],
+        ..Options::default()
+    };
+    let output = compile("int g; int main() { return N; }", &options).unwrap();
+    assert_eq!(
+        output.preprocessed.trim(),
+        "# 1 \"<input>\"\nint g; int main() { return 3; }"
+    );
+    assert_eq!(output.tokens.len(), 13);
+    assert_eq!(output.tokens.last().unwrap().kind, TokenKind::Eof);
+    assert_eq!(globals(&output.decls).next().unwrap().label, "g");
+    assert_eq!(functions(&output.decls).next().unwrap().name, "main");
+    assert!(output.asm.contains(".global main"));
+    assert!(output.warnings.is_empty());
+}
+
+#[test]
+fn errors_are_returned_as_diagnostics() {
+    let err = compile("int main() { int x; int x; }", &Options::default()).unwrap_err();
+    assert_eq!(err.errors.len(), 1);
+    assert!(err.to_string().contains("x"), "{}", err);
+
+    let source = "#define ONE 1\nint main() {\n  return ONE +\n  ;\n}\n";
+    let err = compile(source, &Options::default()).unwrap_err();
+    let location = err.errors[0].location.as_ref().unwrap();
+    assert_eq!((location.file.as_str(), location.line), ("<input>", 4));
+    let err = compile("#if 1\nint x;\n", &Options::default()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "<input>:2: Unterminated conditional directive"
+    );
+
+    let output = compile("int f() {} int main() { return f(); }", &Options::default());
+    assert_eq!(output.unwrap().warnings.len(), 1);
+}
+
+#[test]
+fn compiled_output_links_and_runs() {
+    let output = compile("int main() { return 6 * 7; }", &Options::default()).unwrap();
+    let object = Object::from_asm("main", &output.asm).unwrap();
+    assert_eq!(run_objects(vec![object]), Ok(42));
+}
+
+// Links objects in the default target and runs them until main returns.
+fn run_objects(objects: Vec<Object>) -> Result<i32, Fault> {
+    let target = Target::default();
+    let image = link(objects, target.memory).unwrap();
+    let bytes = assemble(&image, target.base).unwrap();
+    let halt = label_address(&image, target.base, HALT).unwrap();
+    sim::run(&bytes, target.base, target.memory, halt)
+}
+
+// Links the units in order and runs the result.
+fn link_and_run(units: &[(&str, &str)]) -> i32 {
+    let objects = units
+        .iter()
+        .map(|(name, source)| {
+            let output = compile(source, &Options::default()).unwrap();
+            Object::from_asm(name, &output.asm).unwrap()
+        })
+        .collect();
+    run_objects(objects).unwrap()
+}
+
+#[test]
+fn clashing_static_symbols_are_renamed_apart() {
+    // The static local h in f is labelled h.1, which a renamed h must not take.
+    let first = "int g; int f() { static int h = 5; return h; } \
+                 static int h() { return 1; } int a() { return h(); }";
+    let second = "static int h() { return 2; } int f(); int a(); \
+                  int main() { return f() * 100 + a() * 10 + h(); }";
+    assert_eq!(link_and_run(&[("q1", first), ("q2", second)]), 512);
+    assert_eq!(link_and_run(&[("q2", second), ("q1", first)]), 512);
+}
+
+// Counts the calls in a program.
+struct Calls(usize);
+
+impl Visitor for Calls {
+    fn visit_expr(&mut self, expr: &Expr) {
+        if let ExprKind::Call { .. } = expr.kind {
+            self.0 += 1;
+        }
+        walk_expr(self, expr);
+    }
+}
+
+// Doubles every constant.
+struct Double;
+
+impl Folder for Double {
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        match expr.kind {
+            ExprKind::Num(val) => Expr::new(ExprKind::Num(val * 2), expr.ty),
+            _ => fold_expr_children(self, expr),
+        }
+    }
+}
+
+#[test]
+fn passes_walk_and_rewrite_the_tree() {
+    let source = "int f(int x) { return x + 1; } int main() { return f(f(19)); }";
+    let output = compile(source, &Options::default()).unwrap();
+
+    let mut calls = Calls(0);
+    for decl in &output.decls {
+        calls.visit_decl(decl);
+    }
+    assert_eq!(calls.0, 2);
+
+    let decls: Vec<Decl> = output
+        .decls
+        .into_iter()
+        .map(|decl| Double.fold_decl(decl))
+        .collect();
+    assert_eq!(interpret(&decls), Ok(42));
+
+    let (_, asm) = generate(&decls).unwrap();
+    let object = Object::from_asm("main", &asm).unwrap();
+    assert_eq!(run_objects(vec![object]), Ok(42));
+}
+
+#[test]
+fn scalar_locals_stay_out_of_the_frame() {
+    let source = "int sum(int n) { int s = 0; int i; \
+                  for (i = 0; i < n; i = i + 1) s = s + i; return s; }\n\
+                  int main() { int x = 2; int *p = &x; return sum(*p); }";
+    let output = compile(source, &Options::default()).unwrap();
+    let (ir, _) = generate(&output.decls).unwrap();
+    let ir = ir.to_string();
+    let (sum, main) = ir.split_at(ir.find("function main").unwrap());
+    assert!(!sum.contains("= frame") && !sum.contains("load"), "{}", sum);
+    // x has its address taken, so it is kept in the frame.
+    assert!(main.contains("= frame"), "{}", main);
+}
@@ -0,0 +1,94 @@
+// Checks the encoding of every instruction format and directive against
+// bytes worked out by hand from the layout in src/asm.rs.
+
+use ktc32_com::asm::assemble;
+
+fn bytes(text: &str, base: u32) -> Vec<u8> {
+    assemble(text, base).unwrap()
+}
+
+fn error(text: &str) -> String {
+    assemble(text, 0).unwrap_err().to_string()
+}
+
+#[test]
+fn register_instructions_take_two_bytes() {
+    assert_eq!(bytes("add a0, a1", 0), [0x02, 0x4a]);
+    assert_eq!(bytes("mov s3, zero", 0), [0xc1, 0x03]);
+}
+
+#[test]
+fn immediates_are_encoded_in_the_upper_half() {
+    assert_eq!(bytes("addi sp, sp, -8", 0), [0xa0, 0x10, 0xf8, 0xff]);
+    assert_eq!(bytes("lw ra, fp, 4", 0), [0x68, 0x18, 0x04, 0x00]);
+    assert_eq!(bytes("ori t0, t1, 0xffff", 0), [0x62, 0x31, 0xff, 0xff]);
+    assert_eq!(bytes("srai a2, a3, 31", 0), [0xa6, 0x5a, 0x1f, 0x00]);
+    assert_eq!(bytes("lui s0, 0x1234", 0), [0x27, 0x03, 0x34, 0x12]);
+}
+
+#[test]
+fn branches_and_jumps_are_relative_to_the_next_instruction() {
+    let text = "start:\nbeq a0, zero, end\nblt a0, a1, start\nend:";
+    assert_eq!(
+        bytes(text, 0),
+        [0x30, 0x02, 0x04, 0x00, 0x32, 0x4a, 0xf8, 0xff]
+    );
+    // jal keeps the top of its offset in the rs field.
+    assert_eq!(bytes("jal ra, 0x12344", 0), [0x73, 0x08, 0x44, 0x23]);
+    assert_eq!(bytes("jal zero, -4", 0), [0x33, 0xf8, 0xfc, 0xff]);
+}
+
+#[test]
+fn la_expands_to_lui_and_addi() {
+    // addi sign-extends 0x8000, so lui loads one more than the top half.
+    assert_eq!(
+        bytes("la t0, 0x12348000", 0),
+        [0x67, 0x01, 0x35, 0x12, 0x60, 0x29, 0x00, 0x80]
+    );
+    assert_eq!(
+        bytes("la a0, value\nvalue:\n.word 7", 0x100),
+        [0x27, 0x02, 0x00, 0x00, 0x20, 0x42, 0x08, 0x01, 0x07, 0x00, 0x00, 0x00]
+    );
+}
+
+#[test]
+fn data_directives_are_laid_out_in_order() {
+    let text = "\
+.byte 1, -1, 2
+.align 4
+.half 0x1234
+.align 4
+.word value+4, -2
+.zero 3
+.byte 0xff
+value:";
+    assert_eq!(
+        bytes(text, 0),
+        [
+            0x01, 0xff, 0x02, 0x00, 0x34, 0x12, 0x00, 0x00, 0x18, 0x00, 0x00, 0x00, 0xfe, 0xff,
+            0xff, 0xff, 0x00, 0x00, 0x00, 0xff
+        ]
+    );
+}
+
+#[test]
+fn offsets_out_of_range_are_rejected() {
+    assert!(assemble("beq a0, a1, -32768", 0).is_ok());
+    assert_eq!(
+        error("beq a0, a1, 32768"),
+        "Branch to 32768 is out of range at line 1"
+    );
+    assert_eq!(
+        error("beq zero, zero, far\n.zero 32768\nfar:"),
+        "Branch to far is out of range at line 1"
+    );
+    assert!(assemble("jal ra, -1048576", 0).is_ok());
+    assert_eq!(
+        error("jal ra, 1048576"),
+        "Branch to 1048576 is out of range at line 1"
+    );
+    assert_eq!(
+        error("addi a0, a0, 0x8000"),
+        "Immediate 32768 of addi is out of range at line 1"
+    );
+}
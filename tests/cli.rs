@@ -0,0 +1,104 @@
+// Checks the command line: reading stdin, --emit, --target-config and the
+// exit status of each kind of failure.
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+fn ktc32_com(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ktc32-com"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .env("RUST_BACKTRACE", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+#[test]
+fn version_is_printed() {
+    let output = ktc32_com(&["--version"], "");
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        format!("ktc32-com {}", env!("CARGO_PKG_VERSION"))
+    );
+}
+
+#[test]
+fn source_is_read_from_stdin() {
+    let source = "int main() { return X * 2; }";
+    let output = ktc32_com(&["run", "-D", "X=21", "-"], source);
+    assert_eq!(stdout(&output), "42");
+    let output = ktc32_com(&["interp", "-DX=4", "-"], source);
+    assert_eq!(stdout(&output), "8");
+}
+
+#[test]
+fn tokens_are_emitted() {
+    let output = ktc32_com(&["--emit", "tokens", "-"], "return 0x10;");
+    assert_eq!(stdout(&output), "Keyword return\nNum 0x10\nReserved ;\nEof");
+}
+
+#[test]
+fn ir_is_emitted() {
+    let output = ktc32_com(&["--emit", "ir", "-"], "int main() { return 2 + 3; }");
+    assert_eq!(
+        stdout(&output),
+        "function main() frame 0\nbb0:\n  %0 = imm 2\n  %1 = imm 3\n  %2 = add %0, %1\n  ret %2"
+    );
+}
+
+#[test]
+fn target_config_sets_memory_and_base() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let config = dir.join("board.cfg");
+    fs::write(&config, "# 32 KiB\nmemory = 0x8000\nbase = 0x100\n").unwrap();
+    let config = config.to_str().unwrap();
+    let output = ktc32_com(&["run", "--target-config", config, "tests/test14.ktc"], "");
+    assert_eq!(stdout(&output), "175");
+
+    let source = "int main() { return 0; }";
+    let output = ktc32_com(
+        &["--target-config", config, "--format", "ihex", "-"],
+        source,
+    );
+    assert!(stdout(&output).starts_with(":10010000"));
+}
+
+#[test]
+fn unimplemented_optimization_levels_are_warned_about() {
+    let source = "int main() { return 7; }";
+    let output = ktc32_com(&["run", "-O2", "-"], source);
+    assert_eq!(stdout(&output), "7");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("warning: -O2 has no effect"), "{}", stderr);
+    assert!(ktc32_com(&["run", "-O0", "-"], source).stderr.is_empty());
+}
+
+#[test]
+fn failures_have_distinct_exit_statuses() {
+    let status = |args: &[&str], stdin| ktc32_com(args, stdin).status.code();
+    assert_eq!(status(&["-"], "int main() { return 0; }"), Some(0));
+    assert_eq!(status(&["-"], "int main() { return 1 +; }"), Some(1));
+    assert_eq!(status(&["-O", "9", "-"], ""), Some(2));
+    assert_eq!(status(&["-S", "a.ktc", "b.ktc"], ""), Some(2));
+    assert_eq!(
+        status(&["run", "-"], "int main() { int a[1]; return a[40000]; }"),
+        Some(3)
+    );
+}
@@ -0,0 +1,58 @@
+// Short runs of `ktc32-com fuzz` with fixed seeds, so that the compiler and
+// the interpreter keep agreeing and the front end keeps rejecting bad input
+// with a diagnostic.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+fn fuzz(args: &[&str]) {
+    let output = Command::new(env!("CARGO_BIN_EXE_ktc32-com"))
+        .arg("fuzz")
+        .args(args)
+        .env("RUST_BACKTRACE", "0")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn compiled_programs_agree_with_interpreter() {
+    fuzz(&["--seed", "1", "--iterations", "30"]);
+}
+
+#[test]
+fn front_end_reports_errors_without_crashing() {
+    fuzz(&["--front-end", "--seed", "1", "--iterations", "2000"]);
+}
+
+// The front end once split its input in the middle of characters that take
+// more than one byte, such as a no-break space.
+#[test]
+fn non_ascii_whitespace_is_skipped() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ktc32-com"))
+        .args(["run", "-"])
+        .env("RUST_BACKTRACE", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let source = "int\u{a0}main() {\u{3000}return 3;\u{2028}}\n";
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(source.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        "3",
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
@@ -0,0 +1,76 @@
+// Checks the image formats against output worked out by hand.
+
+use ktc32_com::image::{format_image, Format};
+
+fn text(bytes: &[u8], format: Format, width: u32, base: u32) -> String {
+    String::from_utf8(format_image(bytes, format, width, base).unwrap()).unwrap()
+}
+
+fn error(bytes: &[u8], format: Format, width: u32, base: u32) -> String {
+    format_image(bytes, format, width, base)
+        .unwrap_err()
+        .to_string()
+}
+
+#[test]
+fn binaries_are_the_bytes_themselves() {
+    let bytes = [0x01, 0x02, 0x03];
+    assert_eq!(format_image(&bytes, Format::Bin, 32, 0x100).unwrap(), bytes);
+}
+
+#[test]
+fn ihex_records_carry_checksums() {
+    assert_eq!(
+        text(&[0x01, 0x02, 0x03], Format::IHex, 32, 0x100),
+        ":03010000010203F6\n:00000001FF\n"
+    );
+}
+
+#[test]
+fn ihex_records_switch_segments_at_64_kib() {
+    let bytes: Vec<u8> = (0..20).collect();
+    assert_eq!(
+        text(&bytes, Format::IHex, 32, 0xfff8),
+        ":08FFF8000001020304050607E5\n\
+         :020000040001F9\n\
+         :0C00000008090A0B0C0D0E0F1011121352\n\
+         :00000001FF\n"
+    );
+    assert_eq!(
+        text(&[0xaa], Format::IHex, 32, 0x20000),
+        ":020000040002F8\n:01000000AA55\n:00000001FF\n"
+    );
+    assert_eq!(
+        error(&[0, 0], Format::IHex, 32, 0xffffffff),
+        "Image does not fit below 4 GiB at base 0xffffffff"
+    );
+}
+
+#[test]
+fn readmemh_words_are_little_endian() {
+    let bytes = [0x01, 0x02, 0x03, 0x04, 0x05];
+    assert_eq!(text(&bytes, Format::ReadMemH, 8, 0), "01\n02\n03\n04\n05\n");
+    assert_eq!(text(&bytes, Format::ReadMemH, 16, 0), "0201\n0403\n0005\n");
+    assert_eq!(
+        text(&bytes, Format::ReadMemH, 32, 0),
+        "04030201\n00000005\n"
+    );
+}
+
+#[test]
+fn readmemh_addresses_count_words() {
+    let bytes = [0x01, 0x02, 0x03, 0x04];
+    assert_eq!(text(&bytes, Format::ReadMemH, 32, 0x100), "@40\n04030201\n");
+    assert_eq!(
+        text(&bytes, Format::ReadMemH, 16, 0x100),
+        "@80\n0201\n0403\n"
+    );
+    assert_eq!(
+        error(&bytes, Format::ReadMemH, 32, 0x102),
+        "Base address 0x102 is not aligned to 4 bytes"
+    );
+    assert_eq!(
+        error(&bytes, Format::ReadMemH, 24, 0),
+        "Word width must be 8, 16 or 32, not 24"
+    );
+}
@@ -0,0 +1,6 @@
+#ifndef BOARD_H
+#define BOARD_H
+
+#define BOARD_LEDS 3
+
+#endif
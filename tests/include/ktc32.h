@@ -0,0 +1,5 @@
+// Memory-mapped registers shared by every board.
+#define IO_BASE 0x4000
+#define REG(offset) (IO_BASE + (offset))
+#define UART_DATA REG(0)
+#define UART_STATUS REG(4)
@@ -0,0 +1,95 @@
+// Compiles every program in tests/ and runs it in the simulator, checking
+// the value main returns against the `// expect: N` line at the top of the
+// file. The AST interpreter must agree with the simulator on each of them.
+// Programs in tests/errors/ must instead fail with the message on their
+// `// error: MESSAGE` line.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn sources(dir: &str) -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(dir);
+    let mut paths: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "ktc"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+// The value of a `// key: value` line at the top of a test.
+fn header(path: &Path, key: &str) -> String {
+    let text = fs::read_to_string(path).unwrap();
+    let first = text.lines().next().unwrap_or_default();
+    match first
+        .strip_prefix("// ")
+        .and_then(|line| line.strip_prefix(key))
+        .and_then(|line| line.strip_prefix(':'))
+    {
+        Some(value) => value.trim().to_string(),
+        None => panic!("{} has no `// {}:` header", path.display(), key),
+    }
+}
+
+// Runs a program with `ktc32-com run` or `ktc32-com interp`.
+fn run(mode: &str, path: &Path) -> Output {
+    let include = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/include");
+    Command::new(env!("CARGO_BIN_EXE_ktc32-com"))
+        .arg(mode)
+        .arg("-I")
+        .arg(include)
+        .arg(path)
+        .env("RUST_BACKTRACE", "0")
+        .output()
+        .unwrap()
+}
+
+fn check_expected_values(mode: &str) {
+    let mut failures = Vec::new();
+    for path in sources("tests") {
+        let expected = header(&path, "expect");
+        let output = run(mode, &path);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if !output.status.success() || stdout.trim() != expected {
+            failures.push(format!(
+                "{}: expected {}, got {:?}\n{}",
+                path.display(),
+                expected,
+                stdout.trim(),
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn programs_return_expected_values() {
+    check_expected_values("run");
+}
+
+#[test]
+fn interpreter_returns_expected_values() {
+    check_expected_values("interp");
+}
+
+#[test]
+fn malformed_programs_are_rejected() {
+    let mut failures = Vec::new();
+    for path in sources("tests/errors") {
+        let expected = header(&path, "error");
+        let output = run("run", &path);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if output.status.success() || !stderr.contains(&expected) {
+            failures.push(format!(
+                "{}: expected error {:?}, got\n{}",
+                path.display(),
+                expected,
+                stderr
+            ));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}